@@ -0,0 +1,320 @@
+use tiktoken_rs::{cl100k_base_singleton, CoreBPE};
+
+/// Splits content too long to embed well into overlapping chunks of about
+/// `size` tokens, breaking between paragraphs and sentences where it can.
+#[derive(Clone, Copy)]
+pub struct Chunker {
+    size: usize,
+    overlap: usize,
+}
+
+struct Segment {
+    text: String,
+    tokens: usize,
+    starts_paragraph: bool,
+}
+
+impl Chunker {
+    /// A `size` of 0 turns chunking off.
+    pub fn new(size: usize, overlap: usize) -> Result<Self, String> {
+        if size > 0 && overlap >= size {
+            return Err(format!(
+                "Chunk overlap ({}) must be smaller than the chunk size ({})",
+                overlap, size
+            ));
+        }
+
+        Ok(Chunker { size, overlap })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.size > 0
+    }
+
+    pub fn chunk(&self, text: &str) -> Vec<String> {
+        if self.size == 0 {
+            return vec![text.to_string()];
+        }
+
+        let bpe = cl100k_base_singleton();
+        let bpe = bpe.lock();
+        let count = |text: &str| bpe.encode_ordinary(text).len();
+
+        if count(text) <= self.size {
+            return vec![text.to_string()];
+        }
+
+        let mut segments = Vec::new();
+        for paragraph in text.split("\n\n").map(str::trim) {
+            let mut starts_paragraph = true;
+            for sentence in split_sentences(paragraph) {
+                for piece in self.split_long(sentence, &bpe) {
+                    segments.push(Segment {
+                        tokens: count(&piece),
+                        text: piece,
+                        starts_paragraph,
+                    });
+                    starts_paragraph = false;
+                }
+            }
+        }
+
+        // Sizes are measured on the joined text, words don't always take as
+        // many tokens after a space as on their own
+        let mut chunks = Vec::new();
+        let mut current: Vec<Segment> = Vec::new();
+
+        for segment in segments {
+            current.push(segment);
+            if current.len() == 1 || count(&join(&current)) <= self.size {
+                continue;
+            }
+
+            let segment = current.pop().expect("current has several segments");
+            chunks.push(join(&current));
+
+            // Carry the end of the chunk over to the next one
+            let mut kept_tokens = 0;
+            let kept = current
+                .iter()
+                .rev()
+                .take_while(|segment| {
+                    kept_tokens += segment.tokens;
+                    kept_tokens <= self.overlap
+                })
+                .count();
+            current.drain(..current.len() - kept);
+
+            current.push(segment);
+            while current.len() > 1 && count(&join(&current)) > self.size {
+                current.remove(0);
+            }
+        }
+        if !current.is_empty() {
+            chunks.push(join(&current));
+        }
+
+        chunks
+    }
+
+    // Splits a sentence longer than a chunk between words, and words longer
+    // than a chunk between tokens
+    fn split_long(&self, sentence: &str, bpe: &CoreBPE) -> Vec<String> {
+        let count = |text: &str| bpe.encode_ordinary(text).len();
+        if count(sentence) <= self.size {
+            return vec![sentence.to_string()];
+        }
+
+        let mut pieces = Vec::new();
+        let mut current = String::new();
+
+        for word in sentence.split_whitespace() {
+            if count(word) > self.size {
+                if !current.is_empty() {
+                    pieces.push(std::mem::take(&mut current));
+                }
+                pieces.extend(self.split_word(word, bpe));
+                continue;
+            }
+
+            let candidate = if current.is_empty() {
+                word.to_string()
+            } else {
+                format!("{} {}", current, word)
+            };
+
+            if count(&candidate) > self.size && !current.is_empty() {
+                pieces.push(std::mem::replace(&mut current, word.to_string()));
+            } else {
+                current = candidate;
+            }
+        }
+        if !current.is_empty() {
+            pieces.push(current);
+        }
+
+        pieces
+    }
+
+    // Cuts a word into pieces of `size` tokens, moving a cut that would fall
+    // inside a character to the nearest one that doesn't
+    fn split_word(&self, word: &str, bpe: &CoreBPE) -> Vec<String> {
+        let tokens = bpe.encode_ordinary(word);
+        let mut pieces = Vec::new();
+        let mut start = 0;
+
+        while start < tokens.len() {
+            let full = (start + self.size).min(tokens.len());
+            let (end, piece) = (start + 1..=full)
+                .rev()
+                .chain(full + 1..=tokens.len())
+                .find_map(|end| {
+                    bpe.decode(tokens[start..end].to_vec())
+                        .ok()
+                        .map(|piece| (end, piece))
+                })
+                .expect("the whole word decodes");
+            pieces.push(piece);
+            start = end;
+        }
+
+        pieces
+    }
+}
+
+// Sentences end at a line break, or at punctuation followed by whitespace
+fn split_sentences(paragraph: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = paragraph.char_indices().peekable();
+
+    while let Some((index, c)) = chars.next() {
+        let ends_sentence = c == '\n'
+            || (matches!(c, '.' | '!' | '?')
+                && chars.peek().is_some_and(|(_, next)| next.is_whitespace()));
+
+        if ends_sentence {
+            let end = index + c.len_utf8();
+            sentences.push(paragraph[start..end].trim());
+            start = end;
+        }
+    }
+    sentences.push(paragraph[start..].trim());
+
+    sentences
+        .into_iter()
+        .filter(|sentence| !sentence.is_empty())
+        .collect()
+}
+
+fn join(segments: &[Segment]) -> String {
+    let mut text = String::new();
+
+    for (index, segment) in segments.iter().enumerate() {
+        if index > 0 {
+            text.push_str(if segment.starts_paragraph {
+                "\n\n"
+            } else {
+                " "
+            });
+        }
+        text.push_str(&segment.text);
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(text: &str) -> usize {
+        cl100k_base_singleton().lock().encode_ordinary(text).len()
+    }
+
+    #[test]
+    fn rejects_overlap_not_smaller_than_size() {
+        assert!(Chunker::new(10, 10).is_err());
+        assert!(Chunker::new(10, 9).is_ok());
+        assert!(Chunker::new(0, 64).is_ok());
+    }
+
+    #[test]
+    fn size_zero_keeps_text_whole() {
+        let chunker = Chunker::new(0, 0).unwrap();
+        let text = "word ".repeat(1000);
+
+        assert!(!chunker.enabled());
+        assert_eq!(chunker.chunk(&text), vec![text]);
+    }
+
+    #[test]
+    fn short_text_is_one_chunk() {
+        let chunker = Chunker::new(50, 10).unwrap();
+
+        assert_eq!(
+            chunker.chunk("Short and sweet. Really."),
+            vec!["Short and sweet. Really."]
+        );
+    }
+
+    #[test]
+    fn splits_between_sentences() {
+        let chunker = Chunker::new(8, 0).unwrap();
+        let text = "The first sentence is here. The second one follows it. \
+                    A third one ends the text.";
+
+        let chunks = chunker.chunk(text);
+
+        assert_eq!(
+            chunks,
+            vec![
+                "The first sentence is here.",
+                "The second one follows it.",
+                "A third one ends the text."
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_paragraph_breaks_inside_chunks() {
+        let chunker = Chunker::new(12, 0).unwrap();
+        let text = "One paragraph.\n\nAnother paragraph.\n\n".to_string()
+            + &"Filler words go on and on. ".repeat(5);
+
+        let chunks = chunker.chunk(&text);
+
+        assert_eq!(chunks[0], "One paragraph.\n\nAnother paragraph.");
+        assert!(chunks.iter().all(|chunk| tokens(chunk) <= 12));
+    }
+
+    #[test]
+    fn carries_overlap_into_next_chunk() {
+        let chunker = Chunker::new(13, 6).unwrap();
+        let text = "The first sentence is here. The second one follows it. \
+                    A third one ends the text.";
+
+        let chunks = chunker.chunk(text);
+
+        assert_eq!(
+            chunks,
+            vec![
+                "The first sentence is here. The second one follows it.",
+                "The second one follows it. A third one ends the text."
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_long_sentences_between_words() {
+        let chunker = Chunker::new(10, 0).unwrap();
+        let text = "one two three four five six seven eight nine ten eleven twelve \
+                    thirteen fourteen fifteen sixteen seventeen eighteen nineteen twenty";
+
+        let chunks = chunker.chunk(text);
+
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| tokens(chunk) <= 10));
+        assert_eq!(chunks.join(" "), text);
+    }
+
+    #[test]
+    fn splits_long_words_anywhere() {
+        let chunker = Chunker::new(8, 0).unwrap();
+        let word = "x".repeat(200);
+
+        let chunks = chunker.chunk(&word);
+
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| tokens(chunk) <= 8));
+        assert_eq!(chunks.concat(), word);
+    }
+
+    #[test]
+    fn sentences_end_at_punctuation_and_line_breaks() {
+        assert_eq!(
+            split_sentences("Is it? Yes! Version 1.2 works.\nNext line"),
+            vec!["Is it?", "Yes!", "Version 1.2 works.", "Next line"]
+        );
+    }
+}
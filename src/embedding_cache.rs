@@ -0,0 +1,145 @@
+use crate::long_term_memory::{decode, encode};
+use crate::models::{AnyOpenAIClient, EmbeddingCacheMetrics};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const EMBEDDING_KEY_PREFIX: &str = "motorhead_embedding:";
+
+/// Remembers embeddings in Redis for `ttl` seconds, keyed by a hash of the
+/// model and text, so repeated strings and queries aren't embedded again.
+/// A `ttl` of 0 turns the cache off.
+pub struct EmbeddingCache {
+    ttl: usize,
+    model: String,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+fn embedding_key(model: &str, text: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(model);
+    hasher.update([0]);
+    hasher.update(text);
+
+    format!("{}{:x}", EMBEDDING_KEY_PREFIX, hasher.finalize())
+}
+
+impl EmbeddingCache {
+    /// `model` tells apart embeddings of the same text by different models,
+    /// see `OpenAIEndpoint::embedding_model`.
+    pub fn new(ttl: usize, model: String) -> Self {
+        EmbeddingCache {
+            ttl,
+            model,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Embeds `texts`, only sending the ones not cached yet to OpenAI, each once.
+    pub async fn embed(
+        &self,
+        texts: Vec<String>,
+        openai_client: &AnyOpenAIClient,
+        redis_conn: &mut redis::aio::ConnectionManager,
+    ) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
+        if self.ttl == 0 || texts.is_empty() {
+            self.misses.fetch_add(texts.len() as u64, Ordering::Relaxed);
+            return Ok(openai_client.create_embedding(texts).await?);
+        }
+
+        let keys: Vec<String> = texts
+            .iter()
+            .map(|text| embedding_key(&self.model, text))
+            .collect();
+        let cached: Vec<Option<Vec<u8>>> = redis::cmd("MGET")
+            .arg(&keys)
+            .query_async(redis_conn)
+            .await?;
+
+        let mut embeddings: Vec<Option<Vec<f32>>> = cached
+            .into_iter()
+            .map(|bytes| bytes.map(|bytes| decode(&bytes)))
+            .collect();
+
+        let mut missing: Vec<usize> = Vec::new();
+        let mut missing_texts: HashMap<&str, usize> = HashMap::new();
+        for (index, embedding) in embeddings.iter().enumerate() {
+            if embedding.is_none() && !missing_texts.contains_key(texts[index].as_str()) {
+                missing_texts.insert(&texts[index], missing.len());
+                missing.push(index);
+            }
+        }
+
+        let hits = embeddings
+            .iter()
+            .filter(|embedding| embedding.is_some())
+            .count();
+        self.hits.fetch_add(hits as u64, Ordering::Relaxed);
+        self.misses
+            .fetch_add((texts.len() - hits) as u64, Ordering::Relaxed);
+
+        if !missing.is_empty() {
+            let created = openai_client
+                .create_embedding(missing.iter().map(|index| texts[*index].clone()).collect())
+                .await?;
+            if created.len() != missing.len() {
+                return Err("Embedding response is missing vectors".into());
+            }
+
+            let mut pipe = redis::pipe();
+            for (index, embedding) in missing.iter().zip(&created) {
+                pipe.cmd("SET")
+                    .arg(&keys[*index])
+                    .arg(encode(embedding.clone()))
+                    .arg("EX")
+                    .arg(self.ttl)
+                    .ignore();
+            }
+            // The embeddings are paid for already, failing to cache them
+            // shouldn't fail the request
+            if let Err(e) = pipe.query_async::<_, ()>(redis_conn).await {
+                log::error!("Error caching embeddings: {:?}", e);
+            }
+
+            for (index, embedding) in embeddings.iter_mut().enumerate() {
+                if embedding.is_none() {
+                    let created_index = missing_texts[texts[index].as_str()];
+                    *embedding = created.get(created_index).cloned();
+                }
+            }
+        }
+
+        Ok(embeddings.into_iter().flatten().collect())
+    }
+
+    pub fn metrics(&self) -> EmbeddingCacheMetrics {
+        EmbeddingCacheMetrics {
+            enabled: self.ttl > 0,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_tell_models_and_texts_apart() {
+        let key = embedding_key("text-embedding-ada-002", "hello");
+
+        assert!(key.starts_with(EMBEDDING_KEY_PREFIX));
+        assert_eq!(key.len(), EMBEDDING_KEY_PREFIX.len() + 64);
+        assert_eq!(key, embedding_key("text-embedding-ada-002", "hello"));
+        assert_ne!(key, embedding_key("text-embedding-ada-002", "hello "));
+        assert_ne!(key, embedding_key("azure:https://example/ada", "hello"));
+    }
+
+    #[test]
+    fn model_and_text_dont_run_together() {
+        assert_ne!(embedding_key("ab", "c"), embedding_key("a", "bc"));
+    }
+}
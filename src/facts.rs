@@ -0,0 +1,744 @@
+use crate::embedding_cache::EmbeddingCache;
+use crate::long_term_memory::{encode, escape_tag, namespace_tag};
+use crate::models::{AnyOpenAIClient, Fact, MemoryMessage, SearchPayload};
+use crate::reducer::extract_json;
+use crate::sessions::SessionKeys;
+use nanoid::nanoid;
+use redis::aio::ConnectionManager;
+use redis::Value;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+
+// Facts get their own index so message searches never return them
+pub const FACTS_INDEX: &str = "motorhead_facts";
+pub const FACT_KEY_PREFIX: &str = "motorhead_fact:";
+
+// New facts closer than this to a known one are taken as a restatement of it
+const DUPLICATE_DISTANCE: f64 = 0.05;
+
+// How many known facts are shown to the LLM when extracting new ones
+const KNOWN_FACTS: usize = 50;
+
+// How many of a session's facts are read at a time when deleting or moving them
+const SESSION_FACTS_PAGE: usize = 1000;
+
+fn fact_key(fact_id: &str) -> String {
+    format!("{}{}", FACT_KEY_PREFIX, fact_id)
+}
+
+// The session TAG holds the IDs of every session a fact came from, split on
+// commas, so commas in the IDs are percent-encoded.
+fn session_tag(session_id: &str) -> String {
+    session_id.replace('%', "%25").replace(',', "%2C")
+}
+
+fn session_tags<S: AsRef<str>>(session_ids: &[S]) -> String {
+    session_ids
+        .iter()
+        .map(|session_id| session_tag(session_id.as_ref()))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn parse_session_tags(tags: &str) -> Vec<String> {
+    tags.split(',')
+        .map(|tag| tag.replace("%2C", ",").replace("%25", "%"))
+        .collect()
+}
+
+// Facts are deduplicated across all of a user's sessions, or within the session
+// when it isn't linked to a user.
+fn scope_filter(session: &SessionKeys, user_id: Option<&str>) -> String {
+    let namespace = escape_tag(namespace_tag(session.namespace.as_deref()));
+    match user_id {
+        Some(user_id) => format!(
+            "@user:{{{}}} @namespace:{{{}}}",
+            escape_tag(user_id),
+            namespace
+        ),
+        None => format!(
+            "@session:{{{}}} @namespace:{{{}}}",
+            escape_tag(&session_tag(&session.id)),
+            namespace
+        ),
+    }
+}
+
+fn session_filter(session: &SessionKeys) -> String {
+    scope_filter(session, None)
+}
+
+// The source messages of a fact, by the session they're from
+type Sources = HashMap<String, Vec<String>>;
+
+// What deleting or moving a session needs to know of its facts
+#[derive(Clone)]
+struct SessionFact {
+    key: String,
+    session_ids: Vec<String>,
+    source_message_ids: Vec<String>,
+    sources: Sources,
+}
+
+impl SessionFact {
+    // The fact without what the session contributed, or None if nothing is
+    // left. Facts stored before their sources were kept by session only lose
+    // the session.
+    fn without_session(&self, session_id: &str) -> Option<SessionFact> {
+        let session_ids: Vec<String> = self
+            .session_ids
+            .iter()
+            .filter(|id| *id != session_id)
+            .cloned()
+            .collect();
+
+        let mut sources = self.sources.clone();
+        let dropped = sources.remove(session_id).unwrap_or_default();
+        let source_message_ids: Vec<String> = self
+            .source_message_ids
+            .iter()
+            .filter(|id| !dropped.contains(id))
+            .cloned()
+            .collect();
+
+        if session_ids.is_empty() || source_message_ids.is_empty() {
+            return None;
+        }
+
+        Some(SessionFact {
+            key: self.key.clone(),
+            session_ids,
+            source_message_ids,
+            sources,
+        })
+    }
+
+    // The fact with what the source session contributed credited to the target
+    fn moved(&self, source_id: &str, target_id: &str) -> SessionFact {
+        let mut session_ids: Vec<String> = self
+            .session_ids
+            .iter()
+            .filter(|id| *id != source_id && *id != target_id)
+            .cloned()
+            .collect();
+        session_ids.push(target_id.to_string());
+
+        let mut sources = self.sources.clone();
+        if let Some(moved) = sources.remove(source_id) {
+            let target = sources.entry(target_id.to_string()).or_default();
+            for id in moved {
+                if !target.contains(&id) {
+                    target.push(id);
+                }
+            }
+        }
+
+        SessionFact {
+            key: self.key.clone(),
+            session_ids,
+            source_message_ids: self.source_message_ids.clone(),
+            sources,
+        }
+    }
+
+    // Queues the write of the fact's provenance
+    fn store(&self, pipe: &mut redis::Pipeline) -> Result<(), serde_json::Error> {
+        pipe.cmd("HSET")
+            .arg(&self.key)
+            .arg("session")
+            .arg(session_tags(&self.session_ids))
+            .arg("source_message_ids")
+            .arg(serde_json::to_string(&self.source_message_ids)?)
+            .arg("sources")
+            .arg(serde_json::to_string(&self.sources)?)
+            .ignore();
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct ExtractedFacts {
+    #[serde(default)]
+    facts: Vec<ExtractedFact>,
+}
+
+#[derive(Deserialize)]
+struct ExtractedFact {
+    id: Option<String>,
+    fact: String,
+    #[serde(default)]
+    sources: Vec<usize>,
+}
+
+// Search results as field maps keyed by doc key, after the total count
+fn search_docs(values: Vec<Value>) -> Vec<(String, HashMap<String, String>)> {
+    values
+        .iter()
+        .skip(1)
+        .collect::<Vec<_>>()
+        .chunks(2)
+        .filter(|chunk| chunk.len() == 2)
+        .filter_map(|chunk| {
+            let key: String = redis::from_redis_value(chunk[0]).ok()?;
+            let fields: Vec<String> = redis::from_redis_value(chunk[1]).ok()?;
+            let fields = fields
+                .chunks(2)
+                .filter(|pair| pair.len() == 2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+            Some((key, fields))
+        })
+        .collect()
+}
+
+fn to_fact(key: &str, fields: &HashMap<String, String>) -> Fact {
+    let list = |field: &str| -> Vec<String> {
+        fields
+            .get(field)
+            .and_then(|value| serde_json::from_str(value).ok())
+            .unwrap_or_default()
+    };
+
+    Fact {
+        id: key.trim_start_matches(FACT_KEY_PREFIX).to_string(),
+        fact: fields.get("fact").cloned().unwrap_or_default(),
+        session_ids: fields
+            .get("session")
+            .map(|sessions| parse_session_tags(sessions))
+            .unwrap_or_default(),
+        source_message_ids: list("source_message_ids"),
+        created_at: fields
+            .get("created_at")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0),
+        updated_at: fields
+            .get("updated_at")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0),
+        dist: fields.get("dist").and_then(|value| value.parse().ok()),
+    }
+}
+
+async fn known_facts(
+    filter: &str,
+    redis_conn: &mut ConnectionManager,
+) -> Result<Vec<Fact>, redis::RedisError> {
+    let values: Vec<Value> = redis::cmd("FT.SEARCH")
+        .arg(FACTS_INDEX)
+        .arg(filter)
+        .arg("RETURN")
+        .arg(5)
+        .arg("fact")
+        .arg("session")
+        .arg("source_message_ids")
+        .arg("created_at")
+        .arg("updated_at")
+        .arg("SORTBY")
+        .arg("updated_at")
+        .arg("DESC")
+        .arg("LIMIT")
+        .arg(0)
+        .arg(KNOWN_FACTS)
+        .arg("DIALECT")
+        .arg("2")
+        .query_async(redis_conn)
+        .await?;
+
+    Ok(search_docs(values)
+        .iter()
+        .map(|(key, fields)| to_fact(key, fields))
+        .collect())
+}
+
+async fn nearest_fact(
+    filter: &str,
+    vector: &[u8],
+    redis_conn: &mut ConnectionManager,
+) -> Result<Option<Fact>, redis::RedisError> {
+    let values: Vec<Value> = redis::cmd("FT.SEARCH")
+        .arg(FACTS_INDEX)
+        .arg(format!("({})=>[KNN 1 @vector $V AS dist]", filter))
+        .arg("PARAMS")
+        .arg("2")
+        .arg("V")
+        .arg(vector)
+        .arg("RETURN")
+        .arg(6)
+        .arg("fact")
+        .arg("session")
+        .arg("source_message_ids")
+        .arg("created_at")
+        .arg("updated_at")
+        .arg("dist")
+        .arg("DIALECT")
+        .arg("2")
+        .query_async(redis_conn)
+        .await?;
+
+    Ok(search_docs(values)
+        .first()
+        .map(|(key, fields)| to_fact(key, fields)))
+}
+
+fn extraction_prompt(known: &[Fact], lines: &[String]) -> String {
+    let known_joined = known
+        .iter()
+        .map(|fact| format!("{}: {}", fact.id, fact.fact))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let lines_joined = lines
+        .iter()
+        .enumerate()
+        .map(|(index, line)| format!("[{}] {}", index + 1, line))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        r#"
+Extract durable facts from the numbered lines of conversation: lasting facts about the human, their preferences and plans, and commitments such as dates and deadlines, that would still be useful in future conversations. Ignore small talk and anything that only matters in the moment.
+
+Known facts are listed with their ids. If the conversation updates or contradicts a known fact, return the corrected fact with that fact's id. Don't return known facts that are unchanged.
+
+Respond only with JSON, where sources are the numbers of the lines the fact comes from:
+{{"facts": [{{"id": "<id of the known fact it replaces, or null>", "fact": "<the fact as a short sentence>", "sources": [1]}}]}}
+
+Known facts:
+{known_joined}
+Lines of conversation:
+{lines_joined}
+"#
+    )
+}
+
+fn parse_extracted(completion: &str) -> Result<Vec<ExtractedFact>, serde_json::Error> {
+    let extracted: ExtractedFacts = serde_json::from_str(extract_json(completion))?;
+
+    Ok(extracted
+        .facts
+        .into_iter()
+        .filter(|fact| !fact.fact.trim().is_empty())
+        .collect())
+}
+
+/// Extracts durable facts from messages that were just summarized and stores
+/// them, updating the known facts they restate or correct. Returns how many
+/// facts were added or updated.
+pub async fn extract_facts(
+    session: &SessionKeys,
+    messages: &[MemoryMessage],
+    model: &str,
+    embeddings: &EmbeddingCache,
+    openai_client: &AnyOpenAIClient,
+    mut redis_conn: ConnectionManager,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let messages: Vec<&MemoryMessage> = messages
+        .iter()
+        .filter(|message| !message.is_legacy())
+        .collect();
+    if messages.is_empty() {
+        return Ok(0);
+    }
+
+    let user_id: Option<String> = redis::Cmd::hget(session.meta(), "user_id")
+        .query_async(&mut redis_conn)
+        .await?;
+    let filter = scope_filter(session, user_id.as_deref());
+
+    let known = known_facts(&filter, &mut redis_conn).await?;
+    let known: HashMap<String, Fact> = known
+        .into_iter()
+        .map(|fact| (fact.id.clone(), fact))
+        .collect();
+
+    let lines: Vec<String> = messages
+        .iter()
+        .map(|message| message.to_prompt_line())
+        .collect();
+    let prompt = extraction_prompt(&known.values().cloned().collect::<Vec<_>>(), &lines);
+
+    let response = openai_client.create_chat_completion(model, &prompt).await?;
+    let completion = response
+        .choices
+        .first()
+        .ok_or("No completion found")?
+        .message
+        .content
+        .clone();
+
+    let extracted = parse_extracted(&completion)?;
+    if extracted.is_empty() {
+        return Ok(0);
+    }
+
+    // Errors of the cache aren't Send, they're only kept as text
+    let embeddings = embeddings
+        .embed(
+            extracted.iter().map(|fact| fact.fact.clone()).collect(),
+            openai_client,
+            &mut redis_conn,
+        )
+        .await
+        .map_err(|e| format!("{:?}", e))?;
+    let now = chrono::Utc::now().timestamp();
+
+    for (extracted, embedding) in extracted.iter().zip(embeddings) {
+        let vector = encode(embedding);
+
+        let mut source_message_ids: Vec<String> = extracted
+            .sources
+            .iter()
+            .filter_map(|line| messages.get(line.wrapping_sub(1)))
+            .map(|message| message.id.clone())
+            .collect();
+        if source_message_ids.is_empty() {
+            source_message_ids = messages.iter().map(|message| message.id.clone()).collect();
+        }
+
+        let existing = match extracted.id.as_ref().and_then(|id| known.get(id)) {
+            Some(fact) => Some(fact.clone()),
+            None => nearest_fact(&filter, &vector, &mut redis_conn)
+                .await?
+                .filter(|fact| fact.dist.is_some_and(|dist| dist < DUPLICATE_DISTANCE)),
+        };
+
+        let new_message_ids = source_message_ids.clone();
+        let (fact_id, mut session_ids, mut sources, created_at) = match existing {
+            Some(fact) => {
+                let sources: Option<String> = redis::Cmd::hget(fact_key(&fact.id), "sources")
+                    .query_async(&mut redis_conn)
+                    .await?;
+                let sources: Sources = sources
+                    .and_then(|sources| serde_json::from_str(&sources).ok())
+                    .unwrap_or_default();
+
+                source_message_ids.extend(
+                    fact.source_message_ids
+                        .into_iter()
+                        .filter(|id| !source_message_ids.contains(id))
+                        .collect::<Vec<_>>(),
+                );
+                (fact.id, fact.session_ids, sources, fact.created_at)
+            }
+            None => (nanoid!(), vec![], Sources::new(), now),
+        };
+        if !session_ids.contains(&session.id) {
+            session_ids.push(session.id.clone());
+        }
+
+        let session_sources = sources.entry(session.id.clone()).or_default();
+        for id in new_message_ids {
+            if !session_sources.contains(&id) {
+                session_sources.push(id);
+            }
+        }
+
+        let mut cmd = redis::cmd("HSET");
+        cmd.arg(fact_key(&fact_id))
+            .arg("fact")
+            .arg(&extracted.fact)
+            .arg("vector")
+            .arg(vector)
+            .arg("session")
+            .arg(session_tags(&session_ids))
+            .arg("namespace")
+            .arg(namespace_tag(session.namespace.as_deref()))
+            .arg("source_message_ids")
+            .arg(serde_json::to_string(&source_message_ids)?)
+            .arg("sources")
+            .arg(serde_json::to_string(&sources)?)
+            .arg("created_at")
+            .arg(created_at)
+            .arg("updated_at")
+            .arg(now);
+        if let Some(user_id) = &user_id {
+            cmd.arg("user").arg(user_id);
+        }
+        cmd.query_async::<_, ()>(&mut redis_conn).await?;
+    }
+
+    Ok(extracted.len())
+}
+
+/// Searches the session's facts the way messages are searched, keeping the
+/// payload's `top_k`, distance threshold and time range, the last one applied to
+/// when facts were last updated.
+pub async fn search_facts(
+    payload: &SearchPayload,
+    session: &SessionKeys,
+    embeddings: &EmbeddingCache,
+    openai_client: &AnyOpenAIClient,
+    mut redis_conn: ConnectionManager,
+) -> Result<Vec<Fact>, Box<dyn std::error::Error>> {
+    let response = embeddings
+        .embed(vec![payload.text.clone()], openai_client, &mut redis_conn)
+        .await?;
+    let vector = encode(response[0].clone());
+
+    let values: Vec<Value> = redis::cmd("FT.SEARCH")
+        .arg(FACTS_INDEX)
+        .arg(format!(
+            "({})=>[KNN {} @vector $V AS dist]",
+            search_filter(session, payload),
+            payload.top_k
+        ))
+        .arg("PARAMS")
+        .arg("2")
+        .arg("V")
+        .arg(vector)
+        .arg("RETURN")
+        .arg(6)
+        .arg("fact")
+        .arg("session")
+        .arg("source_message_ids")
+        .arg("created_at")
+        .arg("updated_at")
+        .arg("dist")
+        .arg("SORTBY")
+        .arg("dist")
+        .arg("LIMIT")
+        .arg(0)
+        .arg(payload.top_k)
+        .arg("DIALECT")
+        .arg("2")
+        .query_async(&mut redis_conn)
+        .await?;
+
+    let threshold = payload.distance_threshold();
+    Ok(search_docs(values)
+        .iter()
+        .map(|(key, fields)| to_fact(key, fields))
+        .filter(|fact| {
+            threshold.is_none_or(|threshold| fact.dist.is_some_and(|dist| dist <= threshold))
+        })
+        .collect())
+}
+
+fn search_filter(session: &SessionKeys, payload: &SearchPayload) -> String {
+    let mut filter = session_filter(session);
+
+    if payload.created_after.is_some() || payload.created_before.is_some() {
+        filter.push_str(&format!(
+            " @updated_at:[{} {}]",
+            payload
+                .created_after
+                .map_or(String::from("-inf"), |after| after.to_string()),
+            payload
+                .created_before
+                .map_or(String::from("+inf"), |before| before.to_string())
+        ));
+    }
+
+    filter
+}
+
+async fn session_facts(
+    session: &SessionKeys,
+    redis_conn: &mut ConnectionManager,
+) -> Result<Vec<SessionFact>, redis::RedisError> {
+    let mut facts = Vec::new();
+
+    loop {
+        let values: Vec<Value> = redis::cmd("FT.SEARCH")
+            .arg(FACTS_INDEX)
+            .arg(session_filter(session))
+            .arg("RETURN")
+            .arg(3)
+            .arg("session")
+            .arg("source_message_ids")
+            .arg("sources")
+            .arg("LIMIT")
+            .arg(facts.len())
+            .arg(SESSION_FACTS_PAGE)
+            .arg("DIALECT")
+            .arg("2")
+            .query_async(redis_conn)
+            .await?;
+
+        let page = search_docs(values);
+        let last_page = page.len() < SESSION_FACTS_PAGE;
+        facts.extend(page.into_iter().map(|(key, fields)| {
+            SessionFact {
+                session_ids: fields
+                    .get("session")
+                    .map(|sessions| parse_session_tags(sessions))
+                    .unwrap_or_default(),
+                source_message_ids: fields
+                    .get("source_message_ids")
+                    .and_then(|ids| serde_json::from_str(ids).ok())
+                    .unwrap_or_default(),
+                sources: fields
+                    .get("sources")
+                    .and_then(|sources| serde_json::from_str(sources).ok())
+                    .unwrap_or_default(),
+                key,
+            }
+        }));
+
+        if last_page {
+            return Ok(facts);
+        }
+    }
+}
+
+/// Removes the session and its source messages from the provenance of its
+/// facts, deleting the facts left without sources. Returns how many were
+/// deleted.
+pub async fn delete_session_facts(
+    session: &SessionKeys,
+    mut redis_conn: ConnectionManager,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let facts = session_facts(session, &mut redis_conn).await?;
+    let mut deleted = 0;
+
+    let mut pipe = redis::pipe();
+    for fact in facts {
+        match fact.without_session(&session.id) {
+            Some(remaining) => remaining.store(&mut pipe)?,
+            None => {
+                pipe.cmd("DEL").arg(&fact.key).ignore();
+                deleted += 1;
+            }
+        }
+    }
+    pipe.query_async::<_, ()>(&mut redis_conn).await?;
+
+    Ok(deleted)
+}
+
+/// Credits the source session's facts to the target session instead.
+pub async fn move_session_facts(
+    source: &SessionKeys,
+    target: &SessionKeys,
+    mut redis_conn: ConnectionManager,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let facts = session_facts(source, &mut redis_conn).await?;
+
+    let mut pipe = redis::pipe();
+    for fact in &facts {
+        fact.moved(&source.id, &target.id).store(&mut pipe)?;
+    }
+    pipe.query_async::<_, ()>(&mut redis_conn).await?;
+
+    Ok(facts.len())
+}
+
+/// Moves the session's facts to its new user. Facts other sessions contributed
+/// to stay with them, the session getting its own copy.
+pub async fn rehome_session_facts(
+    session: &SessionKeys,
+    user_id: &str,
+    mut redis_conn: ConnectionManager,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let facts = session_facts(session, &mut redis_conn).await?;
+
+    let mut pipe = redis::pipe();
+    for fact in &facts {
+        let Some(remaining) = fact.without_session(&session.id) else {
+            pipe.cmd("HSET")
+                .arg(&fact.key)
+                .arg("user")
+                .arg(user_id)
+                .ignore();
+            continue;
+        };
+
+        let copy = SessionFact {
+            key: fact_key(&nanoid!()),
+            session_ids: vec![session.id.clone()],
+            source_message_ids: match fact.sources.get(&session.id) {
+                Some(ids) => ids.clone(),
+                None => fact.source_message_ids.clone(),
+            },
+            sources: fact
+                .sources
+                .get_key_value(&session.id)
+                .map(|(id, ids)| (id.clone(), ids.clone()))
+                .into_iter()
+                .collect(),
+        };
+        pipe.cmd("COPY").arg(&fact.key).arg(&copy.key).ignore();
+        copy.store(&mut pipe)?;
+        pipe.cmd("HSET")
+            .arg(&copy.key)
+            .arg("user")
+            .arg(user_id)
+            .ignore();
+        remaining.store(&mut pipe)?;
+    }
+    pipe.query_async::<_, ()>(&mut redis_conn).await?;
+
+    Ok(facts.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fact(sources: &[(&str, &[&str])]) -> SessionFact {
+        SessionFact {
+            key: fact_key("f1"),
+            session_ids: sources.iter().map(|(id, _)| id.to_string()).collect(),
+            source_message_ids: sources
+                .iter()
+                .flat_map(|(_, ids)| ids.iter().map(|id| id.to_string()))
+                .collect(),
+            sources: sources
+                .iter()
+                .map(|(id, ids)| {
+                    (
+                        id.to_string(),
+                        ids.iter().map(|id| id.to_string()).collect(),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn deleting_a_session_drops_its_sources() {
+        let shared = fact(&[("a", &["m1", "m2"]), ("b", &["m3"])]);
+
+        let remaining = shared.without_session("a").unwrap();
+
+        assert_eq!(remaining.session_ids, ["b"]);
+        assert_eq!(remaining.source_message_ids, ["m3"]);
+        assert!(!remaining.sources.contains_key("a"));
+        assert!(fact(&[("a", &["m1"])]).without_session("a").is_none());
+    }
+
+    #[test]
+    fn facts_without_sources_by_session_only_lose_the_session() {
+        let mut legacy = fact(&[("a", &["m1"]), ("b", &["m2"])]);
+        legacy.sources.clear();
+
+        let remaining = legacy.without_session("a").unwrap();
+
+        assert_eq!(remaining.session_ids, ["b"]);
+        assert_eq!(remaining.source_message_ids, ["m1", "m2"]);
+    }
+
+    #[test]
+    fn moving_a_session_merges_its_sources() {
+        let shared = fact(&[("a", &["m1"]), ("b", &["m1", "m2"]), ("c", &["m3"])]);
+
+        let moved = shared.moved("a", "b");
+
+        assert_eq!(moved.session_ids, ["c", "b"]);
+        assert_eq!(moved.sources["b"], ["m1", "m2"]);
+        assert!(!moved.sources.contains_key("a"));
+        assert_eq!(moved.source_message_ids, shared.source_message_ids);
+    }
+
+    #[test]
+    fn searches_filter_on_when_facts_were_updated() {
+        let session = SessionKeys::parse(None, "s1").unwrap();
+        let payload: SearchPayload = serde_json::from_value(serde_json::json!({
+            "text": "diet",
+            "created_after": 1686000000,
+        }))
+        .unwrap();
+
+        assert!(search_filter(&session, &payload).ends_with(" @updated_at:[1686000000 +inf]"));
+    }
+}
@@ -0,0 +1,794 @@
+use crate::models::{
+    AnyOpenAIClient, AppState, GraphEntity, GraphQuery, GraphRelation, KnowledgeGraph,
+    MemoryMessage, NamespaceQuery,
+};
+use crate::redis_lock::RedisLock;
+use crate::reducer::extract_json;
+use crate::sessions::{check_namespace, namespaced_key, SessionKeys};
+use actix_web::{error, get, web, HttpResponse, Responder};
+use redis::aio::ConnectionManager;
+use redis::RedisResult;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::Arc;
+
+// How many known entity names are shown to the LLM so it reuses them
+const KNOWN_ENTITIES: usize = 100;
+
+// The graph is kept per user, or per session when it isn't linked to a user.
+// Entities are a hash of JSON docs keyed by normalized name, relations a hash
+// keyed by their normalized endpoints and label, and each entity has a set of
+// the relations touching it. Changes are made under the graph's lock, as they
+// read the docs before writing them back.
+struct GraphKeys {
+    prefix: String,
+}
+
+impl GraphKeys {
+    fn user(namespace: Option<&str>, user_id: &str) -> Self {
+        Self {
+            prefix: namespaced_key(namespace, &format!("graph:user:{}", user_id)),
+        }
+    }
+
+    fn session(session: &SessionKeys) -> Self {
+        Self {
+            prefix: namespaced_key(
+                session.namespace.as_deref(),
+                &format!("graph:session:{}", session.id),
+            ),
+        }
+    }
+
+    fn entities(&self) -> String {
+        format!("{}:entities", self.prefix)
+    }
+
+    fn relations(&self) -> String {
+        format!("{}:relations", self.prefix)
+    }
+
+    fn edges(&self, entity_key: &str) -> String {
+        format!("{}:edges:{}", self.prefix, entity_key)
+    }
+
+    fn lock(&self) -> String {
+        format!("{}:lock", self.prefix)
+    }
+}
+
+fn entity_key(name: &str) -> String {
+    name.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+// The parts of a relation key are split by pipes, so pipes in the names are
+// percent-encoded.
+fn relation_key(relation: &GraphRelation) -> String {
+    [&relation.source, &relation.relation, &relation.target]
+        .map(|part| entity_key(part).replace('%', "%25").replace('|', "%7C"))
+        .join("|")
+}
+
+fn union(ids: &mut Vec<String>, other: Vec<String>) {
+    for id in other {
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+}
+
+// Swaps a session out of a provenance list, or just drops it
+fn reassign(ids: Vec<String>, from: &str, to: Option<&str>) -> Vec<String> {
+    let mut ids: Vec<String> = ids
+        .into_iter()
+        .filter(|id| id != from && Some(id.as_str()) != to)
+        .collect();
+    if let Some(to) = to {
+        ids.push(to.to_string());
+    }
+    ids
+}
+
+async fn session_user(
+    session: &SessionKeys,
+    redis_conn: &mut ConnectionManager,
+) -> RedisResult<Option<String>> {
+    redis::Cmd::hget(session.meta(), "user_id")
+        .query_async(redis_conn)
+        .await
+}
+
+async fn all_entities(
+    keys: &GraphKeys,
+    redis_conn: &mut ConnectionManager,
+) -> RedisResult<HashMap<String, GraphEntity>> {
+    let docs: HashMap<String, String> = redis::Cmd::hgetall(keys.entities())
+        .query_async(redis_conn)
+        .await?;
+
+    Ok(docs
+        .into_iter()
+        .filter_map(|(key, doc)| Some((key, serde_json::from_str(&doc).ok()?)))
+        .collect())
+}
+
+async fn all_relations(
+    keys: &GraphKeys,
+    redis_conn: &mut ConnectionManager,
+) -> RedisResult<HashMap<String, GraphRelation>> {
+    let docs: HashMap<String, String> = redis::Cmd::hgetall(keys.relations())
+        .query_async(redis_conn)
+        .await?;
+
+    Ok(docs
+        .into_iter()
+        .filter_map(|(key, doc)| Some((key, serde_json::from_str(&doc).ok()?)))
+        .collect())
+}
+
+async fn get_docs<T: serde::de::DeserializeOwned>(
+    hash: String,
+    fields: &[String],
+    redis_conn: &mut ConnectionManager,
+) -> RedisResult<Vec<T>> {
+    if fields.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let docs: Vec<Option<String>> = redis::cmd("HMGET")
+        .arg(hash)
+        .arg(fields)
+        .query_async(redis_conn)
+        .await?;
+
+    Ok(docs
+        .into_iter()
+        .flatten()
+        .filter_map(|doc| serde_json::from_str(&doc).ok())
+        .collect())
+}
+
+// Adds entities and relations to the graph, merging them into the ones already
+// there. Relation endpoints missing from the entities are added bare.
+async fn merge_into(
+    keys: &GraphKeys,
+    entities: Vec<GraphEntity>,
+    relations: Vec<GraphRelation>,
+    redis_conn: &mut ConnectionManager,
+) -> RedisResult<()> {
+    let _lock = RedisLock::acquire(keys.lock(), redis_conn).await?;
+    let mut merged_entities: HashMap<String, GraphEntity> = HashMap::new();
+    let mut merged_relations: HashMap<String, GraphRelation> = HashMap::new();
+
+    let endpoints = relations.iter().flat_map(|relation| {
+        [&relation.source, &relation.target].map(|name| GraphEntity {
+            name: name.clone(),
+            kind: None,
+            description: None,
+            session_ids: relation.session_ids.clone(),
+            updated_at: relation.updated_at,
+        })
+    });
+    let endpoints: Vec<GraphEntity> = endpoints.collect();
+
+    let entity_keys: Vec<String> = entities
+        .iter()
+        .chain(&endpoints)
+        .map(|entity| entity_key(&entity.name))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    let relation_keys: Vec<String> = relations
+        .iter()
+        .map(relation_key)
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+
+    let existing: Vec<GraphEntity> = get_docs(keys.entities(), &entity_keys, redis_conn).await?;
+    let existing_relations: Vec<GraphRelation> =
+        get_docs(keys.relations(), &relation_keys, redis_conn).await?;
+
+    for entity in existing.into_iter().chain(entities).chain(endpoints) {
+        match merged_entities.get_mut(&entity_key(&entity.name)) {
+            Some(merged) => {
+                merged.kind = entity.kind.or(merged.kind.take());
+                merged.description = entity.description.or(merged.description.take());
+                merged.updated_at = merged.updated_at.max(entity.updated_at);
+                union(&mut merged.session_ids, entity.session_ids);
+            }
+            None => {
+                merged_entities.insert(entity_key(&entity.name), entity);
+            }
+        }
+    }
+
+    for relation in existing_relations.into_iter().chain(relations) {
+        match merged_relations.get_mut(&relation_key(&relation)) {
+            Some(merged) => {
+                merged.updated_at = merged.updated_at.max(relation.updated_at);
+                union(&mut merged.session_ids, relation.session_ids);
+                union(&mut merged.source_message_ids, relation.source_message_ids);
+            }
+            None => {
+                merged_relations.insert(relation_key(&relation), relation);
+            }
+        }
+    }
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for (key, entity) in &merged_entities {
+        pipe.cmd("HSET")
+            .arg(keys.entities())
+            .arg(key)
+            .arg(serde_json::to_string(entity).unwrap_or_default())
+            .ignore();
+    }
+    for (key, relation) in &merged_relations {
+        pipe.cmd("HSET")
+            .arg(keys.relations())
+            .arg(key)
+            .arg(serde_json::to_string(relation).unwrap_or_default())
+            .ignore();
+        pipe.cmd("SADD")
+            .arg(keys.edges(&entity_key(&relation.source)))
+            .arg(key)
+            .ignore();
+        pipe.cmd("SADD")
+            .arg(keys.edges(&entity_key(&relation.target)))
+            .arg(key)
+            .ignore();
+    }
+    pipe.query_async::<_, ()>(redis_conn).await
+}
+
+// Reads the whole graph, or an entity with its relations and their other ends.
+// With a session ID, only what that session contributed is kept.
+async fn read_graph(
+    keys: &GraphKeys,
+    entity: Option<&str>,
+    session_id: Option<&str>,
+    redis_conn: &mut ConnectionManager,
+) -> RedisResult<KnowledgeGraph> {
+    let in_session = |session_ids: &Vec<String>| {
+        session_id.is_none_or(|session_id| session_ids.iter().any(|id| id == session_id))
+    };
+
+    let (entities, relations) = match entity {
+        Some(entity) => {
+            let key = entity_key(entity);
+            let relation_keys: Vec<String> = redis::Cmd::smembers(keys.edges(&key))
+                .query_async(redis_conn)
+                .await?;
+            let relations: Vec<GraphRelation> =
+                get_docs(keys.relations(), &relation_keys, redis_conn).await?;
+            let relations: Vec<GraphRelation> = relations
+                .into_iter()
+                .filter(|relation| in_session(&relation.session_ids))
+                .collect();
+
+            let mut entity_keys = vec![key];
+            for relation in &relations {
+                for name in [&relation.source, &relation.target] {
+                    let key = entity_key(name);
+                    if !entity_keys.contains(&key) {
+                        entity_keys.push(key);
+                    }
+                }
+            }
+            let entities: Vec<GraphEntity> =
+                get_docs(keys.entities(), &entity_keys, redis_conn).await?;
+
+            (entities, relations)
+        }
+        None => (
+            all_entities(keys, redis_conn)
+                .await?
+                .into_values()
+                .collect(),
+            all_relations(keys, redis_conn)
+                .await?
+                .into_values()
+                .collect(),
+        ),
+    };
+
+    Ok(KnowledgeGraph {
+        entities: entities
+            .into_iter()
+            .filter(|entity| in_session(&entity.session_ids))
+            .collect(),
+        relations: relations
+            .into_iter()
+            .filter(|relation| in_session(&relation.session_ids))
+            .collect(),
+    })
+}
+
+// Drops the session from the provenance of a shared graph, or credits another
+// session instead. Returns how many entities were left without any session.
+async fn reassign_session(
+    keys: &GraphKeys,
+    session_id: &str,
+    replacement: Option<&str>,
+    redis_conn: &mut ConnectionManager,
+) -> RedisResult<usize> {
+    let _lock = RedisLock::acquire(keys.lock(), redis_conn).await?;
+    let entities = all_entities(keys, redis_conn).await?;
+    let relations = all_relations(keys, redis_conn).await?;
+    let mut deleted = 0;
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for (key, mut entity) in entities {
+        if !entity.session_ids.iter().any(|id| id == session_id) {
+            continue;
+        }
+
+        entity.session_ids = reassign(entity.session_ids, session_id, replacement);
+        if entity.session_ids.is_empty() {
+            pipe.cmd("HDEL").arg(keys.entities()).arg(&key).ignore();
+            pipe.cmd("DEL").arg(keys.edges(&key)).ignore();
+            deleted += 1;
+        } else {
+            pipe.cmd("HSET")
+                .arg(keys.entities())
+                .arg(&key)
+                .arg(serde_json::to_string(&entity).unwrap_or_default())
+                .ignore();
+        }
+    }
+    for (key, mut relation) in relations {
+        if !relation.session_ids.iter().any(|id| id == session_id) {
+            continue;
+        }
+
+        relation.session_ids = reassign(relation.session_ids, session_id, replacement);
+        if relation.session_ids.is_empty() {
+            pipe.cmd("HDEL").arg(keys.relations()).arg(&key).ignore();
+            for name in [&relation.source, &relation.target] {
+                pipe.cmd("SREM")
+                    .arg(keys.edges(&entity_key(name)))
+                    .arg(&key)
+                    .ignore();
+            }
+        } else {
+            pipe.cmd("HSET")
+                .arg(keys.relations())
+                .arg(&key)
+                .arg(serde_json::to_string(&relation).unwrap_or_default())
+                .ignore();
+        }
+    }
+    pipe.query_async::<_, ()>(redis_conn).await?;
+
+    Ok(deleted)
+}
+
+// Deletes a graph outright. Returns how many entities it had.
+async fn drop_graph(keys: &GraphKeys, redis_conn: &mut ConnectionManager) -> RedisResult<usize> {
+    let _lock = RedisLock::acquire(keys.lock(), redis_conn).await?;
+    let entity_keys: Vec<String> = redis::Cmd::hkeys(keys.entities())
+        .query_async(redis_conn)
+        .await?;
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    pipe.cmd("DEL")
+        .arg(keys.entities())
+        .arg(keys.relations())
+        .ignore();
+    for key in &entity_keys {
+        pipe.cmd("DEL").arg(keys.edges(key)).ignore();
+    }
+    pipe.query_async::<_, ()>(redis_conn).await?;
+
+    Ok(entity_keys.len())
+}
+
+#[derive(Deserialize)]
+struct ExtractedGraph {
+    #[serde(default)]
+    entities: Vec<ExtractedEntity>,
+    #[serde(default)]
+    relations: Vec<ExtractedRelation>,
+}
+
+#[derive(Deserialize)]
+struct ExtractedEntity {
+    name: String,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ExtractedRelation {
+    source: String,
+    relation: String,
+    target: String,
+    #[serde(default)]
+    sources: Vec<usize>,
+}
+
+fn extraction_prompt(known: &[String], lines: &[String]) -> String {
+    let known_joined = known.join("\n");
+    let lines_joined = lines
+        .iter()
+        .enumerate()
+        .map(|(index, line)| format!("[{}] {}", index + 1, line))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        r#"
+Extract a knowledge graph from the numbered lines of conversation: the people, organizations, places, projects and other things that are mentioned by name, and how they relate to each other. Use the name of a known entity when the conversation refers to it. Relations are short phrases such as "works at" or "is married to", read from source to target. Leave out small talk and anything that only matters in the moment.
+
+Respond only with JSON, where sources are the numbers of the lines the relation comes from:
+{{"entities": [{{"name": "Alice", "type": "person", "description": "<what the conversation says about it, or null>"}}], "relations": [{{"source": "Alice", "relation": "works at", "target": "Acme", "sources": [1]}}]}}
+
+Known entities:
+{known_joined}
+Lines of conversation:
+{lines_joined}
+"#
+    )
+}
+
+fn non_empty(value: Option<String>) -> Option<String> {
+    value
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Extracts the entities and relations mentioned in messages that were just
+/// summarized and merges them into the graph of the session's user, or of the
+/// session itself when it isn't linked to one. Returns how many entities and
+/// relations were extracted.
+pub async fn extract_graph(
+    session: &SessionKeys,
+    messages: &[MemoryMessage],
+    model: &str,
+    openai_client: &AnyOpenAIClient,
+    mut redis_conn: ConnectionManager,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let messages: Vec<&MemoryMessage> = messages
+        .iter()
+        .filter(|message| !message.is_legacy())
+        .collect();
+    if messages.is_empty() {
+        return Ok(0);
+    }
+
+    let keys = match session_user(session, &mut redis_conn).await? {
+        Some(user_id) => GraphKeys::user(session.namespace.as_deref(), &user_id),
+        None => GraphKeys::session(session),
+    };
+
+    let mut known: Vec<GraphEntity> = all_entities(&keys, &mut redis_conn)
+        .await?
+        .into_values()
+        .collect();
+    known.sort_by_key(|entity| std::cmp::Reverse(entity.updated_at));
+    let known: Vec<String> = known
+        .into_iter()
+        .take(KNOWN_ENTITIES)
+        .map(|entity| entity.name)
+        .collect();
+
+    let lines: Vec<String> = messages
+        .iter()
+        .map(|message| message.to_prompt_line())
+        .collect();
+    let prompt = extraction_prompt(&known, &lines);
+
+    let response = openai_client.create_chat_completion(model, &prompt).await?;
+    let completion = response
+        .choices
+        .first()
+        .ok_or("No completion found")?
+        .message
+        .content
+        .clone();
+
+    let extracted: ExtractedGraph = serde_json::from_str(extract_json(&completion))?;
+    let now = chrono::Utc::now().timestamp();
+
+    let entities: Vec<GraphEntity> = extracted
+        .entities
+        .into_iter()
+        .filter(|entity| !entity_key(&entity.name).is_empty())
+        .map(|entity| GraphEntity {
+            name: entity.name.trim().to_string(),
+            kind: non_empty(entity.kind),
+            description: non_empty(entity.description),
+            session_ids: vec![session.id.clone()],
+            updated_at: now,
+        })
+        .collect();
+
+    let relations: Vec<GraphRelation> = extracted
+        .relations
+        .into_iter()
+        .filter(|relation| {
+            [&relation.source, &relation.relation, &relation.target]
+                .iter()
+                .all(|part| !entity_key(part).is_empty())
+        })
+        .map(|relation| {
+            let mut source_message_ids: Vec<String> = relation
+                .sources
+                .iter()
+                .filter_map(|line| messages.get(line.wrapping_sub(1)))
+                .map(|message| message.id.clone())
+                .collect();
+            if source_message_ids.is_empty() {
+                source_message_ids = messages.iter().map(|message| message.id.clone()).collect();
+            }
+
+            GraphRelation {
+                source: relation.source.trim().to_string(),
+                relation: relation.relation.trim().to_string(),
+                target: relation.target.trim().to_string(),
+                session_ids: vec![session.id.clone()],
+                source_message_ids,
+                updated_at: now,
+            }
+        })
+        .collect();
+
+    let extracted = entities.len() + relations.len();
+    merge_into(&keys, entities, relations, &mut redis_conn).await?;
+
+    Ok(extracted)
+}
+
+/// Removes what the session contributed to the knowledge graph. Returns how
+/// many entities were deleted.
+pub async fn delete_session_graph(
+    session: &SessionKeys,
+    mut redis_conn: ConnectionManager,
+) -> RedisResult<usize> {
+    let mut deleted = drop_graph(&GraphKeys::session(session), &mut redis_conn).await?;
+
+    if let Some(user_id) = session_user(session, &mut redis_conn).await? {
+        let keys = GraphKeys::user(session.namespace.as_deref(), &user_id);
+        deleted += reassign_session(&keys, &session.id, None, &mut redis_conn).await?;
+    }
+
+    Ok(deleted)
+}
+
+/// Credits what the source session contributed to the knowledge graph to the
+/// target session instead. Takes the source's user, as its metadata has
+/// already been moved or deleted by then.
+pub async fn move_session_graph(
+    source: &SessionKeys,
+    target: &SessionKeys,
+    user_id: Option<&str>,
+    mut redis_conn: ConnectionManager,
+) -> RedisResult<()> {
+    let source_keys = GraphKeys::session(source);
+    let entities = all_entities(&source_keys, &mut redis_conn).await?;
+    let relations = all_relations(&source_keys, &mut redis_conn).await?;
+
+    if !entities.is_empty() || !relations.is_empty() {
+        let entities = entities
+            .into_values()
+            .map(|entity| GraphEntity {
+                session_ids: reassign(entity.session_ids, &source.id, Some(&target.id)),
+                ..entity
+            })
+            .collect();
+        let relations = relations
+            .into_values()
+            .map(|relation| GraphRelation {
+                session_ids: reassign(relation.session_ids, &source.id, Some(&target.id)),
+                ..relation
+            })
+            .collect();
+
+        merge_into(
+            &GraphKeys::session(target),
+            entities,
+            relations,
+            &mut redis_conn,
+        )
+        .await?;
+        drop_graph(&source_keys, &mut redis_conn).await?;
+    }
+
+    if let Some(user_id) = user_id {
+        let keys = GraphKeys::user(source.namespace.as_deref(), user_id);
+        reassign_session(&keys, &source.id, Some(&target.id), &mut redis_conn).await?;
+    }
+
+    Ok(())
+}
+
+/// Moves what the session contributed to the knowledge graph of its former
+/// user, or its own graph when it had none, to the graph of its new user.
+pub async fn rehome_session_graph(
+    session: &SessionKeys,
+    old_user_id: Option<&str>,
+    new_user_id: &str,
+    mut redis_conn: ConnectionManager,
+) -> RedisResult<()> {
+    let source_keys = match old_user_id {
+        Some(user_id) => GraphKeys::user(session.namespace.as_deref(), user_id),
+        None => GraphKeys::session(session),
+    };
+    let in_session = |session_ids: &Vec<String>| session_ids.contains(&session.id);
+
+    let entities: Vec<GraphEntity> = all_entities(&source_keys, &mut redis_conn)
+        .await?
+        .into_values()
+        .filter(|entity| in_session(&entity.session_ids))
+        .map(|entity| GraphEntity {
+            session_ids: vec![session.id.clone()],
+            ..entity
+        })
+        .collect();
+    let relations: Vec<GraphRelation> = all_relations(&source_keys, &mut redis_conn)
+        .await?
+        .into_values()
+        .filter(|relation| in_session(&relation.session_ids))
+        .map(|relation| GraphRelation {
+            session_ids: vec![session.id.clone()],
+            ..relation
+        })
+        .collect();
+
+    if !entities.is_empty() || !relations.is_empty() {
+        let target_keys = GraphKeys::user(session.namespace.as_deref(), new_user_id);
+        merge_into(&target_keys, entities, relations, &mut redis_conn).await?;
+    }
+
+    match old_user_id {
+        Some(_) => reassign_session(&source_keys, &session.id, None, &mut redis_conn).await?,
+        None => drop_graph(&source_keys, &mut redis_conn).await?,
+    };
+
+    Ok(())
+}
+
+#[get("/sessions/{session_id}/graph")]
+pub async fn get_session_graph(
+    session_id: web::Path<String>,
+    data: web::Data<Arc<AppState>>,
+    redis: web::Data<redis::Client>,
+    web::Query(graph_query): web::Query<GraphQuery>,
+    web::Query(namespace_query): web::Query<NamespaceQuery>,
+) -> actix_web::Result<impl Responder> {
+    let session = SessionKeys::parse(namespace_query.namespace.as_deref(), &session_id)
+        .map_err(error::ErrorBadRequest)?;
+
+    if !data.knowledge_graph {
+        return Ok(HttpResponse::BadRequest().body("Knowledge graph is disabled"));
+    }
+
+    let mut conn = redis
+        .get_tokio_connection_manager()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let entity = graph_query.entity.as_deref();
+    let mut graph = read_graph(&GraphKeys::session(&session), entity, None, &mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    // What the session added while linked to its user lives in the user's graph
+    let user_id = session_user(&session, &mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    if let Some(user_id) = user_id {
+        let keys = GraphKeys::user(session.namespace.as_deref(), &user_id);
+        let user_graph = read_graph(&keys, entity, Some(&session.id), &mut conn)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        graph.entities.extend(user_graph.entities);
+        graph.relations.extend(user_graph.relations);
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(graph))
+}
+
+#[get("/users/{user_id}/graph")]
+pub async fn get_user_graph(
+    user_id: web::Path<String>,
+    data: web::Data<Arc<AppState>>,
+    redis: web::Data<redis::Client>,
+    web::Query(graph_query): web::Query<GraphQuery>,
+    web::Query(namespace_query): web::Query<NamespaceQuery>,
+) -> actix_web::Result<impl Responder> {
+    let namespace = namespace_query.namespace.as_deref();
+    check_namespace(namespace).map_err(error::ErrorBadRequest)?;
+
+    if !data.knowledge_graph {
+        return Ok(HttpResponse::BadRequest().body("Knowledge graph is disabled"));
+    }
+
+    let mut conn = redis
+        .get_tokio_connection_manager()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let graph = read_graph(
+        &GraphKeys::user(namespace, &user_id),
+        graph_query.entity.as_deref(),
+        None,
+        &mut conn,
+    )
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(graph))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relation(source: &str, relation: &str, target: &str) -> GraphRelation {
+        GraphRelation {
+            source: source.to_string(),
+            relation: relation.to_string(),
+            target: target.to_string(),
+            session_ids: vec![],
+            source_message_ids: vec![],
+            updated_at: 0,
+        }
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn entities_match_regardless_of_case_and_spacing() {
+        assert_eq!(entity_key("  Acme   Corp "), "acme corp");
+        assert_eq!(entity_key("ACME corp"), entity_key("acme Corp"));
+        assert_eq!(entity_key(" \t"), "");
+    }
+
+    #[test]
+    fn relations_are_keyed_by_their_normalized_parts() {
+        assert_eq!(
+            relation_key(&relation("Alice", "Works  at", "ACME")),
+            "alice|works at|acme"
+        );
+        assert_eq!(
+            relation_key(&relation("alice", "works at", "acme")),
+            relation_key(&relation("ALICE", "Works At", "Acme"))
+        );
+    }
+
+    #[test]
+    fn pipes_in_names_dont_shift_relation_keys() {
+        assert_ne!(
+            relation_key(&relation("a|b", "c", "d")),
+            relation_key(&relation("a", "b|c", "d"))
+        );
+        assert_ne!(
+            relation_key(&relation("a%7Cb", "c", "d")),
+            relation_key(&relation("a|b", "c", "d"))
+        );
+    }
+
+    #[test]
+    fn reassigns_sessions_once() {
+        assert_eq!(reassign(ids(&["a", "b"]), "a", Some("c")), ids(&["b", "c"]));
+        assert_eq!(reassign(ids(&["a", "b"]), "a", Some("b")), ids(&["b"]));
+        assert_eq!(reassign(ids(&["a", "b"]), "a", None), ids(&["b"]));
+        assert_eq!(reassign(ids(&["b"]), "a", None), ids(&["b"]));
+    }
+}
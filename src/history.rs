@@ -0,0 +1,202 @@
+use crate::models::MemoryMessage;
+use crate::sessions::SessionKeys;
+use redis::aio::ConnectionManager;
+use redis::RedisResult;
+use std::collections::HashMap;
+
+// With long term memory, every message stored in a session is also kept by its
+// position, along with the position of each message ID. Unlike the session list
+// it isn't compacted, so search results can be shown with the messages around
+// them, embedded or not.
+
+/// Queues the recording of messages, the first being at `first_position` and
+/// the others following it, on the pipeline storing them.
+pub fn record_history(
+    pipe: &mut redis::Pipeline,
+    session: &SessionKeys,
+    messages: &[MemoryMessage],
+    first_position: i64,
+) {
+    let positioned: Vec<(i64, &MemoryMessage)> = (first_position..).zip(messages).collect();
+    record_positioned(pipe, session, &positioned);
+}
+
+fn record_positioned(
+    pipe: &mut redis::Pipeline,
+    session: &SessionKeys,
+    messages: &[(i64, &MemoryMessage)],
+) {
+    if messages.is_empty() {
+        return;
+    }
+
+    let entries: Vec<(i64, String)> = messages
+        .iter()
+        .map(|(position, message)| (*position, message.to_redis_entry()))
+        .collect();
+    let positions: Vec<(&str, i64)> = messages
+        .iter()
+        .map(|(position, message)| (message.id.as_str(), *position))
+        .collect();
+
+    pipe.cmd("HSET")
+        .arg(session.history())
+        .arg(entries)
+        .ignore();
+    pipe.cmd("HSET")
+        .arg(session.message_positions())
+        .arg(positions)
+        .ignore();
+}
+
+/// Every recorded message of the session with its position.
+pub async fn read_history(
+    session: &SessionKeys,
+    conn: &mut ConnectionManager,
+) -> RedisResult<Vec<(i64, MemoryMessage)>> {
+    let entries: HashMap<i64, String> = redis::Cmd::hgetall(session.history())
+        .query_async(conn)
+        .await?;
+
+    let mut messages: Vec<(i64, MemoryMessage)> = entries
+        .into_iter()
+        .filter_map(|(position, entry)| Some((position, MemoryMessage::from_redis_entry(&entry)?)))
+        .collect();
+    messages.sort_by_key(|(position, _)| *position);
+
+    Ok(messages)
+}
+
+/// Queues the recording of another session's messages, moved `offset`
+/// positions along, on the pipeline copying or merging them.
+pub fn copy_history(
+    pipe: &mut redis::Pipeline,
+    session: &SessionKeys,
+    messages: &[(i64, MemoryMessage)],
+    offset: i64,
+) {
+    let positioned: Vec<(i64, &MemoryMessage)> = messages
+        .iter()
+        .map(|(position, message)| (position + offset, message))
+        .collect();
+    record_positioned(pipe, session, &positioned);
+}
+
+/// Rewrites a recorded message, returning it as rewritten if it was recorded.
+pub async fn update_history(
+    session: &SessionKeys,
+    message_id: &str,
+    update: impl FnOnce(MemoryMessage) -> MemoryMessage,
+    conn: &mut ConnectionManager,
+) -> RedisResult<Option<MemoryMessage>> {
+    let Some((position, message)) = find_recorded(session, message_id, conn).await? else {
+        return Ok(None);
+    };
+
+    let updated = update(message);
+    redis::Cmd::hset(session.history(), position, updated.to_redis_entry())
+        .query_async::<_, ()>(conn)
+        .await?;
+
+    Ok(Some(updated))
+}
+
+/// Forgets a recorded message, returning it if it was recorded.
+pub async fn delete_history(
+    session: &SessionKeys,
+    message_id: &str,
+    conn: &mut ConnectionManager,
+) -> RedisResult<Option<MemoryMessage>> {
+    let Some((position, message)) = find_recorded(session, message_id, conn).await? else {
+        return Ok(None);
+    };
+
+    redis::pipe()
+        .atomic()
+        .cmd("HDEL")
+        .arg(session.history())
+        .arg(position)
+        .ignore()
+        .cmd("HDEL")
+        .arg(session.message_positions())
+        .arg(message_id)
+        .ignore()
+        .query_async::<_, ()>(conn)
+        .await?;
+
+    Ok(Some(message))
+}
+
+async fn find_recorded(
+    session: &SessionKeys,
+    message_id: &str,
+    conn: &mut ConnectionManager,
+) -> RedisResult<Option<(i64, MemoryMessage)>> {
+    let position: Option<i64> = redis::Cmd::hget(session.message_positions(), message_id)
+        .query_async(conn)
+        .await?;
+    let Some(position) = position else {
+        return Ok(None);
+    };
+
+    let entry: Option<String> = redis::Cmd::hget(session.history(), position)
+        .query_async(conn)
+        .await?;
+
+    Ok(entry
+        .and_then(|entry| MemoryMessage::from_redis_entry(&entry))
+        .map(|message| (position, message)))
+}
+
+// The positions up to `window` before and after the position, in order
+fn around(position: i64, window: i64) -> Vec<i64> {
+    (position - window..=position + window)
+        .filter(|neighbour| *neighbour != position)
+        .collect()
+}
+
+/// The recorded messages up to `window` positions before and after each of
+/// the positions, in one round trip. Missing positions are left out.
+pub async fn neighbours(
+    positions: &[(SessionKeys, i64)],
+    window: i64,
+    conn: &mut ConnectionManager,
+) -> RedisResult<Vec<Vec<(i64, MemoryMessage)>>> {
+    if positions.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut pipe = redis::pipe();
+    for (session, position) in positions {
+        pipe.cmd("HMGET")
+            .arg(session.history())
+            .arg(around(*position, window));
+    }
+    let entries: Vec<Vec<Option<String>>> = pipe.query_async(conn).await?;
+
+    Ok(positions
+        .iter()
+        .zip(entries)
+        .map(|((_, position), entries)| {
+            around(*position, window)
+                .into_iter()
+                .zip(entries)
+                .filter_map(|(neighbour, entry)| {
+                    Some((neighbour, MemoryMessage::from_redis_entry(&entry?)?))
+                })
+                .collect()
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn neighbours_surround_the_position() {
+        assert_eq!(around(5, 2), vec![3, 4, 6, 7]);
+        assert_eq!(around(0, 1), vec![-1, 1]);
+        assert!(around(5, 0).is_empty());
+    }
+}
@@ -0,0 +1,289 @@
+use crate::models::MemoryMessage;
+use crate::sessions::SessionKeys;
+use redis::aio::ConnectionManager;
+use redis::RedisResult;
+use std::collections::HashSet;
+
+// Held by a key whose request is still running, replaced by the response once
+// it succeeds
+const PENDING: &str = "pending";
+
+// Seconds a pending claim outlives its request if that dies before completing or
+// releasing it, longer than storing messages takes, waiting on the session lock
+// included
+const PENDING_TTL: usize = 60;
+
+pub enum IdempotencyClaim {
+    /// The key is new, the request should run.
+    Claimed,
+    /// A request with the key is still running.
+    Pending,
+    /// A request with the key already succeeded with this response.
+    Completed(String),
+}
+
+/// Marks the key as taken by a running request, unless it was already claimed.
+pub async fn claim_idempotency_key(
+    session: &SessionKeys,
+    key: &str,
+    conn: &mut ConnectionManager,
+) -> RedisResult<IdempotencyClaim> {
+    let redis_key = session.idempotency(key);
+    let claimed: Option<String> = redis::cmd("SET")
+        .arg(&redis_key)
+        .arg(PENDING)
+        .arg("NX")
+        .arg("EX")
+        .arg(PENDING_TTL)
+        .query_async(conn)
+        .await?;
+
+    if claimed.is_some() {
+        return Ok(IdempotencyClaim::Claimed);
+    }
+
+    let original: Option<String> = redis::Cmd::get(&redis_key).query_async(conn).await?;
+    Ok(existing_claim(original))
+}
+
+// What a key that couldn't be claimed holds, a key that expired since counts as
+// still pending
+fn existing_claim(original: Option<String>) -> IdempotencyClaim {
+    match original {
+        Some(original) if original != PENDING => IdempotencyClaim::Completed(original),
+        _ => IdempotencyClaim::Pending,
+    }
+}
+
+/// Stores the response replays of the key get once its request succeeded, for
+/// `ttl` seconds.
+pub async fn complete_idempotency_key(
+    session: &SessionKeys,
+    key: &str,
+    response: &str,
+    ttl: usize,
+    conn: &mut ConnectionManager,
+) -> RedisResult<()> {
+    // Completed keys are listed by when they expire, so a rename can find them
+    let keys_key = session.idempotency_keys();
+    let now = chrono::Utc::now().timestamp();
+
+    redis::pipe()
+        .cmd("SET")
+        .arg(session.idempotency(key))
+        .arg(response)
+        .arg("EX")
+        .arg(ttl)
+        .ignore()
+        .cmd("ZREMRANGEBYSCORE")
+        .arg(&keys_key)
+        .arg("-inf")
+        .arg(now)
+        .ignore()
+        .cmd("ZADD")
+        .arg(&keys_key)
+        .arg(now + ttl as i64)
+        .arg(key)
+        .ignore()
+        .cmd("EXPIRE")
+        .arg(&keys_key)
+        .arg(ttl)
+        .ignore()
+        .query_async(conn)
+        .await
+}
+
+/// Lets a retry go through if the original request failed after claiming its key.
+pub async fn release_idempotency_key(
+    session: &SessionKeys,
+    key: &str,
+    conn: &mut ConnectionManager,
+) -> RedisResult<()> {
+    redis::Cmd::del(session.idempotency(key))
+        .query_async(conn)
+        .await
+}
+
+/// Drops messages whose `client_id` was already seen for this session within `ttl`
+/// seconds, or earlier in the same request. Nothing is recorded until the
+/// messages are stored, see `record_client_ids`.
+pub async fn filter_seen_client_ids(
+    session: &SessionKeys,
+    messages: Vec<MemoryMessage>,
+    ttl: usize,
+    conn: &mut ConnectionManager,
+) -> RedisResult<Vec<MemoryMessage>> {
+    if messages.iter().all(|message| message.client_id.is_none()) {
+        return Ok(messages);
+    }
+
+    let client_ids_key = session.client_ids();
+    let now = chrono::Utc::now().timestamp();
+
+    let mut pipe = redis::pipe();
+    pipe.cmd("ZREMRANGEBYSCORE")
+        .arg(&client_ids_key)
+        .arg("-inf")
+        .arg(now - ttl as i64)
+        .ignore();
+
+    for client_id in messages
+        .iter()
+        .filter_map(|message| message.client_id.as_ref())
+    {
+        pipe.cmd("ZSCORE").arg(&client_ids_key).arg(client_id);
+    }
+
+    let scores: Vec<Option<f64>> = pipe.query_async(conn).await?;
+    Ok(unseen_messages(messages, scores))
+}
+
+// `scores` has the stored score of each `client_id`, in the order of the
+// messages that have one
+fn unseen_messages(messages: Vec<MemoryMessage>, scores: Vec<Option<f64>>) -> Vec<MemoryMessage> {
+    let mut scores = scores.into_iter();
+    let mut seen = HashSet::new();
+
+    messages
+        .into_iter()
+        .filter(|message| match &message.client_id {
+            Some(client_id) => scores.next().flatten().is_none() && seen.insert(client_id.clone()),
+            None => true,
+        })
+        .collect()
+}
+
+/// Queues the recording of the messages' `client_id`s on the pipeline storing
+/// them, so a failed store doesn't make its retry look like a duplicate.
+pub fn record_client_ids(
+    pipe: &mut redis::Pipeline,
+    session: &SessionKeys,
+    messages: &[MemoryMessage],
+    ttl: usize,
+) {
+    let client_ids: Vec<&String> = messages
+        .iter()
+        .filter_map(|message| message.client_id.as_ref())
+        .collect();
+    if client_ids.is_empty() {
+        return;
+    }
+
+    let client_ids_key = session.client_ids();
+    let now = chrono::Utc::now().timestamp();
+    for client_id in client_ids {
+        pipe.cmd("ZADD")
+            .arg(&client_ids_key)
+            .arg(now)
+            .arg(client_id)
+            .ignore();
+    }
+    pipe.cmd("EXPIRE").arg(&client_ids_key).arg(ttl).ignore();
+}
+
+/// Queues the move of the session's completed idempotency keys, with what's
+/// left of their expiry, to the target session on the pipeline renaming it. The
+/// list of the keys moves along with the session's other keys.
+pub async fn move_idempotency_keys(
+    pipe: &mut redis::Pipeline,
+    source: &SessionKeys,
+    target: &SessionKeys,
+    conn: &mut ConnectionManager,
+) -> RedisResult<()> {
+    let keys: Vec<String> = redis::cmd("ZRANGEBYSCORE")
+        .arg(source.idempotency_keys())
+        .arg(chrono::Utc::now().timestamp())
+        .arg("+inf")
+        .query_async(conn)
+        .await?;
+    if keys.is_empty() {
+        return Ok(());
+    }
+
+    let mut read = redis::pipe();
+    for key in &keys {
+        let source_key = source.idempotency(key);
+        read.cmd("GET")
+            .arg(&source_key)
+            .cmd("PTTL")
+            .arg(&source_key);
+    }
+    let values: Vec<(Option<String>, i64)> = read.query_async(conn).await?;
+
+    for (key, (value, ttl)) in keys.iter().zip(values) {
+        // Expired since it was listed
+        let Some(value) = value else {
+            continue;
+        };
+
+        pipe.cmd("SET").arg(target.idempotency(key)).arg(value);
+        if ttl > 0 {
+            pipe.arg("PX").arg(ttl);
+        }
+        pipe.ignore();
+        pipe.cmd("DEL").arg(source.idempotency(key)).ignore();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message(content: &str, client_id: Option<&str>) -> MemoryMessage {
+        serde_json::from_value(json!({
+            "role": "user",
+            "content": content,
+            "client_id": client_id,
+        }))
+        .unwrap()
+    }
+
+    fn contents(messages: &[MemoryMessage]) -> Vec<String> {
+        messages
+            .iter()
+            .map(|message| message.content.render())
+            .collect()
+    }
+
+    #[test]
+    fn drops_messages_seen_before() {
+        let messages = vec![
+            message("a", Some("1")),
+            message("b", None),
+            message("c", Some("2")),
+        ];
+
+        let unseen = unseen_messages(messages, vec![Some(1700000000.0), None]);
+
+        assert_eq!(contents(&unseen), ["b", "c"]);
+    }
+
+    #[test]
+    fn drops_repeats_within_a_request() {
+        let messages = vec![
+            message("a", Some("1")),
+            message("again", Some("1")),
+            message("b", Some("2")),
+        ];
+
+        let unseen = unseen_messages(messages, vec![None, None, None]);
+
+        assert_eq!(contents(&unseen), ["a", "b"]);
+    }
+
+    #[test]
+    fn replays_completed_keys_only() {
+        assert!(matches!(
+            existing_claim(Some(String::from(r#"{"status":"Ok"}"#))),
+            IdempotencyClaim::Completed(response) if response == r#"{"status":"Ok"}"#
+        ));
+        assert!(matches!(
+            existing_claim(Some(String::from(PENDING))),
+            IdempotencyClaim::Pending
+        ));
+        assert!(matches!(existing_claim(None), IdempotencyClaim::Pending));
+    }
+}
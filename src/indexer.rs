@@ -0,0 +1,488 @@
+use crate::indexing_rules::IndexingFilter;
+use crate::long_term_memory::{add_vector_doc, pending_vectors, PendingVector};
+use crate::models::{AppState, IndexingMetrics, MemoryMessage};
+use crate::sessions::SessionKeys;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+// Attempts at indexing a batch, or a job split off a failed batch, waiting
+// twice as long before each retry
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Messages of a session waiting to be embedded into long term memory.
+#[derive(Clone)]
+pub struct IndexJob {
+    pub session: SessionKeys,
+    pub messages: Vec<MemoryMessage>,
+    pub first_position: i64,
+    pub filter: IndexingFilter,
+    epoch: u64,
+    // When the job was queued, or moved to its current session
+    since: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    enqueued_jobs: AtomicU64,
+    blocked_enqueues: AtomicU64,
+    enqueue_wait_ms: AtomicU64,
+    dropped_jobs: AtomicU64,
+    batches: AtomicU64,
+    retries: AtomicU64,
+    split_batches: AtomicU64,
+    failed_jobs: AtomicU64,
+    indexed_messages: AtomicU64,
+    embedded_chunks: AtomicU64,
+}
+
+// What happened to a session's keys at some point of the queue
+struct SessionChange {
+    epoch: u64,
+    // The session its messages went to and how far their positions shifted,
+    // none when it was deleted
+    moved_to: Option<(SessionKeys, i64)>,
+}
+
+/// Queues messages for the indexing worker, which batches them across
+/// sessions into embedding calls and pipelined writes. When the queue is full,
+/// `post_memory` waits for room instead of piling up requests to OpenAI.
+pub struct Indexer {
+    sender: mpsc::Sender<IndexJob>,
+    capacity: usize,
+    counters: Counters,
+    // Jobs are numbered as they're queued. Deleting a session drops its jobs
+    // queued before, renaming or merging it moves them along.
+    epoch: AtomicU64,
+    changes: Mutex<HashMap<String, Vec<SessionChange>>>,
+    // Held while queueing so jobs go in in epoch order
+    send_lock: tokio::sync::Mutex<()>,
+    // Held by the worker from resolving a batch's sessions until its vectors
+    // are written, so a change it missed waits for the write to be done
+    write_lock: tokio::sync::Mutex<()>,
+}
+
+impl Indexer {
+    pub fn new(capacity: usize) -> (Self, mpsc::Receiver<IndexJob>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let indexer = Indexer {
+            sender,
+            capacity,
+            counters: Counters::default(),
+            epoch: AtomicU64::new(0),
+            changes: Mutex::new(HashMap::new()),
+            send_lock: tokio::sync::Mutex::new(()),
+            write_lock: tokio::sync::Mutex::new(()),
+        };
+
+        (indexer, receiver)
+    }
+
+    pub async fn enqueue(
+        &self,
+        session: SessionKeys,
+        messages: Vec<MemoryMessage>,
+        first_position: i64,
+        filter: IndexingFilter,
+    ) {
+        let _send_lock = self.send_lock.lock().await;
+        let epoch = self.epoch.fetch_add(1, Ordering::SeqCst);
+        let job = IndexJob {
+            session,
+            messages,
+            first_position,
+            filter,
+            epoch,
+            since: epoch,
+        };
+
+        let job = match self.sender.try_send(job) {
+            Ok(()) => None,
+            Err(mpsc::error::TrySendError::Full(job)) => Some(job),
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                log::error!("Indexing queue is closed, dropping messages");
+                return;
+            }
+        };
+
+        if let Some(job) = job {
+            self.counters
+                .blocked_enqueues
+                .fetch_add(1, Ordering::Relaxed);
+            let started = Instant::now();
+            if self.sender.send(job).await.is_err() {
+                log::error!("Indexing queue is closed, dropping messages");
+                return;
+            }
+            self.counters
+                .enqueue_wait_ms
+                .fetch_add(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+        }
+
+        self.counters.enqueued_jobs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Drops the session's queued jobs, for when it's deleted or expires.
+    /// Returns once no vectors of the session are being written, so they can be
+    /// deleted.
+    pub async fn cancel(&self, session: &SessionKeys) {
+        self.record_change(session, None).await;
+    }
+
+    /// Indexes the session's queued jobs into `target` instead, their positions
+    /// shifted by `position_offset`, for when it's renamed or merged. Returns
+    /// once no vectors of the session are being written, so they can be moved.
+    pub async fn redirect(
+        &self,
+        session: &SessionKeys,
+        target: &SessionKeys,
+        position_offset: i64,
+    ) {
+        self.record_change(session, Some((target.clone(), position_offset)))
+            .await;
+    }
+
+    async fn record_change(&self, session: &SessionKeys, moved_to: Option<(SessionKeys, i64)>) {
+        {
+            let mut changes = self.changes.lock().unwrap();
+            // Numbered under the lock so each session's changes stay in order
+            let epoch = self.epoch.fetch_add(1, Ordering::SeqCst);
+            changes
+                .entry(session.scoped_id())
+                .or_default()
+                .push(SessionChange { epoch, moved_to });
+        }
+
+        // A batch resolved before the change may still be writing to the session
+        let _write_lock = self.write_lock.lock().await;
+    }
+
+    // Follows the renames and merges of the job's session since it was queued,
+    // returning false when the session was deleted in the meantime
+    fn resolve(&self, job: &mut IndexJob, pending: &mut [PendingVector]) -> bool {
+        let changes = self.changes.lock().unwrap();
+
+        loop {
+            let Some(change) = changes
+                .get(&job.session.scoped_id())
+                .and_then(|changes| changes.iter().find(|change| change.epoch > job.since))
+            else {
+                return true;
+            };
+            let Some((target, offset)) = &change.moved_to else {
+                return false;
+            };
+
+            job.session = target.clone();
+            job.since = change.epoch;
+            job.first_position += offset;
+            for pending in pending.iter_mut() {
+                pending.position += offset;
+            }
+        }
+    }
+
+    // Jobs come out in the order they went in, so changes older than a
+    // processed job can't apply to any job still queued
+    fn forget_changes(&self, processed_epoch: u64) {
+        let mut changes = self.changes.lock().unwrap();
+        for session_changes in changes.values_mut() {
+            session_changes.retain(|change| change.epoch > processed_epoch);
+        }
+        changes.retain(|_, session_changes| !session_changes.is_empty());
+    }
+
+    pub fn metrics(&self) -> IndexingMetrics {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        IndexingMetrics {
+            queue_capacity: self.capacity,
+            queue_depth: self.capacity - self.sender.capacity(),
+            enqueued_jobs: load(&self.counters.enqueued_jobs),
+            blocked_enqueues: load(&self.counters.blocked_enqueues),
+            enqueue_wait_ms: load(&self.counters.enqueue_wait_ms),
+            dropped_jobs: load(&self.counters.dropped_jobs),
+            batches: load(&self.counters.batches),
+            retries: load(&self.counters.retries),
+            split_batches: load(&self.counters.split_batches),
+            failed_jobs: load(&self.counters.failed_jobs),
+            indexed_messages: load(&self.counters.indexed_messages),
+            embedded_chunks: load(&self.counters.embedded_chunks),
+        }
+    }
+}
+
+/// Embeds queued messages in batches of up to `batch_size` chunks, waiting at
+/// most `batch_latency` after the first job of a batch for more to come in.
+pub async fn run_indexer(
+    mut receiver: mpsc::Receiver<IndexJob>,
+    redis: redis::Client,
+    state: Arc<AppState>,
+    batch_size: usize,
+    batch_latency: Duration,
+) {
+    let mut conn = match redis.get_tokio_connection_manager().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            log::error!("Indexer could not connect to redis: {:?}", e);
+            return;
+        }
+    };
+
+    while let Some(job) = receiver.recv().await {
+        let deadline = tokio::time::Instant::now() + batch_latency;
+        let mut batch = vec![prepare(&state, job)];
+        let mut chunks = batch[0].1.len();
+
+        while chunks < batch_size {
+            match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(job)) => {
+                    let job = prepare(&state, job);
+                    chunks += job.1.len();
+                    batch.push(job);
+                }
+                _ => break,
+            }
+        }
+
+        let processed_epoch = batch.iter().map(|(job, _)| job.epoch).max().unwrap_or(0);
+
+        state
+            .indexer
+            .counters
+            .batches
+            .fetch_add(1, Ordering::Relaxed);
+        index_with_fallback(&state, batch, &mut conn).await;
+
+        state.indexer.forget_changes(processed_epoch);
+    }
+}
+
+// Indexes the batch, retrying with backoff. If it keeps failing, its jobs are
+// indexed one by one so a bad input only loses its own session's messages.
+async fn index_with_fallback(
+    state: &AppState,
+    batch: Vec<(IndexJob, Vec<PendingVector>)>,
+    conn: &mut redis::aio::ConnectionManager,
+) {
+    let counters = &state.indexer.counters;
+
+    let Err(e) = index_with_retries(state, &batch, conn).await else {
+        return;
+    };
+
+    if batch.len() > 1 {
+        counters.split_batches.fetch_add(1, Ordering::Relaxed);
+        log::warn!(
+            "Error indexing a batch of {} jobs, indexing them one by one: {}",
+            batch.len(),
+            e
+        );
+
+        for job in batch {
+            if let Err(e) = index_with_retries(state, std::slice::from_ref(&job), conn).await {
+                counters.failed_jobs.fetch_add(1, Ordering::Relaxed);
+                log::error!(
+                    "Error indexing messages of session {}: {}",
+                    job.0.session.scoped_id(),
+                    e
+                );
+            }
+        }
+    } else {
+        counters.failed_jobs.fetch_add(1, Ordering::Relaxed);
+        log::error!(
+            "Error indexing messages of session {}: {}",
+            batch[0].0.session.scoped_id(),
+            e
+        );
+    }
+}
+
+async fn index_with_retries(
+    state: &AppState,
+    batch: &[(IndexJob, Vec<PendingVector>)],
+    conn: &mut redis::aio::ConnectionManager,
+) -> Result<(), String> {
+    let mut backoff = RETRY_BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        // Errors aren't Send, they're only kept as text across the retries
+        let result = index_batch(state, batch.to_vec(), conn)
+            .await
+            .map_err(|e| format!("{:?}", e));
+
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt >= MAX_ATTEMPTS => return Err(e),
+            Err(e) => {
+                log::warn!("Error indexing messages, retrying: {}", e);
+                state
+                    .indexer
+                    .counters
+                    .retries
+                    .fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+fn prepare(state: &AppState, job: IndexJob) -> (IndexJob, Vec<PendingVector>) {
+    let pending = pending_vectors(
+        &job.messages,
+        job.first_position,
+        &job.filter,
+        &state.indexed_parts,
+        &state.chunker,
+    );
+
+    (job, pending)
+}
+
+async fn index_batch(
+    state: &AppState,
+    mut batch: Vec<(IndexJob, Vec<PendingVector>)>,
+    conn: &mut redis::aio::ConnectionManager,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let counters = &state.indexer.counters;
+    batch.retain_mut(|(job, pending)| {
+        let kept = state.indexer.resolve(job, pending);
+        if !kept {
+            counters.dropped_jobs.fetch_add(1, Ordering::Relaxed);
+        }
+        kept
+    });
+
+    let contents: Vec<String> = batch
+        .iter()
+        .flat_map(|(_, pending)| pending.iter().map(|pending| pending.content.clone()))
+        .collect();
+    if contents.is_empty() {
+        return Ok(());
+    }
+
+    let client_wrapper = state.openai_pool.get().await?;
+    let mut embeddings = state
+        .embeddings
+        .embed(contents, client_wrapper.deref(), conn)
+        .await?
+        .into_iter();
+    let batch: Vec<(IndexJob, Vec<PendingVector>, Vec<Vec<f32>>)> = batch
+        .into_iter()
+        .map(|(job, pending)| {
+            let job_embeddings = embeddings.by_ref().take(pending.len()).collect();
+            (job, pending, job_embeddings)
+        })
+        .collect();
+
+    // Deleted, renamed or merged while its embeddings were being created. The
+    // changes made from here on wait for the vectors to be written.
+    let _write_lock = state.indexer.write_lock.lock().await;
+    let mut resolved = Vec::with_capacity(batch.len());
+    for (mut job, mut pending, job_embeddings) in batch {
+        if state.indexer.resolve(&mut job, &mut pending) {
+            resolved.push((job, pending, job_embeddings));
+        } else {
+            counters.dropped_jobs.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let mut pipe = redis::pipe();
+    for (job, _, _) in &resolved {
+        pipe.cmd("HGET").arg(job.session.meta()).arg("user_id");
+    }
+    let user_ids: Vec<Option<String>> = pipe.query_async(conn).await?;
+
+    let mut pipe = redis::pipe();
+    let mut embedded = 0;
+    let mut indexed = 0;
+    for ((job, pending, job_embeddings), user_id) in resolved.into_iter().zip(user_ids) {
+        for (pending, embedding) in pending.iter().zip(job_embeddings) {
+            add_vector_doc(
+                &mut pipe,
+                &job.session,
+                user_id.as_deref(),
+                pending,
+                embedding,
+            );
+            embedded += 1;
+        }
+        indexed += pending.iter().filter(|pending| pending.chunk == 0).count();
+    }
+    pipe.query_async::<_, ()>(conn).await?;
+
+    counters
+        .embedded_chunks
+        .fetch_add(embedded, Ordering::Relaxed);
+    counters
+        .indexed_messages
+        .fetch_add(indexed as u64, Ordering::Relaxed);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::IndexingRules;
+
+    fn job(indexer: &Indexer, session: &SessionKeys) -> IndexJob {
+        let epoch = indexer.epoch.fetch_add(1, Ordering::SeqCst);
+        IndexJob {
+            session: session.clone(),
+            messages: vec![],
+            first_position: 0,
+            filter: IndexingFilter::new(&IndexingRules::default()).unwrap(),
+            epoch,
+            since: epoch,
+        }
+    }
+
+    #[tokio::test]
+    async fn cancel_waits_for_the_write_in_flight() {
+        let (indexer, _receiver) = Indexer::new(1);
+        let indexer = Arc::new(indexer);
+        let session = SessionKeys::parse(None, "s1").unwrap();
+        let mut queued = job(&indexer, &session);
+
+        let write_lock = indexer.write_lock.lock().await;
+        let cancel = tokio::spawn({
+            let indexer = Arc::clone(&indexer);
+            let session = session.clone();
+            async move { indexer.cancel(&session).await }
+        });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!cancel.is_finished());
+
+        drop(write_lock);
+        cancel.await.unwrap();
+        assert!(!indexer.resolve(&mut queued, &mut []));
+    }
+
+    #[tokio::test]
+    async fn redirected_jobs_follow_their_session() {
+        let (indexer, _receiver) = Indexer::new(1);
+        let session = SessionKeys::parse(None, "s1").unwrap();
+        let target = SessionKeys::parse(None, "s2").unwrap();
+        let mut queued = job(&indexer, &session);
+
+        indexer.redirect(&session, &target, 5).await;
+
+        assert!(indexer.resolve(&mut queued, &mut []));
+        assert_eq!(queued.session.id, "s2");
+        assert_eq!(queued.first_position, 5);
+
+        let mut later = job(&indexer, &session);
+        assert!(indexer.resolve(&mut later, &mut []));
+        assert_eq!(later.session.id, "s1");
+    }
+}
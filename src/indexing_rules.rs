@@ -0,0 +1,265 @@
+use crate::models::{
+    AckResponse, AppState, CanonicalRole, IndexingRules, MemoryMessage, MotorheadError,
+};
+use crate::sessions::{check_namespace, namespaced_key};
+use actix_web::{delete, error, get, put, web, HttpResponse, Responder};
+use regex::Regex;
+use std::sync::Arc;
+use tiktoken_rs::cl100k_base_singleton;
+
+fn indexing_rules_key(namespace: &str) -> String {
+    namespaced_key(Some(namespace), "indexing_rules")
+}
+
+/// Indexing rules ready to be checked against messages.
+#[derive(Clone)]
+pub struct IndexingFilter {
+    roles: Option<Vec<CanonicalRole>>,
+    min_tokens: usize,
+    exclude: Vec<Regex>,
+}
+
+impl IndexingFilter {
+    pub fn new(rules: &IndexingRules) -> Result<Self, String> {
+        let exclude = rules
+            .exclude
+            .iter()
+            .flatten()
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .map_err(|err| format!("Invalid exclusion pattern {:?}: {}", pattern, err))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(IndexingFilter {
+            roles: rules.roles.clone(),
+            min_tokens: rules.min_tokens.unwrap_or(0),
+            exclude,
+        })
+    }
+
+    /// Whether the message, whose embedded text is `content`, belongs in long
+    /// term memory. Messages with `"index": false` in their metadata never do.
+    pub fn indexes(&self, message: &MemoryMessage, content: &str) -> bool {
+        let opted_out = message
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.get("index"))
+            .and_then(serde_json::Value::as_bool)
+            == Some(false);
+        if opted_out {
+            return false;
+        }
+
+        if let Some(roles) = &self.roles {
+            if !message
+                .canonical_role
+                .is_some_and(|role| roles.contains(&role))
+            {
+                return false;
+            }
+        }
+
+        if self.exclude.iter().any(|pattern| pattern.is_match(content)) {
+            return false;
+        }
+
+        self.min_tokens == 0
+            || cl100k_base_singleton()
+                .lock()
+                .encode_ordinary(content)
+                .len()
+                >= self.min_tokens
+    }
+}
+
+/// The global indexing rules, overridden by the namespace's own.
+pub async fn indexing_filter(
+    state: &AppState,
+    namespace: Option<&str>,
+    conn: &mut redis::aio::ConnectionManager,
+) -> Result<IndexingFilter, MotorheadError> {
+    let rules = match namespace {
+        Some(namespace) => {
+            let stored: Option<String> = redis::Cmd::get(indexing_rules_key(namespace))
+                .query_async(conn)
+                .await?;
+
+            match stored {
+                Some(stored) => serde_json::from_str::<IndexingRules>(&stored)
+                    .map_err(|e| MotorheadError::InvalidRequest(e.to_string()))?
+                    .or(&state.indexing_rules),
+                None => state.indexing_rules.clone(),
+            }
+        }
+        None => state.indexing_rules.clone(),
+    };
+
+    IndexingFilter::new(&rules).map_err(MotorheadError::InvalidRequest)
+}
+
+#[get("/namespaces/{namespace}/indexing_rules")]
+pub async fn get_indexing_rules(
+    namespace: web::Path<String>,
+    redis: web::Data<redis::Client>,
+) -> actix_web::Result<impl Responder> {
+    check_namespace(Some(&namespace)).map_err(error::ErrorBadRequest)?;
+
+    let mut conn = redis
+        .get_tokio_connection_manager()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let stored: Option<String> = redis::Cmd::get(indexing_rules_key(&namespace))
+        .query_async(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let response: IndexingRules = match stored {
+        Some(stored) => serde_json::from_str(&stored).map_err(error::ErrorInternalServerError)?,
+        None => IndexingRules::default(),
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(response))
+}
+
+#[put("/namespaces/{namespace}/indexing_rules")]
+pub async fn put_indexing_rules(
+    namespace: web::Path<String>,
+    web::Json(rules): web::Json<IndexingRules>,
+    data: web::Data<Arc<AppState>>,
+    redis: web::Data<redis::Client>,
+) -> actix_web::Result<impl Responder> {
+    check_namespace(Some(&namespace)).map_err(error::ErrorBadRequest)?;
+    IndexingFilter::new(&rules.or(&data.indexing_rules)).map_err(error::ErrorBadRequest)?;
+
+    let mut conn = redis
+        .get_tokio_connection_manager()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let stored = serde_json::to_string(&rules).map_err(error::ErrorInternalServerError)?;
+    redis::Cmd::set(indexing_rules_key(&namespace), stored)
+        .query_async::<_, ()>(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let response = AckResponse { status: "Ok" };
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(response))
+}
+
+#[delete("/namespaces/{namespace}/indexing_rules")]
+pub async fn delete_indexing_rules(
+    namespace: web::Path<String>,
+    redis: web::Data<redis::Client>,
+) -> actix_web::Result<impl Responder> {
+    check_namespace(Some(&namespace)).map_err(error::ErrorBadRequest)?;
+
+    let mut conn = redis
+        .get_tokio_connection_manager()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    redis::Cmd::del(indexing_rules_key(&namespace))
+        .query_async::<_, ()>(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let response = AckResponse { status: "Ok" };
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn filter(rules: serde_json::Value) -> IndexingFilter {
+        IndexingFilter::new(&serde_json::from_value(rules).unwrap()).unwrap()
+    }
+
+    fn message(role: &str, content: &str, metadata: Option<serde_json::Value>) -> MemoryMessage {
+        serde_json::from_value(json!({
+            "role": role,
+            "canonical_role": role,
+            "content": content,
+            "metadata": metadata,
+        }))
+        .unwrap()
+    }
+
+    fn indexes(filter: &IndexingFilter, message: &MemoryMessage) -> bool {
+        filter.indexes(message, &message.content.render())
+    }
+
+    #[test]
+    fn no_rules_index_everything() {
+        let filter = filter(json!({}));
+
+        assert!(indexes(&filter, &message("user", "hi", None)));
+        assert!(indexes(&filter, &message("tool", "", None)));
+    }
+
+    #[test]
+    fn metadata_opts_messages_out() {
+        let filter = filter(json!({}));
+
+        assert!(!indexes(
+            &filter,
+            &message("user", "hi", Some(json!({"index": false})))
+        ));
+        assert!(indexes(
+            &filter,
+            &message("user", "hi", Some(json!({"index": true})))
+        ));
+        assert!(indexes(
+            &filter,
+            &message("user", "hi", Some(json!({"index": "no"})))
+        ));
+    }
+
+    #[test]
+    fn only_listed_roles_are_indexed() {
+        let filter = filter(json!({"roles": ["user", "assistant"]}));
+
+        assert!(indexes(&filter, &message("user", "hi", None)));
+        assert!(indexes(&filter, &message("assistant", "hi", None)));
+        assert!(!indexes(&filter, &message("tool", "hi", None)));
+
+        let mut unknown = message("user", "hi", None);
+        unknown.canonical_role = None;
+        assert!(!indexes(&filter, &unknown));
+    }
+
+    #[test]
+    fn excluded_patterns_are_not_indexed() {
+        let filter = filter(json!({"exclude": ["^/", "(?i)password"]}));
+
+        assert!(!indexes(&filter, &message("user", "/reset", None)));
+        assert!(!indexes(&filter, &message("user", "my PASSWORD is", None)));
+        assert!(indexes(&filter, &message("user", "reset /tmp", None)));
+    }
+
+    #[test]
+    fn short_messages_are_not_indexed() {
+        let filter = filter(json!({"min_tokens": 3}));
+
+        assert!(!indexes(&filter, &message("user", "ok", None)));
+        assert!(indexes(
+            &filter,
+            &message("user", "Remember to water the plants", None)
+        ));
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        let rules = serde_json::from_value(json!({"exclude": ["("]})).unwrap();
+
+        assert!(IndexingFilter::new(&rules).is_err());
+    }
+}
@@ -1,10 +1,22 @@
-use crate::models::{parse_redisearch_response, AnyOpenAIClient, MemoryMessage, RedisearchResult};
+use crate::chunker::Chunker;
+use crate::embedding_cache::EmbeddingCache;
+use crate::history;
+use crate::indexing_rules::IndexingFilter;
+use crate::models::{
+    parse_redisearch_response, AnyOpenAIClient, AppState, CanonicalRole, Fusion, IndexedParts,
+    MemoryMessage, NamespaceSearchPayload, RedisearchResult, RoleMapping, SearchMode,
+    SearchPayload,
+};
+use crate::reducer::extract_json;
+use crate::sessions::SessionKeys;
 use byteorder::{LittleEndian, WriteBytesExt};
 use nanoid::nanoid;
 use redis::Value;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use std::io::Cursor;
 
-fn encode(fs: Vec<f32>) -> Vec<u8> {
+pub fn encode(fs: Vec<f32>) -> Vec<u8> {
     let mut buf = Cursor::new(Vec::new());
     for f in fs {
         buf.write_f32::<LittleEndian>(f).unwrap();
@@ -12,48 +24,1082 @@ fn encode(fs: Vec<f32>) -> Vec<u8> {
     buf.into_inner()
 }
 
-pub async fn index_messages(
-    messages: Vec<MemoryMessage>,
-    session_id: String,
+// Vector docs are keyed by message ID so they can be found again when a single
+// message is edited or deleted. Legacy messages without an ID get a random one.
+fn vector_key(message_id: &str) -> String {
+    if message_id.is_empty() {
+        format!("motorhead:{}", nanoid!())
+    } else {
+        format!("motorhead:{}", message_id)
+    }
+}
+
+// Vector docs are tagged with their session's namespace. Namespaces can't contain
+// `:`, so docs of sessions outside any namespace get a tag that can't clash.
+pub const GLOBAL_NAMESPACE_TAG: &str = ":global";
+
+pub fn namespace_tag(namespace: Option<&str>) -> &str {
+    namespace.unwrap_or(GLOBAL_NAMESPACE_TAG)
+}
+
+// Matches the session's docs, the same session ID may be used in other namespaces
+pub fn session_filter(session: &SessionKeys) -> String {
+    format!(
+        "@session:{{{}}} @namespace:{{{}}}",
+        escape_tag(&session.id),
+        escape_tag(namespace_tag(session.namespace.as_deref()))
+    )
+}
+
+// Message IDs are unique, but a request should only touch its own session's docs
+async fn vector_in_session(
+    key: &str,
+    session: &SessionKeys,
+    redis_conn: &mut redis::aio::ConnectionManager,
+) -> Result<bool, redis::RedisError> {
+    let (session_id, namespace): (Option<String>, Option<String>) = redis::cmd("HMGET")
+        .arg(key)
+        .arg("session")
+        .arg("namespace")
+        .query_async(redis_conn)
+        .await?;
+
+    Ok(session_id.as_deref() == Some(session.id.as_str())
+        && namespace.as_deref().unwrap_or(GLOBAL_NAMESPACE_TAG)
+            == namespace_tag(session.namespace.as_deref()))
+}
+
+// Docs are also tagged with the session's user, so a user's memory can be
+// searched across all of their sessions.
+async fn session_user(
+    session: &SessionKeys,
+    redis_conn: &mut redis::aio::ConnectionManager,
+) -> Result<Option<String>, redis::RedisError> {
+    redis::Cmd::hget(session.meta(), "user_id")
+        .query_async(redis_conn)
+        .await
+}
+
+pub fn user_filter(namespace: Option<&str>, user_id: &str) -> String {
+    format!(
+        "@user:{{{}}} @namespace:{{{}}}",
+        escape_tag(user_id),
+        escape_tag(namespace_tag(namespace))
+    )
+}
+
+// Chunks after the first are stored next to the message's doc
+fn chunk_key(key: &str, chunk: usize) -> String {
+    if chunk == 0 {
+        key.to_string()
+    } else {
+        format!("{}:{}", key, chunk)
+    }
+}
+
+/// A chunk of a message waiting to be embedded into its vector doc.
+#[derive(Clone)]
+pub struct PendingVector {
+    key: String,
+    pub content: String,
+    role: String,
+    canonical_role: Option<CanonicalRole>,
+    message_id: String,
+    created_at: i64,
+    pub position: i64,
+    pub chunk: usize,
+    chunks: usize,
+}
+
+/// Splits the messages the filter lets through into the chunks to embed, the
+/// first message being at `first_position` in the session and the others
+/// following it. Long messages get a doc per chunk, all carrying the message's ID.
+pub fn pending_vectors(
+    messages: &[MemoryMessage],
+    first_position: i64,
+    filter: &IndexingFilter,
+    indexed_parts: &IndexedParts,
+    chunker: &Chunker,
+) -> Vec<PendingVector> {
+    let mut pending = Vec::new();
+
+    for (message, position) in messages.iter().zip(first_position..) {
+        let content = message.index_text(indexed_parts);
+        if content.is_empty() || !filter.indexes(message, &content) {
+            continue;
+        }
+
+        let key = vector_key(&message.id);
+        let chunks = chunker.chunk(&content);
+        let count = chunks.len();
+
+        for (chunk, content) in chunks.into_iter().enumerate() {
+            pending.push(PendingVector {
+                key: chunk_key(&key, chunk),
+                content,
+                role: message.role.clone(),
+                canonical_role: message.canonical_role,
+                message_id: message.id.clone(),
+                created_at: message.created_at,
+                position,
+                chunk,
+                chunks: count,
+            });
+        }
+    }
+
+    pending
+}
+
+/// Queues the write of an embedded chunk's vector doc on the pipeline.
+pub fn add_vector_doc(
+    pipe: &mut redis::Pipeline,
+    session: &SessionKeys,
+    user_id: Option<&str>,
+    pending: &PendingVector,
+    embedding: Vec<f32>,
+) {
+    let cmd = pipe
+        .cmd("HSET")
+        .arg(&pending.key)
+        .arg("session")
+        .arg(&session.id)
+        .arg("namespace")
+        .arg(namespace_tag(session.namespace.as_deref()))
+        .arg("vector")
+        .arg(encode(embedding))
+        .arg("content")
+        .arg(&pending.content)
+        .arg("role")
+        .arg(&pending.role)
+        .arg("message_id")
+        .arg(&pending.message_id)
+        .arg("created_at")
+        .arg(pending.created_at)
+        .arg("position")
+        .arg(pending.position);
+    if pending.chunks > 1 {
+        cmd.arg("chunk")
+            .arg(pending.chunk)
+            .arg("chunks")
+            .arg(pending.chunks);
+    }
+    if let Some(role) = pending.canonical_role {
+        cmd.arg("canonical_role").arg(role.as_str());
+    }
+    if let Some(user_id) = user_id {
+        cmd.arg("user").arg(user_id);
+    }
+    cmd.ignore();
+}
+
+// The keys of all of a message's chunk docs, starting with its own doc
+async fn message_chunk_keys(
+    key: &str,
+    redis_conn: &mut redis::aio::ConnectionManager,
+) -> Result<Vec<String>, redis::RedisError> {
+    let chunks: Option<usize> = redis::Cmd::hget(key, "chunks")
+        .query_async(redis_conn)
+        .await?;
+
+    Ok((0..chunks.unwrap_or(1).max(1))
+        .map(|chunk| chunk_key(key, chunk))
+        .collect())
+}
+
+pub async fn update_message_vector(
+    session: &SessionKeys,
+    message_id: &str,
+    role: Option<&str>,
+    content: Option<&str>,
+    state: &AppState,
     openai_client: &AnyOpenAIClient,
     mut redis_conn: redis::aio::ConnectionManager,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let contents: Vec<String> = messages.iter().map(|msg| msg.content.clone()).collect();
-    let embeddings = openai_client.create_embedding(contents.clone()).await?;
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let key = vector_key(message_id);
 
-    // TODO add used tokens let tokens_used = response.usage.total_tokens;
-    for (index, embedding) in embeddings.iter().enumerate() {
-        let id = nanoid!();
-        let key = format!("motorhead:{}", id);
-        let vector = encode(embedding.to_vec());
+    if !vector_in_session(&key, session, &mut redis_conn).await? {
+        return Ok(false);
+    }
 
-        redis::cmd("HSET")
-            .arg(key)
-            .arg("session")
-            .arg(&session_id)
-            .arg("vector")
-            .arg(vector)
-            .arg("content")
-            .arg(&contents[index])
-            .arg("role")
-            .arg(&messages[index].role)
+    let old_keys = message_chunk_keys(&key, &mut redis_conn).await?;
+
+    let Some(content) = content else {
+        if let Some(role) = role {
+            let mut pipe = redis::pipe();
+            for key in &old_keys {
+                pipe.cmd("HSET").arg(key).arg("role").arg(role).ignore();
+                match state.role_mapping.resolve(role) {
+                    Some(canonical_role) => pipe
+                        .cmd("HSET")
+                        .arg(key)
+                        .arg("canonical_role")
+                        .arg(canonical_role.as_str()),
+                    None => pipe.cmd("HDEL").arg(key).arg("canonical_role"),
+                }
+                .ignore();
+            }
+            pipe.query_async::<_, ()>(&mut redis_conn).await?;
+        }
+
+        return Ok(true);
+    };
+
+    // The new content may have a different number of chunks, they're rebuilt
+    // from the message's doc
+    let mut fields: HashMap<String, Vec<u8>> = redis::Cmd::hgetall(&key)
+        .query_async(&mut redis_conn)
+        .await?;
+    if let Some(role) = role {
+        fields.insert(String::from("role"), role.as_bytes().to_vec());
+        match state.role_mapping.resolve(role) {
+            Some(canonical_role) => fields.insert(
+                String::from("canonical_role"),
+                canonical_role.as_str().as_bytes().to_vec(),
+            ),
+            None => fields.remove("canonical_role"),
+        };
+    }
+    fields.remove("chunk");
+    fields.remove("chunks");
+
+    let chunks = state.chunker.chunk(content);
+    let embeddings = state
+        .embeddings
+        .embed(chunks.clone(), openai_client, &mut redis_conn)
+        .await?;
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for old_key in &old_keys {
+        pipe.cmd("DEL").arg(old_key).ignore();
+    }
+    for (chunk, (content, embedding)) in chunks.iter().zip(embeddings).enumerate() {
+        let mut fields = fields.clone();
+        fields.insert(String::from("content"), content.as_bytes().to_vec());
+        fields.insert(String::from("vector"), encode(embedding));
+        if chunks.len() > 1 {
+            fields.insert(String::from("chunk"), chunk.to_string().into_bytes());
+            fields.insert(
+                String::from("chunks"),
+                chunks.len().to_string().into_bytes(),
+            );
+        }
+
+        let fields: Vec<(String, Vec<u8>)> = fields.into_iter().collect();
+        pipe.cmd("HSET")
+            .arg(chunk_key(&key, chunk))
+            .arg(fields)
+            .ignore();
+    }
+    pipe.query_async::<_, ()>(&mut redis_conn).await?;
+
+    Ok(true)
+}
+
+pub async fn delete_message_vector(
+    session: &SessionKeys,
+    message_id: &str,
+    mut redis_conn: redis::aio::ConnectionManager,
+) -> Result<bool, redis::RedisError> {
+    let key = vector_key(message_id);
+
+    if !vector_in_session(&key, session, &mut redis_conn).await? {
+        return Ok(false);
+    }
+
+    let keys = message_chunk_keys(&key, &mut redis_conn).await?;
+    let deleted: i64 = redis::Cmd::del(keys).query_async(&mut redis_conn).await?;
+
+    Ok(deleted > 0)
+}
+
+// Punctuation in TAG queries has to be escaped, session ids like `a-b` would
+// otherwise be parsed as query syntax.
+pub fn escape_tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if !c.is_alphanumeric() && c != '_' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Deletes every vector doc tagged with the session, returning how many were removed.
+pub async fn delete_session_vectors(
+    session: &SessionKeys,
+    mut redis_conn: redis::aio::ConnectionManager,
+) -> Result<usize, redis::RedisError> {
+    let query = session_filter(session);
+    let mut deleted = 0;
+
+    loop {
+        let values: Vec<Value> = redis::cmd("FT.SEARCH")
+            .arg("motorhead")
+            .arg(&query)
+            .arg("NOCONTENT")
+            .arg("LIMIT")
+            .arg(0)
+            .arg(1000)
+            .arg("DIALECT")
+            .arg("2")
+            .query_async(&mut redis_conn)
+            .await?;
+
+        let keys: Vec<String> = values
+            .iter()
+            .skip(1)
+            .filter_map(|value| redis::from_redis_value(value).ok())
+            .collect();
+
+        if keys.is_empty() {
+            return Ok(deleted);
+        }
+
+        let removed: usize = redis::Cmd::del(keys).query_async(&mut redis_conn).await?;
+
+        if removed == 0 {
+            return Ok(deleted);
+        }
+        deleted += removed;
+    }
+}
+
+async fn session_vector_keys(
+    session: &SessionKeys,
+    redis_conn: &mut redis::aio::ConnectionManager,
+) -> Result<Vec<String>, redis::RedisError> {
+    let query = session_filter(session);
+    let page_size = 1000;
+    let mut keys = Vec::new();
+
+    loop {
+        let values: Vec<Value> = redis::cmd("FT.SEARCH")
+            .arg("motorhead")
+            .arg(&query)
+            .arg("NOCONTENT")
+            .arg("LIMIT")
+            .arg(keys.len())
+            .arg(page_size)
+            .arg("DIALECT")
+            .arg("2")
+            .query_async(redis_conn)
+            .await?;
+
+        let page: Vec<String> = values
+            .iter()
+            .skip(1)
+            .filter_map(|value| redis::from_redis_value(value).ok())
+            .collect();
+        let last_page = page.len() < page_size;
+        keys.extend(page);
+
+        if last_page {
+            return Ok(keys);
+        }
+    }
+}
+
+/// Copies the source session's vector docs onto the target session. Docs of
+/// messages in `message_ids` are re-keyed to the mapped message ID, docs of
+/// messages in `excluded_ids` are skipped and the rest get a fresh ID. Docs of
+/// legacy messages have no message ID and are all copied.
+pub async fn copy_session_vectors(
+    source: &SessionKeys,
+    target: &SessionKeys,
+    message_ids: &HashMap<String, String>,
+    excluded_ids: &HashSet<String>,
+    mut redis_conn: redis::aio::ConnectionManager,
+) -> Result<usize, redis::RedisError> {
+    let keys = session_vector_keys(source, &mut redis_conn).await?;
+    let user_id = session_user(target, &mut redis_conn).await?;
+    let mut copied = 0;
+    let mut copies = DocCopies::new(message_ids);
+
+    for key in keys {
+        let mut fields: HashMap<String, Vec<u8>> = redis::Cmd::hgetall(&key)
+            .query_async(&mut redis_conn)
+            .await?;
+
+        let message_id = fields
+            .get("message_id")
+            .map(|id| String::from_utf8_lossy(id).to_string())
+            .unwrap_or_default();
+
+        if !message_id.is_empty() && excluded_ids.contains(&message_id) {
+            continue;
+        }
+
+        let chunk = fields
+            .get("chunk")
+            .and_then(|chunk| String::from_utf8_lossy(chunk).parse().ok())
+            .unwrap_or(0);
+        let (new_key, new_id) = copies.target(&key, &message_id, chunk);
+
+        fields.insert(String::from("session"), target.id.clone().into_bytes());
+        fields.insert(
+            String::from("namespace"),
+            namespace_tag(target.namespace.as_deref()).into(),
+        );
+        fields.insert(String::from("message_id"), new_id.into_bytes());
+        match &user_id {
+            Some(user_id) => fields.insert(String::from("user"), user_id.clone().into_bytes()),
+            None => fields.remove("user"),
+        };
+
+        let fields: Vec<(String, Vec<u8>)> = fields.into_iter().collect();
+        redis::Cmd::hset_multiple(new_key, &fields)
             .query_async::<_, ()>(&mut redis_conn)
             .await?;
+        copied += 1;
     }
 
-    Ok(())
+    Ok(copied)
+}
+
+// Where the docs of a copied session go, keeping the chunks of a message together
+struct DocCopies<'a> {
+    message_ids: &'a HashMap<String, String>,
+    // Messages that are no longer in the list, by their old ID
+    new_ids: HashMap<String, String>,
+    // Legacy messages have no ID, their docs are told apart by their own key
+    legacy_keys: HashMap<String, String>,
+}
+
+impl<'a> DocCopies<'a> {
+    fn new(message_ids: &'a HashMap<String, String>) -> Self {
+        DocCopies {
+            message_ids,
+            new_ids: HashMap::new(),
+            legacy_keys: HashMap::new(),
+        }
+    }
+
+    /// The key and message ID of the copy of the doc at `key`.
+    fn target(&mut self, key: &str, message_id: &str, chunk: usize) -> (String, String) {
+        if message_id.is_empty() {
+            let message_key = match key.rsplit_once(':') {
+                Some((message_key, _)) if chunk > 0 => message_key,
+                _ => key,
+            };
+            let new_key = self
+                .legacy_keys
+                .entry(message_key.to_string())
+                .or_insert_with(|| vector_key(""));
+            return (chunk_key(new_key, chunk), String::new());
+        }
+
+        let new_id = match self.message_ids.get(message_id) {
+            Some(new_id) => new_id.clone(),
+            None => self
+                .new_ids
+                .entry(message_id.to_string())
+                .or_insert_with(|| nanoid!())
+                .clone(),
+        };
+        (chunk_key(&vector_key(&new_id), chunk), new_id)
+    }
+}
+
+/// Moves the source session's vector docs to the target session, shifting
+/// their positions by `position_offset`.
+pub async fn move_session_vectors(
+    source: &SessionKeys,
+    target: &SessionKeys,
+    position_offset: i64,
+    mut redis_conn: redis::aio::ConnectionManager,
+) -> Result<usize, redis::RedisError> {
+    let keys = session_vector_keys(source, &mut redis_conn).await?;
+
+    let mut pipe = redis::pipe();
+    for key in &keys {
+        pipe.cmd("HGET").arg(key).arg("position");
+    }
+    let positions: Vec<Option<i64>> = pipe.query_async(&mut redis_conn).await?;
+
+    let mut pipe = redis::pipe();
+    for (key, position) in keys.iter().zip(positions) {
+        pipe.cmd("HSET")
+            .arg(key)
+            .arg("session")
+            .arg(&target.id)
+            .arg("namespace")
+            .arg(namespace_tag(target.namespace.as_deref()))
+            .ignore();
+        if let Some(position) = position.filter(|_| position_offset != 0) {
+            pipe.cmd("HSET")
+                .arg(key)
+                .arg("position")
+                .arg(position + position_offset)
+                .ignore();
+        }
+    }
+    pipe.query_async::<_, ()>(&mut redis_conn).await?;
+
+    let user_id = session_user(target, &mut redis_conn).await?;
+    set_vectors_user(&keys, user_id.as_deref(), &mut redis_conn).await?;
+
+    Ok(keys.len())
+}
+
+async fn set_vectors_user(
+    keys: &[String],
+    user_id: Option<&str>,
+    redis_conn: &mut redis::aio::ConnectionManager,
+) -> Result<(), redis::RedisError> {
+    let mut pipe = redis::pipe();
+    for key in keys {
+        match user_id {
+            Some(user_id) => pipe.cmd("HSET").arg(key).arg("user").arg(user_id).ignore(),
+            None => pipe.cmd("HDEL").arg(key).arg("user").ignore(),
+        };
+    }
+    pipe.query_async(redis_conn).await
+}
+
+/// Re-tags the session's vector docs with its current user, after the session
+/// was linked to another user.
+pub async fn retag_session_vectors(
+    session: &SessionKeys,
+    mut redis_conn: redis::aio::ConnectionManager,
+) -> Result<usize, redis::RedisError> {
+    let keys = session_vector_keys(session, &mut redis_conn).await?;
+    let user_id = session_user(session, &mut redis_conn).await?;
+    set_vectors_user(&keys, user_id.as_deref(), &mut redis_conn).await?;
+
+    Ok(keys.len())
 }
 
 pub async fn search_messages(
-    query: String,
-    session_id: String,
+    payload: &SearchPayload,
+    session: &SessionKeys,
+    state: &AppState,
+    openai_client: &AnyOpenAIClient,
+    redis_conn: redis::aio::ConnectionManager,
+) -> Result<Vec<RedisearchResult>, Box<dyn std::error::Error>> {
+    search(
+        payload,
+        session.namespace.as_deref(),
+        &session_filter(session),
+        state,
+        openai_client,
+        redis_conn,
+    )
+    .await
+}
+
+/// Searches the long term memory of all of the user's sessions in the namespace.
+pub async fn search_user_messages(
+    payload: &SearchPayload,
+    namespace: Option<&str>,
+    user_id: &str,
+    state: &AppState,
+    openai_client: &AnyOpenAIClient,
+    redis_conn: redis::aio::ConnectionManager,
+) -> Result<Vec<RedisearchResult>, Box<dyn std::error::Error>> {
+    search(
+        payload,
+        namespace,
+        &user_filter(namespace, user_id),
+        state,
+        openai_client,
+        redis_conn,
+    )
+    .await
+}
+
+/// Searches the long term memory of every session in the namespace, or of the
+/// sessions and user the payload narrows it down to.
+pub async fn search_namespace_messages(
+    payload: &NamespaceSearchPayload,
+    namespace: Option<&str>,
+    state: &AppState,
+    openai_client: &AnyOpenAIClient,
+    redis_conn: redis::aio::ConnectionManager,
+) -> Result<Vec<RedisearchResult>, Box<dyn std::error::Error>> {
+    search(
+        &payload.search,
+        namespace,
+        &namespace_filter(payload, namespace),
+        state,
+        openai_client,
+        redis_conn,
+    )
+    .await
+}
+
+fn namespace_filter(payload: &NamespaceSearchPayload, namespace: Option<&str>) -> String {
+    let mut filter = format!("@namespace:{{{}}}", escape_tag(namespace_tag(namespace)));
+
+    if !payload.sessions.is_empty() {
+        let sessions: Vec<String> = payload
+            .sessions
+            .iter()
+            .map(|session_id| escape_tag(session_id))
+            .collect();
+        filter.push_str(&format!(" @session:{{{}}}", sessions.join("|")));
+    }
+
+    if let Some(user_id) = &payload.user_id {
+        filter.push_str(&format!(" @user:{{{}}}", escape_tag(user_id)));
+    }
+
+    filter
+}
+
+// Narrows a search down to the roles and time range asked for
+fn payload_filter(filter: &str, payload: &SearchPayload, role_mapping: &RoleMapping) -> String {
+    let mut filter = filter.to_string();
+
+    if !payload.roles.is_empty() {
+        // Messages with a role outside the mapping aren't tagged with a
+        // canonical one, they're matched on the role they were stored with
+        let (canonical, native): (Vec<_>, Vec<_>) = payload
+            .roles
+            .iter()
+            .map(|role| (role, role_mapping.resolve(role)))
+            .partition(|(_, canonical)| canonical.is_some());
+
+        let mut roles = Vec::new();
+        if !canonical.is_empty() {
+            let canonical: Vec<&str> = canonical
+                .iter()
+                .filter_map(|(_, canonical)| canonical.map(|role| role.as_str()))
+                .collect();
+            roles.push(format!("@canonical_role:{{{}}}", canonical.join("|")));
+        }
+        roles.extend(native.iter().map(|(role, _)| {
+            format!(
+                "@role:\"{}\"",
+                role.replace('\\', "\\\\").replace('"', "\\\"")
+            )
+        }));
+        filter.push_str(&format!(" ({})", roles.join(" | ")));
+    }
+
+    if payload.created_after.is_some() || payload.created_before.is_some() {
+        filter.push_str(&format!(
+            " @created_at:[{} {}]",
+            payload
+                .created_after
+                .map_or(String::from("-inf"), |after| after.to_string()),
+            payload
+                .created_before
+                .map_or(String::from("+inf"), |before| before.to_string())
+        ));
+    }
+
+    filter
+}
+
+// Fields returned for every search result
+const RESULT_FIELDS: [&str; 7] = [
+    "role",
+    "content",
+    "session",
+    "message_id",
+    "created_at",
+    "position",
+    "chunk",
+];
+
+// Rank offset of reciprocal rank fusion, damping the lead of the top results
+const RRF_K: f64 = 60.0;
+
+// How many candidates per result are looked at when diversifying
+const CANDIDATES_PER_RESULT: usize = 4;
+const MAX_CANDIDATES: usize = 200;
+
+// How many docs per result are looked at when long messages are chunked
+const CHUNKS_PER_RESULT: usize = 3;
+
+// Results more similar than this to a better ranked one are near duplicates
+const NEAR_DUPLICATE_SIMILARITY: f64 = 0.98;
+
+// Passages shown to the LLM when reranking are cut to this many characters
+const RERANK_PASSAGE_CHARS: usize = 1000;
+
+async fn search(
+    payload: &SearchPayload,
+    namespace: Option<&str>,
+    filter: &str,
+    state: &AppState,
     openai_client: &AnyOpenAIClient,
     mut redis_conn: redis::aio::ConnectionManager,
 ) -> Result<Vec<RedisearchResult>, Box<dyn std::error::Error>> {
-    let response = openai_client.create_embedding(vec![query]).await?;
+    let filter = payload_filter(filter, payload, &state.role_mapping);
+    let count = payload.rerank_count();
+    let mut limit = if payload.diversifies() {
+        (count * CANDIDATES_PER_RESULT).min(MAX_CANDIDATES)
+    } else {
+        count
+    };
+    // Chunks of a long message collapse into one result, leaving room for
+    // other messages
+    if state.chunker.enabled() {
+        limit = (limit * CHUNKS_PER_RESULT).min(MAX_CANDIDATES).max(limit);
+    }
+
+    let candidates = match payload.mode {
+        SearchMode::Vector => {
+            search_vectors(
+                payload,
+                &filter,
+                limit,
+                &state.embeddings,
+                openai_client,
+                &mut redis_conn,
+            )
+            .await?
+        }
+        SearchMode::Keyword => search_keywords(payload, &filter, limit, &mut redis_conn).await?,
+        SearchMode::Hybrid => {
+            let vector_results = search_vectors(
+                payload,
+                &filter,
+                limit,
+                &state.embeddings,
+                openai_client,
+                &mut redis_conn,
+            )
+            .await?;
+            // The distance threshold only narrows down the vector results,
+            // keyword hits are kept for matching the words of the query
+            let keyword_results = search_keywords(payload, &filter, limit, &mut redis_conn).await?;
+            fuse(payload, limit, vector_results, keyword_results)
+        }
+    };
+
+    let candidates = collapse_chunks(candidates);
+
+    let results = if payload.diversifies() {
+        diversify(payload, count, candidates, &mut redis_conn).await?
+    } else {
+        candidates
+            .into_iter()
+            .take(count)
+            .map(|(_, result)| result)
+            .collect()
+    };
+
+    let results = if payload.rerank {
+        match rerank(payload, &results, &state.model, openai_client).await {
+            Ok(scores) => {
+                let mut reranked: Vec<RedisearchResult> = results
+                    .into_iter()
+                    .zip(scores)
+                    .map(|(result, score)| RedisearchResult {
+                        rerank_score: Some(score),
+                        ..result
+                    })
+                    .collect();
+                reranked.sort_by(|a, b| {
+                    b.rerank_score
+                        .unwrap_or(0.0)
+                        .total_cmp(&a.rerank_score.unwrap_or(0.0))
+                });
+                reranked.truncate(payload.top_k);
+                reranked
+            }
+            Err(e) => {
+                // Better results in search order than none
+                log::warn!("Error reranking results, keeping search order: {:?}", e);
+                results.into_iter().take(payload.top_k).collect()
+            }
+        }
+    } else {
+        results
+    };
+
+    if payload.context_window == 0 {
+        return Ok(results);
+    }
+
+    Ok(expand_context(payload.context_window, namespace, results, &mut redis_conn).await?)
+}
+
+// Keeps the best ranked chunk of each message
+fn collapse_chunks(candidates: Vec<(String, RedisearchResult)>) -> Vec<(String, RedisearchResult)> {
+    let mut seen = HashSet::new();
+
+    candidates
+        .into_iter()
+        .filter(|(_, result)| match &result.message_id {
+            Some(message_id) => seen.insert(message_id.clone()),
+            None => true,
+        })
+        .collect()
+}
+
+// Adds the messages stored right before and after each result, found by their
+// position in the session's history. Messages stored before the history was
+// kept have no context.
+async fn expand_context(
+    window: usize,
+    namespace: Option<&str>,
+    mut results: Vec<RedisearchResult>,
+    redis_conn: &mut redis::aio::ConnectionManager,
+) -> Result<Vec<RedisearchResult>, redis::RedisError> {
+    let (indexes, positions): (Vec<usize>, Vec<(SessionKeys, i64)>) = results
+        .iter()
+        .enumerate()
+        .filter_map(|(index, result)| {
+            let session = SessionKeys::new(namespace, result.session.as_deref()?);
+            Some((index, (session, result.position?)))
+        })
+        .unzip();
+
+    let neighbours = history::neighbours(&positions, window as i64, redis_conn).await?;
+
+    for ((index, (session, position)), neighbours) in
+        indexes.into_iter().zip(positions).zip(neighbours)
+    {
+        let (before, after) = split_context(&session, position, neighbours);
+        results[index].before = Some(before);
+        results[index].after = Some(after);
+    }
+
+    Ok(results)
+}
+
+// The neighbours of the result at `position` as results, split into the ones
+// before and after it
+fn split_context(
+    session: &SessionKeys,
+    position: i64,
+    neighbours: Vec<(i64, MemoryMessage)>,
+) -> (Vec<RedisearchResult>, Vec<RedisearchResult>) {
+    neighbours
+        .into_iter()
+        .map(|(neighbour_position, message)| RedisearchResult {
+            content: message.content.render(),
+            session: Some(session.id.clone()),
+            message_id: Some(message.id).filter(|id| !id.is_empty()),
+            created_at: Some(message.created_at),
+            position: Some(neighbour_position),
+            role: message.role,
+            ..Default::default()
+        })
+        .partition(|neighbour| neighbour.position < Some(position))
+}
+
+#[derive(Deserialize)]
+struct RerankScores {
+    scores: Vec<f64>,
+}
+
+fn rerank_prompt(query: &str, results: &[RedisearchResult]) -> String {
+    let passages = results
+        .iter()
+        .enumerate()
+        .map(|(index, result)| {
+            let content: String = result.content.chars().take(RERANK_PASSAGE_CHARS).collect();
+            format!("[{}] {}: {}", index + 1, result.role, content)
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        r#"
+Rate how relevant each numbered passage of a conversation is to the query, from 0 (unrelated) to 10 (answers it directly).
+
+Respond only with JSON holding one score per passage, in order:
+{{"scores": [7, 0, 3]}}
+
+Query: {query}
+Passages:
+{passages}
+"#
+    )
+}
+
+// Scores the results against the query with the LLM, from 0 to 1
+async fn rerank(
+    payload: &SearchPayload,
+    results: &[RedisearchResult],
+    model: &str,
+    openai_client: &AnyOpenAIClient,
+) -> Result<Vec<f64>, Box<dyn std::error::Error>> {
+    if results.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let prompt = rerank_prompt(&payload.text, results);
+    let response = openai_client.create_chat_completion(model, &prompt).await?;
+    let completion = &response
+        .choices
+        .first()
+        .ok_or("No completion found")?
+        .message
+        .content;
+
+    parse_rerank_scores(completion, results.len())
+}
+
+fn parse_rerank_scores(
+    completion: &str,
+    count: usize,
+) -> Result<Vec<f64>, Box<dyn std::error::Error>> {
+    let scores: RerankScores = serde_json::from_str(extract_json(completion))?;
+    if scores.scores.len() != count {
+        return Err(format!("Got {} scores for {} results", scores.scores.len(), count).into());
+    }
+
+    Ok(scores
+        .scores
+        .into_iter()
+        .map(|score| (score / 10.0).clamp(0.0, 1.0))
+        .collect())
+}
+
+pub fn decode(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect()
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f64 {
+    let (mut dot, mut norm_a, mut norm_b) = (0.0, 0.0, 0.0);
+    for (a, b) in a.iter().zip(b) {
+        dot += (a * b) as f64;
+        norm_a += (a * a) as f64;
+        norm_b += (b * b) as f64;
+    }
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a.sqrt() * norm_b.sqrt())
+    }
+}
+
+// Picks the results out of the candidates with maximal marginal relevance,
+// trading how relevant each one is for how much it repeats the ones picked
+// before it, and drops duplicates when asked to.
+async fn diversify(
+    payload: &SearchPayload,
+    count: usize,
+    mut candidates: Vec<(String, RedisearchResult)>,
+    redis_conn: &mut redis::aio::ConnectionManager,
+) -> Result<Vec<RedisearchResult>, redis::RedisError> {
+    if payload.dedupe {
+        drop_exact_duplicates(&mut candidates);
+    }
+    if candidates.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut pipe = redis::pipe();
+    for (key, _) in &candidates {
+        pipe.cmd("HGET").arg(key).arg("vector");
+    }
+    let vectors: Vec<Option<Vec<u8>>> = pipe.query_async(redis_conn).await?;
+    let vectors: Vec<Vec<f32>> = vectors
+        .iter()
+        .map(|vector| vector.as_deref().map(decode).unwrap_or_default())
+        .collect();
+
+    Ok(select_diverse(payload, count, candidates, &vectors))
+}
+
+// Keeps the best ranked of results with the same words, ignoring case and spacing
+fn drop_exact_duplicates(candidates: &mut Vec<(String, RedisearchResult)>) {
+    let mut seen = HashSet::new();
+    candidates.retain(|(_, result)| {
+        seen.insert(
+            result
+                .content
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .to_lowercase(),
+        )
+    });
+}
+
+// Picks `count` candidates by maximal marginal relevance, `vectors` holding
+// each candidate's embedding
+fn select_diverse(
+    payload: &SearchPayload,
+    count: usize,
+    candidates: Vec<(String, RedisearchResult)>,
+    vectors: &[Vec<f32>],
+) -> Vec<RedisearchResult> {
+    // Vector results are as relevant as they are close to the query, the
+    // others relative to the best score
+    let best_score = candidates
+        .iter()
+        .filter_map(|(_, result)| result.score)
+        .fold(0.0, f64::max);
+    let relevance: Vec<f64> = candidates
+        .iter()
+        .map(
+            |(_, result)| match (payload.mode, result.dist, result.score) {
+                (SearchMode::Vector, Some(dist), _) => 1.0 - dist,
+                (_, _, Some(score)) if best_score > 0.0 => score / best_score,
+                _ => 0.0,
+            },
+        )
+        .collect();
+
+    let relevance_weight = 1.0 - payload.diversity.unwrap_or(0.0);
+    let mut redundancy = vec![0.0; candidates.len()];
+    let mut remaining: Vec<usize> = (0..candidates.len()).collect();
+    let mut selected: Vec<usize> = Vec::new();
+
+    while selected.len() < count && !remaining.is_empty() {
+        let (position, _) = remaining
+            .iter()
+            .enumerate()
+            .map(|(position, &index)| {
+                let mmr = relevance_weight * relevance[index]
+                    - (1.0 - relevance_weight) * redundancy[index];
+                (position, mmr)
+            })
+            .max_by(|(a_position, a), (b_position, b)| {
+                a.total_cmp(b).then(b_position.cmp(a_position))
+            })
+            .unwrap_or((0, 0.0));
+        let index = remaining.remove(position);
+
+        if payload.dedupe && redundancy[index] >= NEAR_DUPLICATE_SIMILARITY {
+            continue;
+        }
+
+        for &other in &remaining {
+            let similarity = cosine_similarity(&vectors[index], &vectors[other]);
+            redundancy[other] = redundancy[other].max(similarity);
+        }
+        selected.push(index);
+    }
+
+    let mut candidates: Vec<Option<RedisearchResult>> = candidates
+        .into_iter()
+        .map(|(_, result)| Some(result))
+        .collect();
+    selected
+        .into_iter()
+        .filter_map(|index| candidates[index].take())
+        .collect()
+}
+
+async fn search_vectors(
+    payload: &SearchPayload,
+    filter: &str,
+    limit: usize,
+    embeddings: &EmbeddingCache,
+    openai_client: &AnyOpenAIClient,
+    redis_conn: &mut redis::aio::ConnectionManager,
+) -> Result<Vec<(String, RedisearchResult)>, Box<dyn std::error::Error>> {
+    let response = embeddings
+        .embed(vec![payload.text.clone()], openai_client, redis_conn)
+        .await?;
     let embeddings = response[0].clone();
     let vector = encode(embeddings);
-    let query = format!("@session:{{{}}}=>[KNN 10 @vector $V AS dist]", session_id);
+    let query = format!("({})=>[KNN {} @vector $V AS dist]", filter, limit);
 
     let values: Vec<Value> = redis::cmd("FT.SEARCH")
         .arg("motorhead")
@@ -63,19 +1109,491 @@ pub async fn search_messages(
         .arg("V")
         .arg(vector)
         .arg("RETURN")
-        .arg("3")
-        .arg("role")
-        .arg("content")
+        .arg(RESULT_FIELDS.len() + 1)
+        .arg(&RESULT_FIELDS)
         .arg("dist")
         .arg("SORTBY")
         .arg("dist")
+        .arg("LIMIT")
+        .arg(0)
+        .arg(limit)
         .arg("DIALECT")
         .arg("2")
-        .query_async(&mut redis_conn)
+        .query_async(redis_conn)
         .await?;
 
-    let array_value = redis::Value::Bulk(values);
-    let results = parse_redisearch_response(&array_value);
+    let threshold = payload.distance_threshold();
+    let results = parse_redisearch_response(values, false)
+        .into_iter()
+        .filter(|(_, _, result)| {
+            threshold.is_none_or(|threshold| result.dist.is_some_and(|dist| dist <= threshold))
+        })
+        .map(|(key, _, result)| (key, result))
+        .collect();
 
     Ok(results)
 }
+
+// Matches any of the words of the query, ranked by BM25. Words are split the
+// way RediSearch tokenizes content, so "ORD-1234" matches either part.
+async fn search_keywords(
+    payload: &SearchPayload,
+    filter: &str,
+    limit: usize,
+    redis_conn: &mut redis::aio::ConnectionManager,
+) -> Result<Vec<(String, RedisearchResult)>, Box<dyn std::error::Error>> {
+    let mut terms: Vec<String> = Vec::new();
+    for term in payload
+        .text
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|term| !term.is_empty())
+        .map(str::to_lowercase)
+    {
+        if !terms.contains(&term) {
+            terms.push(term);
+        }
+    }
+    if terms.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let query = format!("{} @content:({})", filter, terms.join("|"));
+
+    let values: Vec<Value> = redis::cmd("FT.SEARCH")
+        .arg("motorhead")
+        .arg(query)
+        .arg("SCORER")
+        .arg("BM25")
+        .arg("WITHSCORES")
+        .arg("RETURN")
+        .arg(RESULT_FIELDS.len())
+        .arg(&RESULT_FIELDS)
+        .arg("LIMIT")
+        .arg(0)
+        .arg(limit)
+        .arg("DIALECT")
+        .arg("2")
+        .query_async(redis_conn)
+        .await?;
+
+    Ok(parse_redisearch_response(values, true)
+        .into_iter()
+        .map(|(key, score, result)| (key, RedisearchResult { score, ..result }))
+        .collect())
+}
+
+// Combines the vector and keyword results of a hybrid search into one list,
+// best first
+fn fuse(
+    payload: &SearchPayload,
+    limit: usize,
+    vector_results: Vec<(String, RedisearchResult)>,
+    keyword_results: Vec<(String, RedisearchResult)>,
+) -> Vec<(String, RedisearchResult)> {
+    let vector_weight = payload.vector_weight;
+    let keyword_weight = 1.0 - payload.vector_weight;
+    let best_keyword_score = keyword_results
+        .iter()
+        .filter_map(|(_, result)| result.score)
+        .fold(0.0, f64::max);
+
+    let mut fused: Vec<(String, f64, RedisearchResult)> = Vec::new();
+
+    for (rank, (key, result)) in vector_results.into_iter().enumerate() {
+        let score = match payload.fusion {
+            Fusion::Rrf => vector_weight / (RRF_K + rank as f64 + 1.0),
+            Fusion::Weighted => vector_weight * (1.0 - result.dist.unwrap_or(1.0)).clamp(0.0, 1.0),
+        };
+        fused.push((key, score, result));
+    }
+
+    for (rank, (key, result)) in keyword_results.into_iter().enumerate() {
+        let score = match payload.fusion {
+            Fusion::Rrf => keyword_weight / (RRF_K + rank as f64 + 1.0),
+            Fusion::Weighted if best_keyword_score > 0.0 => {
+                keyword_weight * result.score.unwrap_or(0.0) / best_keyword_score
+            }
+            Fusion::Weighted => 0.0,
+        };
+
+        match fused.iter_mut().find(|(fused_key, _, _)| *fused_key == key) {
+            Some((_, fused_score, _)) => *fused_score += score,
+            None => fused.push((
+                key,
+                score,
+                RedisearchResult {
+                    dist: None,
+                    ..result
+                },
+            )),
+        }
+    }
+
+    fused.sort_by(|(_, a, _), (_, b, _)| b.total_cmp(a));
+    fused
+        .into_iter()
+        .take(limit)
+        .map(|(key, score, result)| {
+            (
+                key,
+                RedisearchResult {
+                    score: Some(score),
+                    ..result
+                },
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn payload(value: serde_json::Value) -> SearchPayload {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn filters_on_canonical_roles() {
+        let role_mapping = RoleMapping::new("human=user", false).unwrap();
+        let payload = payload(json!({"text": "q", "roles": ["Human", "assistant"]}));
+
+        assert_eq!(
+            payload_filter("@session:{s1}", &payload, &role_mapping),
+            "@session:{s1} (@canonical_role:{user|assistant})"
+        );
+    }
+
+    #[test]
+    fn filters_unmapped_roles_on_their_stored_role() {
+        let role_mapping = RoleMapping::new("", false).unwrap();
+        let payload = payload(json!({
+            "text": "q",
+            "roles": ["user", "narrator", "say \"hi\""],
+            "created_after": 1686000000,
+        }));
+
+        assert_eq!(
+            payload_filter("@session:{s1}", &payload, &role_mapping),
+            r#"@session:{s1} (@canonical_role:{user} | @role:"narrator" | @role:"say \"hi\"") @created_at:[1686000000 +inf]"#
+        );
+    }
+
+    fn result(
+        key: &str,
+        content: &str,
+        dist: Option<f64>,
+        score: Option<f64>,
+    ) -> (String, RedisearchResult) {
+        (
+            key.to_string(),
+            RedisearchResult {
+                role: String::from("user"),
+                content: content.to_string(),
+                dist,
+                score,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn keys(results: &[(String, RedisearchResult)]) -> Vec<&str> {
+        results.iter().map(|(key, _)| key.as_str()).collect()
+    }
+
+    fn contents(results: &[RedisearchResult]) -> Vec<&str> {
+        results
+            .iter()
+            .map(|result| result.content.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn escapes_tag_punctuation() {
+        assert_eq!(escape_tag("user_1"), "user_1");
+        assert_eq!(escape_tag("a-b c"), r"a\-b\ c");
+        assert_eq!(escape_tag(":global"), r"\:global");
+        assert_eq!(escape_tag("{x|y}"), r"\{x\|y\}");
+    }
+
+    #[test]
+    fn session_filters_stay_in_their_namespace() {
+        assert_eq!(
+            session_filter(&SessionKeys::new(None, "s-1")),
+            r"@session:{s\-1} @namespace:{\:global}"
+        );
+        assert_eq!(
+            session_filter(&SessionKeys::new(Some("acme"), "s-1")),
+            r"@session:{s\-1} @namespace:{acme}"
+        );
+    }
+
+    #[test]
+    fn copies_keep_legacy_docs_apart() {
+        let message_ids = HashMap::from([(String::from("m1"), String::from("f1"))]);
+        let mut copies = DocCopies::new(&message_ids);
+
+        let first = copies.target("motorhead:legacy1", "", 0);
+        let second = copies.target("motorhead:legacy2", "", 0);
+        let second_chunk = copies.target("motorhead:legacy2:1", "", 1);
+
+        assert_ne!(first.0, second.0);
+        assert_eq!(second_chunk.0, format!("{}:1", second.0));
+        assert!(first.0.starts_with("motorhead:") && first.0 != "motorhead:legacy1");
+        assert_eq!((first.1.as_str(), second.1.as_str()), ("", ""));
+    }
+
+    #[test]
+    fn copies_follow_the_new_message_ids() {
+        let message_ids = HashMap::from([(String::from("m1"), String::from("f1"))]);
+        let mut copies = DocCopies::new(&message_ids);
+
+        assert_eq!(
+            copies.target("motorhead:m1", "m1", 0),
+            (String::from("motorhead:f1"), String::from("f1"))
+        );
+        assert_eq!(copies.target("motorhead:m1:2", "m1", 2).0, "motorhead:f1:2");
+
+        // Compacted messages get a fresh ID shared by their chunks
+        let (key, new_id) = copies.target("motorhead:m2", "m2", 0);
+        assert_ne!(new_id, "m2");
+        assert_eq!(key, format!("motorhead:{}", new_id));
+        assert_eq!(copies.target("motorhead:m2:1", "m2", 1).1, new_id);
+    }
+
+    #[test]
+    fn relevance_only_keeps_the_search_order() {
+        let payload = payload(json!({"text": "q", "diversity": 0.0}));
+        let candidates = vec![
+            result("a", "a", Some(0.1), None),
+            result("b", "b", Some(0.2), None),
+            result("c", "c", Some(0.3), None),
+        ];
+        let vectors = vec![vec![1.0, 0.0], vec![1.0, 0.0], vec![0.0, 1.0]];
+
+        let selected = select_diverse(&payload, 2, candidates, &vectors);
+
+        assert_eq!(contents(&selected), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn diversity_prefers_results_unlike_the_ones_picked() {
+        let payload = payload(json!({"text": "q", "diversity": 0.5}));
+        let candidates = vec![
+            result("a", "a", Some(0.1), None),
+            result("b", "b", Some(0.2), None),
+            result("c", "c", Some(0.3), None),
+        ];
+        let vectors = vec![vec![1.0, 0.0], vec![1.0, 0.1], vec![0.0, 1.0]];
+
+        let selected = select_diverse(&payload, 3, candidates, &vectors);
+
+        assert_eq!(contents(&selected), vec!["a", "c", "b"]);
+    }
+
+    #[test]
+    fn dedupe_drops_near_duplicates() {
+        let payload = payload(json!({"text": "q", "dedupe": true}));
+        let candidates = vec![
+            result("a", "a", Some(0.1), None),
+            result("b", "b", Some(0.2), None),
+            result("c", "c", Some(0.3), None),
+        ];
+        let vectors = vec![vec![1.0, 0.0], vec![1.0, 0.01], vec![0.0, 1.0]];
+
+        let selected = select_diverse(&payload, 3, candidates, &vectors);
+
+        assert_eq!(contents(&selected), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn dedupe_keeps_the_first_of_identical_texts() {
+        let mut candidates = vec![
+            result("a", "Hello  World", Some(0.1), None),
+            result("b", "hello world", Some(0.2), None),
+            result("c", "hello there", Some(0.3), None),
+        ];
+
+        drop_exact_duplicates(&mut candidates);
+
+        assert_eq!(keys(&candidates), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn keyword_relevance_is_relative_to_the_best_score() {
+        let payload = payload(json!({"text": "q", "mode": "keyword", "diversity": 0.0}));
+        let candidates = vec![
+            result("a", "a", None, Some(1.0)),
+            result("b", "b", None, Some(5.0)),
+        ];
+        let vectors = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+
+        let selected = select_diverse(&payload, 2, candidates, &vectors);
+
+        assert_eq!(contents(&selected), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn rrf_ranks_results_found_by_both_searches_first() {
+        let payload = payload(json!({"text": "q", "mode": "hybrid", "vector_weight": 0.5}));
+        let vector = vec![
+            result("a", "a", Some(0.1), None),
+            result("b", "b", Some(0.2), None),
+        ];
+        let keyword = vec![
+            result("b", "b", None, Some(3.0)),
+            result("c", "c", None, Some(2.0)),
+        ];
+
+        let fused = fuse(&payload, 10, vector, keyword);
+
+        assert_eq!(keys(&fused), vec!["b", "a", "c"]);
+        let expected = 0.5 / (RRF_K + 2.0) + 0.5 / (RRF_K + 1.0);
+        assert!((fused[0].1.score.unwrap() - expected).abs() < 1e-12);
+        assert_eq!(fused[0].1.dist, Some(0.2));
+        assert_eq!(fused[2].1.dist, None);
+    }
+
+    #[test]
+    fn rrf_follows_the_vector_weight() {
+        let vector = || vec![result("a", "a", Some(0.1), None)];
+        let keyword = || vec![result("b", "b", None, Some(1.0))];
+
+        let vector_first = payload(json!({"text": "q", "vector_weight": 0.8}));
+        let fused = fuse(&vector_first, 10, vector(), keyword());
+        assert_eq!(keys(&fused), vec!["a", "b"]);
+
+        let keyword_first = payload(json!({"text": "q", "vector_weight": 0.2}));
+        let fused = fuse(&keyword_first, 10, vector(), keyword());
+        assert_eq!(keys(&fused), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn weighted_fusion_adds_similarity_and_normalized_keyword_score() {
+        let payload = payload(json!({"text": "q", "fusion": "weighted", "vector_weight": 0.5}));
+        let vector = vec![
+            result("a", "a", Some(0.1), None),
+            result("b", "b", Some(0.4), None),
+        ];
+        let keyword = vec![
+            result("c", "c", None, Some(4.0)),
+            result("b", "b", None, Some(2.0)),
+        ];
+
+        let fused = fuse(&payload, 10, vector, keyword);
+
+        assert_eq!(keys(&fused), vec!["b", "c", "a"]);
+        let scores: Vec<f64> = fused
+            .iter()
+            .map(|(_, result)| result.score.unwrap())
+            .collect();
+        for (score, expected) in scores.iter().zip([0.3 + 0.25, 0.5, 0.45]) {
+            assert!((score - expected).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn fuse_keeps_the_best_results_up_to_the_limit() {
+        let payload = payload(json!({"text": "q"}));
+        let vector = vec![
+            result("a", "a", Some(0.1), None),
+            result("b", "b", Some(0.2), None),
+            result("c", "c", Some(0.3), None),
+        ];
+
+        let fused = fuse(&payload, 2, vector, vec![]);
+
+        assert_eq!(keys(&fused), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn fusion_keeps_keyword_hits_without_a_distance() {
+        let payload = payload(json!({"text": "q", "mode": "hybrid", "max_distance": 0.3}));
+        let vector = vec![result("a", "a", Some(0.1), None)];
+        let keyword = vec![result("b", "ORD-1234", None, Some(2.0))];
+
+        let fused = fuse(&payload, 10, vector, keyword);
+
+        assert_eq!(keys(&fused), vec!["a", "b"]);
+        assert_eq!(fused[1].1.dist, None);
+    }
+
+    #[test]
+    fn rerank_scores_are_scaled_to_one() {
+        let completion = "Here you go:\n```json\n{\"scores\": [10, 4, -2, 12]}\n```";
+
+        let scores = parse_rerank_scores(completion, 4).unwrap();
+
+        assert_eq!(scores, vec![1.0, 0.4, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn rerank_needs_a_score_per_result() {
+        assert!(parse_rerank_scores(r#"{"scores": [1, 2]}"#, 3).is_err());
+        assert!(parse_rerank_scores("no scores", 1).is_err());
+    }
+
+    #[test]
+    fn rerank_prompt_numbers_and_truncates_passages() {
+        let long = "x".repeat(RERANK_PASSAGE_CHARS + 10);
+        let results = vec![
+            result("a", "short", None, None).1,
+            result("b", &long, None, None).1,
+        ];
+
+        let prompt = rerank_prompt("what?", &results);
+
+        assert!(prompt.contains("Query: what?"));
+        assert!(prompt.contains("[1] user: short\n"));
+        assert!(prompt.contains(&format!("[2] user: {}\n", "x".repeat(RERANK_PASSAGE_CHARS))));
+        assert!(!prompt.contains(&"x".repeat(RERANK_PASSAGE_CHARS + 1)));
+    }
+
+    #[test]
+    fn context_is_split_around_the_result() {
+        let session = SessionKeys::parse(None, "s1").unwrap();
+        let message = |id: &str, content: &str| -> MemoryMessage {
+            serde_json::from_value(json!({"id": id, "role": "user", "content": content})).unwrap()
+        };
+        let neighbours = vec![
+            (3, message("m3", "before")),
+            (5, message("", "legacy")),
+            (6, message("m6", "after")),
+        ];
+
+        let (before, after) = split_context(&session, 4, neighbours);
+
+        assert_eq!(contents(&before), vec!["before"]);
+        assert_eq!(contents(&after), vec!["legacy", "after"]);
+        assert_eq!(before[0].message_id.as_deref(), Some("m3"));
+        assert_eq!(before[0].session.as_deref(), Some("s1"));
+        assert_eq!(after[0].message_id, None);
+        assert_eq!(after[1].position, Some(6));
+    }
+
+    #[test]
+    fn namespace_searches_narrow_down_to_sessions_and_user() {
+        let everything: NamespaceSearchPayload =
+            serde_json::from_value(json!({"text": "q"})).unwrap();
+        assert_eq!(
+            namespace_filter(&everything, Some("acme")),
+            "@namespace:{acme}"
+        );
+
+        let narrowed: NamespaceSearchPayload = serde_json::from_value(json!({
+            "text": "q",
+            "sessions": ["s-1", "s2"],
+            "user_id": "user@acme",
+        }))
+        .unwrap();
+        assert_eq!(
+            namespace_filter(&narrowed, Some("acme")),
+            r"@namespace:{acme} @session:{s\-1|s2} @user:{user\@acme}"
+        );
+        assert_eq!(
+            namespace_filter(&everything, None),
+            format!("@namespace:{{{}}}", escape_tag(GLOBAL_NAMESPACE_TAG))
+        );
+    }
+}
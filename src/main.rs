@@ -1,22 +1,54 @@
+mod chunker;
+mod embedding_cache;
+mod facts;
+mod graph;
 mod healthcheck;
+mod history;
+mod idempotency;
+mod indexer;
+mod indexing_rules;
 mod long_term_memory;
 mod memory;
+mod messages;
+mod metrics;
 mod models;
+mod reaper;
+mod redis_lock;
 mod redis_utils;
 mod reducer;
 mod retrieval;
+mod sessions;
+mod users;
 
 use actix_web::{error, middleware, web, App, HttpResponse, HttpServer};
+use chunker::Chunker;
+use embedding_cache::EmbeddingCache;
+use graph::{get_session_graph, get_user_graph};
 use healthcheck::get_health;
+use indexer::{run_indexer, Indexer};
+use indexing_rules::{
+    delete_indexing_rules, get_indexing_rules, put_indexing_rules, IndexingFilter,
+};
 use memory::{delete_memory, get_memory, get_sessions, post_memory};
-use models::{AppState, OpenAIClientManager};
-use redis_utils::ensure_redisearch_index;
-use retrieval::run_retrieval;
+use messages::{delete_message, patch_message};
+use metrics::get_metrics;
+use models::{
+    AppState, IndexedParts, IndexingRules, OpenAIClientManager, OpenAIEndpoint, RoleMapping,
+};
+use reaper::run_session_reaper;
+use redis_utils::{
+    count_unmigrated_sessions, ensure_facts_index, ensure_redisearch_index,
+    migrate_legacy_messages, migrate_namespaced_sessions,
+};
+use retrieval::{run_fact_retrieval, run_namespace_retrieval, run_retrieval};
+use sessions::{fork_session, get_session, merge_session, put_session, rename_session};
 use std::collections::HashMap;
 use std::env;
 use std::io;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
+use users::{delete_user_profile, get_user_profile, run_user_retrieval};
 
 #[actix_web::main]
 async fn main() -> io::Result<()> {
@@ -38,15 +70,85 @@ async fn main() -> io::Result<()> {
         .map(|value| value.to_lowercase() == "true")
         .unwrap_or(false);
 
+    // Facts are stored with embeddings, so they need long term memory
+    let fact_extraction = env::var("MOTORHEAD_FACT_EXTRACTION")
+        .map(|value| value.to_lowercase() == "true")
+        .unwrap_or(false);
+    if fact_extraction && !long_term_memory {
+        log::warn!("MOTORHEAD_FACT_EXTRACTION requires MOTORHEAD_LONG_TERM_MEMORY, ignoring it");
+    }
+    let fact_extraction = fact_extraction && long_term_memory;
+
+    let knowledge_graph = env::var("MOTORHEAD_KNOWLEDGE_GRAPH")
+        .map(|value| value.to_lowercase() == "true")
+        .unwrap_or(false);
+
+    let strict_roles = env::var("MOTORHEAD_STRICT_ROLES")
+        .map(|value| value.to_lowercase() == "true")
+        .unwrap_or(false);
+    let role_aliases = env::var("MOTORHEAD_ROLE_ALIASES").unwrap_or_default();
+    let role_mapping = RoleMapping::new(&role_aliases, strict_roles).unwrap_or_else(|err| {
+        eprintln!("Role alias error: {}", err);
+        std::process::exit(1);
+    });
+
     if long_term_memory {
         // TODO: Make these configurable - for now just ADA support
         let vector_dimensions = 1536;
         let distance_metric = "COSINE";
 
-        ensure_redisearch_index(&redis, vector_dimensions, distance_metric).unwrap_or_else(|err| {
-            eprintln!("RediSearch index error: {}", err);
-            std::process::exit(1);
-        });
+        ensure_redisearch_index(&redis, vector_dimensions, distance_metric, &role_mapping)
+            .unwrap_or_else(|err| {
+                eprintln!("RediSearch index error: {}", err);
+                std::process::exit(1);
+            });
+
+        if fact_extraction {
+            ensure_facts_index(&redis, vector_dimensions, distance_metric).unwrap_or_else(|err| {
+                eprintln!("RediSearch facts index error: {}", err);
+                std::process::exit(1);
+            });
+        }
+    }
+
+    let migrate_messages = env::var("MOTORHEAD_MIGRATE_LEGACY_MESSAGES")
+        .map(|value| value.to_lowercase() == "true")
+        .unwrap_or(false);
+
+    if migrate_messages {
+        match migrate_legacy_messages(&redis) {
+            Ok(migrated) => log::info!("Migrated legacy messages in {} sessions", migrated),
+            Err(err) => {
+                eprintln!("Legacy message migration error: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Namespaced sessions of older versions are unreachable until migrated
+    let migrate_namespaces = env::var("MOTORHEAD_MIGRATE_NAMESPACES")
+        .map(|value| value.to_lowercase() == "true")
+        .unwrap_or(false);
+
+    if migrate_namespaces {
+        match migrate_namespaced_sessions(&redis, long_term_memory) {
+            Ok(Some(migrated)) => log::info!("Migrated {} namespaced sessions", migrated),
+            Ok(None) => log::info!("Namespaced sessions were already migrated"),
+            Err(err) => {
+                eprintln!("Namespace migration error: {}", err);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        match count_unmigrated_sessions(&redis) {
+            Ok(0) => {}
+            Ok(unmigrated) => log::warn!(
+                "{} namespaced sessions may be stored outside their namespace and can't be read, \
+                 set MOTORHEAD_MIGRATE_NAMESPACES=true to migrate them",
+                unmigrated
+            ),
+            Err(err) => log::warn!("Error checking for unmigrated namespaced sessions: {}", err),
+        }
     }
 
     let port = env::var("PORT")
@@ -59,16 +161,113 @@ async fn main() -> io::Result<()> {
         .and_then(|s| s.parse::<i64>().ok())
         .unwrap_or(12);
     let model = env::var("MOTORHEAD_MODEL").unwrap_or_else(|_| "gpt-3.5-turbo".to_string());
+    let idempotency_ttl = env::var("MOTORHEAD_IDEMPOTENCY_TTL")
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(86400);
+    let indexed_parts = env::var("MOTORHEAD_INDEXED_PARTS")
+        .map(|value| IndexedParts::parse(&value))
+        .unwrap_or_default();
+
+    let chunk_size = env::var("MOTORHEAD_CHUNK_SIZE")
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(512);
+    let chunk_overlap = env::var("MOTORHEAD_CHUNK_OVERLAP")
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(64);
+    let chunker = Chunker::new(chunk_size, chunk_overlap).unwrap_or_else(|err| {
+        eprintln!("Chunking error: {}", err);
+        std::process::exit(1);
+    });
+
+    let index_queue_size = env::var("MOTORHEAD_INDEX_QUEUE_SIZE")
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .filter(|size| *size > 0)
+        .unwrap_or(1000);
+    let index_batch_size = env::var("MOTORHEAD_INDEX_BATCH_SIZE")
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .filter(|size| *size > 0)
+        .unwrap_or(256);
+    let index_batch_latency = env::var("MOTORHEAD_INDEX_BATCH_LATENCY_MS")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or(100);
+    let (indexer, index_receiver) = Indexer::new(index_queue_size);
+
+    let index_roles = env::var("MOTORHEAD_INDEX_ROLES").unwrap_or_default();
+    let index_min_tokens = env::var("MOTORHEAD_INDEX_MIN_TOKENS")
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok());
+    let index_exclude = env::var("MOTORHEAD_INDEX_EXCLUDE")
+        .ok()
+        .filter(|pattern| !pattern.is_empty());
+    let indexing_rules = IndexingRules::parse(&index_roles, index_min_tokens, index_exclude)
+        .and_then(|rules| IndexingFilter::new(&rules).map(|_| rules))
+        .unwrap_or_else(|err| {
+            eprintln!("Indexing rules error: {}", err);
+            std::process::exit(1);
+        });
+
+    let embedding_cache_ttl = env::var("MOTORHEAD_EMBEDDING_CACHE_TTL")
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(604800);
+    let embeddings = EmbeddingCache::new(
+        embedding_cache_ttl,
+        OpenAIEndpoint::from_env().embedding_model(),
+    );
+
+    let session_ttl = env::var("MOTORHEAD_SESSION_TTL")
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .filter(|ttl| *ttl > 0);
+    let reaper_interval = env::var("MOTORHEAD_REAPER_INTERVAL")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .filter(|interval| *interval > 0)
+        .unwrap_or(60);
 
     let session_cleanup = Arc::new(Mutex::new(HashMap::new()));
+    let session_tasks = Arc::new(Mutex::new(HashMap::new()));
     let session_state = Arc::new(AppState {
         window_size,
         session_cleanup,
+        session_tasks,
         openai_pool,
         long_term_memory,
+        fact_extraction,
+        knowledge_graph,
         model,
+        idempotency_ttl,
+        indexed_parts,
+        chunker,
+        indexer,
+        embeddings,
+        indexing_rules,
+        role_mapping,
+        session_ttl,
     });
 
+    if long_term_memory {
+        tokio::spawn(run_indexer(
+            index_receiver,
+            redis.clone(),
+            session_state.clone(),
+            index_batch_size,
+            Duration::from_millis(index_batch_latency),
+        ));
+    }
+
+    tokio::spawn(run_session_reaper(
+        redis.clone(),
+        session_state.clone(),
+        Duration::from_secs(reaper_interval),
+    ));
+
     async fn on_start_logger(port: u16) -> io::Result<()> {
         println!();
         println!("-----------------------------------");
@@ -85,11 +284,29 @@ async fn main() -> io::Result<()> {
             .app_data(web::Data::new(session_state.clone()))
             .wrap(middleware::Logger::default())
             .service(get_health)
+            .service(get_metrics)
             .service(get_memory)
             .service(post_memory)
             .service(delete_memory)
             .service(get_sessions)
+            .service(get_session)
+            .service(put_session)
+            .service(fork_session)
+            .service(rename_session)
+            .service(merge_session)
+            .service(patch_message)
+            .service(delete_message)
             .service(run_retrieval)
+            .service(run_namespace_retrieval)
+            .service(run_fact_retrieval)
+            .service(get_session_graph)
+            .service(get_user_graph)
+            .service(get_indexing_rules)
+            .service(put_indexing_rules)
+            .service(delete_indexing_rules)
+            .service(get_user_profile)
+            .service(delete_user_profile)
+            .service(run_user_retrieval)
             .app_data(web::JsonConfig::default().error_handler(|err, _req| {
                 error::InternalError::from_response(
                     "",
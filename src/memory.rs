@@ -1,10 +1,25 @@
-use crate::long_term_memory::index_messages;
+use crate::facts::{delete_session_facts, extract_facts};
+use crate::graph::{delete_session_graph, extract_graph};
+use crate::history::record_history;
+use crate::idempotency::{
+    claim_idempotency_key, complete_idempotency_key, filter_seen_client_ids, record_client_ids,
+    release_idempotency_key, IdempotencyClaim,
+};
+use crate::indexing_rules::indexing_filter;
+use crate::long_term_memory::delete_session_vectors;
 use crate::models::{
-    AckResponse, AppState, GetSessionsQuery, MemoryMessage, MemoryMessagesAndContext,
-    MemoryResponse, NamespaceQuery,
+    AckResponse, AppState, DeleteResponse, GetMemoryQuery, GetSessionsQuery, MemoryMessage,
+    MemoryMessagesAndContext, MemoryResponse, MotorheadError, NamespaceQuery,
 };
+use crate::redis_lock::RedisLock;
 use crate::reducer::handle_compaction;
-use actix_web::{delete, error, get, post, web, HttpResponse, Responder};
+use crate::sessions::{
+    check_namespace, delete_session, get_sessions_metadata, list_sessions, record_activity,
+    SessionKeys,
+};
+use crate::users::update_user_profile;
+use actix_web::{delete, error, get, post, web, HttpRequest, HttpResponse, Responder};
+use nanoid::nanoid;
 use std::ops::Deref;
 use std::sync::Arc;
 
@@ -14,97 +29,108 @@ pub async fn get_sessions(
     _data: web::Data<Arc<AppState>>,
     redis: web::Data<redis::Client>,
 ) -> actix_web::Result<impl Responder> {
-    let GetSessionsQuery {
-        page,
-        size,
-        namespace,
-    } = pagination;
+    if pagination.page == 0 {
+        return Err(actix_web::error::ErrorBadRequest("Page must be at least 1"));
+    }
 
-    if page > 100 {
+    if pagination.size == 0 || pagination.size > 100 {
         return Err(actix_web::error::ErrorBadRequest(
-            "Page size must not exceed 100",
+            "Page size must be between 1 and 100",
         ));
     }
 
-    let start: isize = ((page - 1) * size) as isize; // 0-indexed
-    let end: isize = (page * size - 1) as isize; // inclusive
+    check_namespace(pagination.namespace.as_deref()).map_err(error::ErrorBadRequest)?;
 
     let mut conn = redis
         .get_tokio_connection_manager()
         .await
         .map_err(error::ErrorInternalServerError)?;
 
-    let sessions_key = match &namespace {
-        Some(namespace) => format!("sessions:{}", namespace),
-        None => String::from("sessions"),
-    };
+    let page = list_sessions(&pagination, &mut conn)
+        .await
+        .map_err(|err| match err {
+            MotorheadError::InvalidRequest(_) => error::ErrorBadRequest(err),
+            _ => error::ErrorInternalServerError(err),
+        })?;
 
-    let session_ids: Vec<String> = redis::cmd("ZRANGE")
-        .arg(sessions_key)
-        .arg(start)
-        .arg(end)
-        .query_async(&mut conn)
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type("application/json")
+        .insert_header(("X-Total-Count", page.total.to_string()));
+
+    if let Some(next_cursor) = &page.next_cursor {
+        response.insert_header(("X-Next-Cursor", next_cursor.as_str()));
+    }
+
+    if pagination.details {
+        let sessions = get_sessions_metadata(
+            pagination.namespace.as_deref(),
+            &page.session_ids,
+            &mut conn,
+        )
         .await
         .map_err(error::ErrorInternalServerError)?;
 
-    Ok(HttpResponse::Ok()
-        .content_type("application/json")
-        .json(session_ids))
+        return Ok(response.json(sessions));
+    }
+
+    Ok(response.json(page.session_ids))
 }
 
 #[get("/sessions/{session_id}/memory")]
 pub async fn get_memory(
     session_id: web::Path<String>,
+    web::Query(query): web::Query<GetMemoryQuery>,
     data: web::Data<Arc<AppState>>,
     redis: web::Data<redis::Client>,
+    web::Query(namespace_query): web::Query<NamespaceQuery>,
 ) -> actix_web::Result<impl Responder> {
+    let session = SessionKeys::parse(namespace_query.namespace.as_deref(), &session_id)
+        .map_err(error::ErrorBadRequest)?;
+
     let mut conn = redis
         .get_tokio_connection_manager()
         .await
         .map_err(error::ErrorInternalServerError)?;
 
-    let lrange_key = format!("session:{}", &*session_id);
-    let context_key = format!("context:{}", &*session_id);
-    let token_count_key = format!("tokens:{}", &*session_id);
-    let keys = vec![context_key, token_count_key];
-
-    let (messages, values): (Vec<String>, Vec<Option<String>>) = redis::pipe()
-        .cmd("LRANGE")
-        .arg(lrange_key)
-        .arg(0)
-        .arg(data.window_size as isize)
-        .cmd("MGET")
-        .arg(keys)
-        .query_async(&mut conn)
-        .await
-        .map_err(error::ErrorInternalServerError)?;
+    let keys = vec![session.context(), session.tokens(), session.context_stale()];
+
+    let (messages, values, expires_at): (Vec<String>, Vec<Option<String>>, Option<i64>) =
+        redis::pipe()
+            .cmd("LRANGE")
+            .arg(session.messages())
+            .arg(0)
+            .arg(data.window_size as isize)
+            .cmd("MGET")
+            .arg(keys)
+            .cmd("HGET")
+            .arg(session.meta())
+            .arg("expires_at")
+            .query_async(&mut conn)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
 
-    let context = values.get(0).cloned().flatten();
+    let context = values.first().cloned().flatten();
     let tokens = values
         .get(1)
         .cloned()
         .flatten()
         .and_then(|tokens_string| tokens_string.parse::<i64>().ok())
         .unwrap_or(0);
+    let context_stale = values.get(2).cloned().flatten().is_some();
 
     let messages: Vec<MemoryMessage> = messages
-        .into_iter()
-        .filter_map(|message| {
-            let mut parts = message.splitn(2, ": ");
-            match (parts.next(), parts.next()) {
-                (Some(role), Some(content)) => Some(MemoryMessage {
-                    role: role.to_string(),
-                    content: content.to_string(),
-                }),
-                _ => None,
-            }
-        })
+        .iter()
+        .filter_map(|message| MemoryMessage::from_redis_entry(message))
+        .map(|message| data.role_mapping.format(message, query.role_format))
         .collect();
 
     let response = MemoryResponse {
         messages,
         context,
         tokens: Some(tokens),
+        context_stale,
+        expires_at,
     };
 
     Ok(HttpResponse::Ok()
@@ -114,134 +140,364 @@ pub async fn get_memory(
 
 #[post("/sessions/{session_id}/memory")]
 pub async fn post_memory(
+    req: HttpRequest,
     session_id: web::Path<String>,
     web::Json(memory_messages): web::Json<MemoryMessagesAndContext>,
     data: web::Data<Arc<AppState>>,
     redis: web::Data<redis::Client>,
     web::Query(namespace_query): web::Query<NamespaceQuery>,
 ) -> actix_web::Result<impl Responder> {
+    let session = SessionKeys::parse(namespace_query.namespace.as_deref(), &session_id)
+        .map_err(error::ErrorBadRequest)?;
+
     let mut conn = redis
         .get_tokio_connection_manager()
         .await
         .map_err(error::ErrorInternalServerError)?;
 
-    let memory_messages_clone: Vec<MemoryMessage> = memory_messages.messages.to_vec();
+    let response = AckResponse { status: "Ok" };
+    let response_body =
+        serde_json::to_string(&response).map_err(error::ErrorInternalServerError)?;
+
+    let idempotency_key = req
+        .headers()
+        .get("Idempotency-Key")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+
+    if let Some(key) = &idempotency_key {
+        let claim = claim_idempotency_key(&session, key, &mut conn)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+
+        match claim {
+            IdempotencyClaim::Claimed => {}
+            IdempotencyClaim::Pending => {
+                return Err(error::ErrorConflict(
+                    "A request with this Idempotency-Key is still in progress",
+                ));
+            }
+            IdempotencyClaim::Completed(original) => {
+                return Ok(HttpResponse::Ok()
+                    .content_type("application/json")
+                    .body(original));
+            }
+        }
+    }
 
-    let messages: Vec<String> = memory_messages
+    let result = store_memory(
+        session.clone(),
+        memory_messages,
+        Arc::clone(&data),
+        conn.clone(),
+    )
+    .await;
+
+    if let Err(err) = result {
+        if let Some(key) = &idempotency_key {
+            if let Err(e) = release_idempotency_key(&session, key, &mut conn).await {
+                log::error!("Error releasing idempotency key: {:?}", e);
+            }
+        }
+        return Err(err);
+    }
+
+    if let Some(key) = &idempotency_key {
+        // The messages are stored, a replay must not store them again even if
+        // it only finds the pending claim
+        if let Err(e) = complete_idempotency_key(
+            &session,
+            key,
+            &response_body,
+            data.idempotency_ttl,
+            &mut conn,
+        )
+        .await
+        {
+            log::error!("Error completing idempotency key: {:?}", e);
+        }
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(response_body))
+}
+
+async fn store_memory(
+    session: SessionKeys,
+    memory_messages: MemoryMessagesAndContext,
+    state: Arc<AppState>,
+    mut conn: redis::aio::ConnectionManager,
+) -> actix_web::Result<()> {
+    let created_at = chrono::Utc::now().timestamp();
+    let memory_messages_clone: Vec<MemoryMessage> = memory_messages
         .messages
         .into_iter()
-        .map(|memory_message| format!("{}: {}", memory_message.role, memory_message.content))
+        .map(|memory_message| {
+            let mut memory_message = MemoryMessage {
+                id: nanoid!(),
+                created_at,
+                ..memory_message
+            };
+            state
+                .role_mapping
+                .apply(&mut memory_message)
+                .map(|_| memory_message)
+        })
+        .collect::<Result<_, _>>()
+        .map_err(error::ErrorBadRequest)?;
+
+    // Resolved before anything is written, so bad namespace rules fail the
+    // request without storing half of it
+    let filter = if state.long_term_memory {
+        Some(
+            indexing_filter(&state, session.namespace.as_deref(), &mut conn)
+                .await
+                .map_err(error::ErrorInternalServerError)?,
+        )
+    } else {
+        None
+    };
+
+    // Renames and merges move a session's keys around, don't write while they do
+    let _session_lock = RedisLock::acquire(session.lock(), &conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    // Retried requests may resend messages that were already stored
+    let memory_messages_clone = filter_seen_client_ids(
+        &session,
+        memory_messages_clone,
+        state.idempotency_ttl,
+        &mut conn,
+    )
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    let messages: Vec<String> = memory_messages_clone
+        .iter()
+        .map(MemoryMessage::to_redis_entry)
         .collect();
 
     // If new context is passed in we overwrite the existing one
     if let Some(context) = memory_messages.context {
-        redis::Cmd::set(format!("context:{}", &*session_id), context)
+        redis::pipe()
+            .cmd("SET")
+            .arg(session.context())
+            .arg(context)
+            .ignore()
+            .cmd("DEL")
+            .arg(session.context_stale())
+            .ignore()
             .query_async::<_, ()>(&mut conn)
             .await
             .map_err(error::ErrorInternalServerError)?;
     }
 
-    let sessions_key = match namespace_query.namespace {
-        Some(namespace) => format!("sessions:{}", namespace),
-        None => String::from("sessions"),
-    };
+    if messages.is_empty() {
+        return Ok(());
+    }
 
     // add to sorted set of sessions
     redis::cmd("ZADD")
-        .arg(sessions_key)
-        .arg(chrono::Utc::now().timestamp())
-        .arg(&*session_id)
-        .query_async(&mut conn)
+        .arg(session.sessions())
+        .arg(created_at)
+        .arg(&session.id)
+        .query_async::<_, ()>(&mut conn)
         .await
         .map_err(error::ErrorInternalServerError)?;
 
-    let res: i64 = redis::Cmd::lpush(format!("session:{}", &*session_id), messages.clone())
-        .query_async::<_, i64>(&mut conn)
+    // Messages are numbered in the order they were stored, so retrieval can
+    // find the ones around a hit
+    let sequence: i64 = redis::Cmd::hincr(session.meta(), "sequence", messages.len())
+        .query_async(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    let first_position = sequence - messages.len() as i64 + 1;
+
+    // Client IDs are only recorded along with their messages, so a request that
+    // fails before this can be retried
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .cmd("LPUSH")
+        .arg(session.messages())
+        .arg(&messages);
+    record_client_ids(
+        &mut pipe,
+        &session,
+        &memory_messages_clone,
+        state.idempotency_ttl,
+    );
+    if state.long_term_memory {
+        record_history(&mut pipe, &session, &memory_messages_clone, first_position);
+    }
+    let (res,): (i64,) = pipe
+        .query_async(&mut conn)
         .await
         .map_err(error::ErrorInternalServerError)?;
 
-    if data.long_term_memory {
-        let session = session_id.clone();
-        let conn_clone = conn.clone();
-        let pool = data.openai_pool.clone();
+    record_activity(
+        &session,
+        &memory_messages_clone,
+        created_at,
+        state.session_ttl,
+        &mut conn,
+    )
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    if let Some(filter) = filter {
+        state
+            .indexer
+            .enqueue(
+                session.clone(),
+                memory_messages_clone,
+                first_position,
+                filter,
+            )
+            .await;
+    }
+
+    if res > state.window_size {
+        spawn_compaction(&state, &session, conn).await;
+    }
 
-        tokio::spawn(async move {
+    Ok(())
+}
+
+/// Summarizes the older half of the session's messages in the background,
+/// unless that's already running for the session.
+pub async fn spawn_compaction(
+    state: &Arc<AppState>,
+    session: &SessionKeys,
+    conn: redis::aio::ConnectionManager,
+) {
+    let scoped_id = session.scoped_id();
+    let mut session_cleanup = state.session_cleanup.lock().await;
+
+    if !session_cleanup.get(&scoped_id).unwrap_or(&false) {
+        session_cleanup.insert(scoped_id.clone(), true);
+        let session_cleanup = Arc::clone(&state.session_cleanup);
+        let compaction_session = session.clone();
+        let compaction_scoped_id = scoped_id.clone();
+        let window_size = state.window_size;
+        let fact_extraction = state.fact_extraction;
+        let knowledge_graph = state.knowledge_graph;
+        let model = state.model.to_string();
+        let pool = state.openai_pool.clone();
+        let compaction_state = Arc::clone(state);
+
+        let task = tokio::spawn(async move {
+            log::info!("running compact");
             let client_wrapper = pool.get().await.unwrap();
             let client = client_wrapper.deref();
-            if let Err(e) = index_messages(memory_messages_clone, session, client, conn_clone).await
-            {
-                log::error!("Error in index_messages: {:?}", e);
+
+            let compaction_result = handle_compaction(
+                compaction_session.clone(),
+                model.clone(),
+                window_size,
+                client,
+                conn.clone(),
+            )
+            .await;
+
+            if let Ok(messages) = compaction_result {
+                if fact_extraction {
+                    if let Err(e) = extract_facts(
+                        &compaction_session,
+                        &messages,
+                        &model,
+                        &compaction_state.embeddings,
+                        client,
+                        conn.clone(),
+                    )
+                    .await
+                    {
+                        log::error!("Error extracting facts: {:?}", e);
+                    }
+                }
+
+                if knowledge_graph {
+                    if let Err(e) =
+                        extract_graph(&compaction_session, &messages, &model, client, conn.clone())
+                            .await
+                    {
+                        log::error!("Error extracting knowledge graph: {:?}", e);
+                    }
+                }
+
+                // The user's profile is built from what their sessions summarize
+                if let Err(e) =
+                    update_user_profile(&compaction_session, &messages, model, client, conn).await
+                {
+                    log::error!("Error updating user profile: {:?}", e);
+                }
             }
-        });
-    }
 
-    if res > data.window_size {
-        let state = data.into_inner();
-        let mut session_cleanup = state.session_cleanup.lock().await;
-
-        if !session_cleanup.get(&*session_id).unwrap_or(&false) {
-            session_cleanup.insert((&*session_id.to_string()).into(), true);
-            let session_cleanup = Arc::clone(&state.session_cleanup);
-            let session_id = session_id.clone();
-            let window_size = state.window_size;
-            let model = state.model.to_string();
-            let pool = state.openai_pool.clone();
-
-            tokio::spawn(async move {
-                log::info!("running compact");
-                let client_wrapper = pool.get().await.unwrap();
-                let client = client_wrapper.deref();
-
-                let _compaction_result =
-                    handle_compaction(session_id.to_string(), model, window_size, client, conn)
-                        .await;
-
-                let mut lock = session_cleanup.lock().await;
-                lock.remove(&session_id);
-            });
-        }
+            let mut lock = session_cleanup.lock().await;
+            lock.remove(&compaction_scoped_id);
+        });
+        state.track_task(&scoped_id, task.abort_handle()).await;
     }
-
-    let response = AckResponse { status: "Ok" };
-    Ok(HttpResponse::Ok()
-        .content_type("application/json")
-        .json(response))
 }
 
 #[delete("/sessions/{session_id}/memory")]
 pub async fn delete_memory(
     session_id: web::Path<String>,
+    data: web::Data<Arc<AppState>>,
     redis: web::Data<redis::Client>,
     web::Query(namespace_query): web::Query<NamespaceQuery>,
 ) -> actix_web::Result<impl Responder> {
+    let session = SessionKeys::parse(namespace_query.namespace.as_deref(), &session_id)
+        .map_err(error::ErrorBadRequest)?;
+
     let mut conn = redis
         .get_tokio_connection_manager()
         .await
         .map_err(error::ErrorInternalServerError)?;
 
-    let context_key = format!("context:{}", &*session_id);
-    let token_count_key = format!("tokens:{}", &*session_id);
-    let session_key = format!("session:{}", &*session_id);
-    let keys = vec![context_key, session_key, token_count_key];
+    // Stop in-flight indexing and compaction first so they can't write the
+    // session back after it's gone
+    let cancelled_tasks = data.cancel_tasks(&session.scoped_id()).await;
+    data.indexer.cancel(&session).await;
 
-    let sessions_key = match namespace_query.namespace {
-        Some(namespace) => format!("sessions:{}", namespace),
-        None => String::from("sessions"),
+    let vectors = if data.long_term_memory {
+        delete_session_vectors(&session, conn.clone())
+            .await
+            .map_err(error::ErrorInternalServerError)?
+    } else {
+        0
     };
 
-    redis::cmd("ZREM")
-        .arg(sessions_key)
-        .arg(&*session_id)
-        .query_async(&mut conn)
-        .await
-        .map_err(error::ErrorInternalServerError)?;
+    let facts = if data.fact_extraction {
+        delete_session_facts(&session, conn.clone())
+            .await
+            .map_err(error::ErrorInternalServerError)?
+    } else {
+        0
+    };
 
-    redis::Cmd::del(keys)
-        .query_async(&mut conn)
+    let entities = if data.knowledge_graph {
+        delete_session_graph(&session, conn.clone())
+            .await
+            .map_err(error::ErrorInternalServerError)?
+    } else {
+        0
+    };
+
+    let (messages, keys) = delete_session(&session, &mut conn)
         .await
         .map_err(error::ErrorInternalServerError)?;
 
-    let response = AckResponse { status: "Ok" };
+    let response = DeleteResponse {
+        status: "Ok",
+        messages,
+        keys,
+        vectors,
+        facts,
+        entities,
+        cancelled_tasks,
+    };
     Ok(HttpResponse::Ok()
         .content_type("application/json")
         .json(response))
@@ -0,0 +1,442 @@
+use crate::history::{delete_history, update_history};
+use crate::indexing_rules::indexing_filter;
+use crate::long_term_memory::{delete_message_vector, update_message_vector};
+use crate::models::{
+    AckResponse, AppState, MemoryMessage, MessagePatch, NamespaceQuery, RoleMapping,
+};
+use crate::redis_lock::RedisLock;
+use crate::sessions::{count_tokens, SessionKeys};
+use actix_web::{delete, error, patch, web, HttpResponse, Responder};
+use std::ops::Deref;
+use std::sync::Arc;
+
+// Replaces a list entry by value rather than by index, so a concurrent LPUSH or
+// compaction between reading the list and writing it back can't hit the wrong entry.
+const REPLACE_ENTRY_SCRIPT: &str = r#"
+local entries = redis.call('LRANGE', KEYS[1], 0, -1)
+for index, entry in ipairs(entries) do
+    if entry == ARGV[1] then
+        redis.call('LSET', KEYS[1], index - 1, ARGV[2])
+        return 1
+    end
+end
+return 0
+"#;
+
+async fn find_message(
+    session: &SessionKeys,
+    message_id: &str,
+    conn: &mut redis::aio::ConnectionManager,
+) -> Result<Option<(String, MemoryMessage)>, redis::RedisError> {
+    let entries: Vec<String> = redis::Cmd::lrange(session.messages(), 0, -1)
+        .query_async(conn)
+        .await?;
+
+    Ok(entries.into_iter().find_map(|entry| {
+        MemoryMessage::from_redis_entry(&entry)
+            .filter(|message| message.id == message_id)
+            .map(|message| (entry, message))
+    }))
+}
+
+// Messages that were already folded into the context summary are no longer in the
+// session list. Changing them means the summary no longer reflects the transcript.
+async fn mark_context_stale(
+    session: &SessionKeys,
+    message_id: &str,
+    conn: &mut redis::aio::ConnectionManager,
+) -> Result<bool, redis::RedisError> {
+    let summarized: bool = redis::cmd("SISMEMBER")
+        .arg(session.summarized())
+        .arg(message_id)
+        .query_async(conn)
+        .await?;
+
+    if summarized {
+        redis::Cmd::set(session.context_stale(), 1)
+            .query_async::<_, ()>(conn)
+            .await?;
+    }
+
+    Ok(summarized)
+}
+
+/// The message with the patch applied, its canonical role following its role.
+fn apply_patch(
+    message: MemoryMessage,
+    patch: &MessagePatch,
+    role_mapping: &RoleMapping,
+) -> MemoryMessage {
+    let mut updated = MemoryMessage {
+        role: patch.role.clone().unwrap_or(message.role),
+        content: patch.content.clone().unwrap_or(message.content),
+        metadata: patch.metadata.clone().or(message.metadata),
+        ..message
+    };
+    updated.canonical_role = role_mapping.resolve(&updated.role);
+    updated
+}
+
+// Keeps the session's `total_tokens` in line with its messages after one of
+// them was edited or deleted
+async fn adjust_tokens(
+    session: &SessionKeys,
+    tokens: i64,
+    conn: &mut redis::aio::ConnectionManager,
+) -> Result<(), redis::RedisError> {
+    if tokens == 0 {
+        return Ok(());
+    }
+
+    redis::cmd("HINCRBY")
+        .arg(session.meta())
+        .arg("total_tokens")
+        .arg(tokens)
+        .query_async(conn)
+        .await
+}
+
+#[patch("/sessions/{session_id}/messages/{message_id}")]
+pub async fn patch_message(
+    path: web::Path<(String, String)>,
+    web::Json(message_patch): web::Json<MessagePatch>,
+    data: web::Data<Arc<AppState>>,
+    redis: web::Data<redis::Client>,
+    web::Query(namespace_query): web::Query<NamespaceQuery>,
+) -> actix_web::Result<impl Responder> {
+    let (session_id, message_id) = path.into_inner();
+    let session = SessionKeys::parse(namespace_query.namespace.as_deref(), &session_id)
+        .map_err(error::ErrorBadRequest)?;
+
+    let mut conn = redis
+        .get_tokio_connection_manager()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    if let Some(role) = &message_patch.role {
+        if data.role_mapping.strict && data.role_mapping.resolve(role).is_none() {
+            return Err(error::ErrorBadRequest(format!("Unknown role: {}", role)));
+        }
+    }
+
+    // Posts, renames and merges rewrite the session's list, don't write a
+    // stale entry back over theirs
+    let session_lock = RedisLock::acquire(session.lock(), &conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let mut found = false;
+    let mut original = None;
+    let mut updated = None;
+    let patch = |message| apply_patch(message, &message_patch, &data.role_mapping);
+
+    if let Some((entry, message)) = find_message(&session, &message_id, &mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+    {
+        let patched = patch(message.clone());
+
+        let replaced: i64 = redis::cmd("EVAL")
+            .arg(REPLACE_ENTRY_SCRIPT)
+            .arg(1)
+            .arg(session.messages())
+            .arg(entry)
+            .arg(patched.to_redis_entry())
+            .query_async(&mut conn)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+
+        if replaced > 0 {
+            found = true;
+            original = Some(message);
+            updated = Some(patched);
+        }
+    }
+
+    if !found {
+        found = mark_context_stale(&session, &message_id, &mut conn)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+    }
+
+    // Search results show the message as edited among their context
+    let mut recorded_original = None;
+    let recorded = update_history(
+        &session,
+        &message_id,
+        |message| {
+            recorded_original = Some(message.clone());
+            patch(message)
+        },
+        &mut conn,
+    )
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+    found = found || recorded.is_some();
+    let original = original.or(recorded_original);
+    let updated = updated.or(recorded);
+
+    if let (Some(original), Some(updated)) = (&original, &updated) {
+        adjust_tokens(
+            &session,
+            count_tokens(std::slice::from_ref(updated))
+                - count_tokens(std::slice::from_ref(original)),
+            &mut conn,
+        )
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    }
+    drop(session_lock);
+
+    if !data.long_term_memory {
+        if !found {
+            return Err(error::ErrorNotFound("Message not found"));
+        }
+
+        let response = AckResponse { status: "Ok" };
+        return Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .json(response));
+    }
+
+    // Embedded the way new messages are, from their indexed parts
+    let indexed_content = message_patch
+        .content
+        .as_ref()
+        .map(|content| match &updated {
+            Some(updated) => updated.index_text(&data.indexed_parts),
+            None => content.index_text(&data.indexed_parts),
+        });
+
+    let excluded = match &updated {
+        Some(updated) => {
+            let filter = indexing_filter(&data, session.namespace.as_deref(), &mut conn)
+                .await
+                .map_err(error::ErrorInternalServerError)?;
+            !filter.indexes(updated, &updated.index_text(&data.indexed_parts))
+        }
+        None => false,
+    };
+
+    if excluded || indexed_content.as_deref() == Some("") {
+        // Nothing left worth embedding after the edit, or the indexing rules
+        // leave the message out now
+        let deleted_vector = delete_message_vector(&session, &message_id, conn)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+
+        found = found || deleted_vector;
+    } else {
+        let client_wrapper = data
+            .openai_pool
+            .get()
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        let openai_client = client_wrapper.deref();
+
+        let updated_vector = update_message_vector(
+            &session,
+            &message_id,
+            message_patch.role.as_deref(),
+            indexed_content.as_deref(),
+            &data,
+            openai_client,
+            conn,
+        )
+        .await
+        .map_err(|e| {
+            log::error!("Error updating message vector: {:?}", e);
+            error::ErrorInternalServerError("Internal server error")
+        })?;
+
+        found = found || updated_vector;
+    }
+
+    if !found {
+        return Err(error::ErrorNotFound("Message not found"));
+    }
+
+    let response = AckResponse { status: "Ok" };
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(response))
+}
+
+#[delete("/sessions/{session_id}/messages/{message_id}")]
+pub async fn delete_message(
+    path: web::Path<(String, String)>,
+    data: web::Data<Arc<AppState>>,
+    redis: web::Data<redis::Client>,
+    web::Query(namespace_query): web::Query<NamespaceQuery>,
+) -> actix_web::Result<impl Responder> {
+    let (session_id, message_id) = path.into_inner();
+    let session = SessionKeys::parse(namespace_query.namespace.as_deref(), &session_id)
+        .map_err(error::ErrorBadRequest)?;
+
+    let mut conn = redis
+        .get_tokio_connection_manager()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let session_lock = RedisLock::acquire(session.lock(), &conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let mut found = false;
+    let mut deleted = None;
+
+    if let Some((entry, message)) = find_message(&session, &message_id, &mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+    {
+        let removed: i64 = redis::Cmd::lrem(session.messages(), 1, entry)
+            .query_async(&mut conn)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+
+        if removed > 0 {
+            found = true;
+            deleted = Some(message);
+        }
+    }
+
+    if !found {
+        found = mark_context_stale(&session, &message_id, &mut conn)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+    }
+
+    let recorded = delete_history(&session, &message_id, &mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    if found {
+        redis::cmd("HINCRBY")
+            .arg(session.meta())
+            .arg("message_count")
+            .arg(-1)
+            .query_async::<_, ()>(&mut conn)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+    }
+    found = found || recorded.is_some();
+
+    if let Some(deleted) = deleted.or(recorded) {
+        adjust_tokens(
+            &session,
+            -count_tokens(std::slice::from_ref(&deleted)),
+            &mut conn,
+        )
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    }
+    drop(session_lock);
+
+    if data.long_term_memory {
+        let deleted_vector = delete_message_vector(&session, &message_id, conn)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+
+        found = found || deleted_vector;
+    }
+
+    if !found {
+        return Err(error::ErrorNotFound("Message not found"));
+    }
+
+    let response = AckResponse { status: "Ok" };
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{IndexedParts, MessageContent};
+    use serde_json::json;
+
+    fn message(value: serde_json::Value) -> MemoryMessage {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn patch(value: serde_json::Value) -> MessagePatch {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn patches_only_the_given_fields() {
+        let role_mapping = RoleMapping::new("", false).unwrap();
+        let original = message(json!({
+            "id": "m1",
+            "role": "user",
+            "content": "hello",
+            "created_at": 7,
+            "metadata": {"source": "web"},
+        }));
+
+        let updated = apply_patch(original, &patch(json!({"content": "bye"})), &role_mapping);
+
+        assert_eq!(updated.id, "m1");
+        assert_eq!(updated.role, "user");
+        assert_eq!(updated.created_at, 7);
+        assert_eq!(updated.metadata, Some(json!({"source": "web"})));
+        assert_eq!(updated.content.render(), "bye");
+    }
+
+    #[test]
+    fn patched_roles_are_mapped_again() {
+        let role_mapping = RoleMapping::new("narrator=system", false).unwrap();
+        let original = message(json!({"role": "user", "canonical_role": "user", "content": "hi"}));
+
+        let narrated = apply_patch(
+            original.clone(),
+            &patch(json!({"role": "Narrator"})),
+            &role_mapping,
+        );
+        assert_eq!(
+            narrated.canonical_role.map(|role| role.as_str()),
+            Some("system")
+        );
+
+        let unknown = apply_patch(original, &patch(json!({"role": "bard"})), &role_mapping);
+        assert!(unknown.canonical_role.is_none());
+    }
+
+    #[test]
+    fn patched_content_is_indexed_from_its_indexed_parts() {
+        let role_mapping = RoleMapping::new("", false).unwrap();
+        let original = message(json!({"role": "user", "content": "hi"}));
+        let content = json!([
+            {"type": "text", "text": "the answer"},
+            {"type": "tool_result", "tool_use_id": "t1", "content": "42"},
+        ]);
+
+        let updated = apply_patch(original, &patch(json!({"content": content})), &role_mapping);
+        let content: MessageContent = serde_json::from_value(content).unwrap();
+
+        assert_eq!(updated.index_text(&IndexedParts::default()), "the answer");
+        assert_eq!(content.index_text(&IndexedParts::default()), "the answer");
+        assert_eq!(
+            updated.index_text(&IndexedParts::parse("text,tool_results")),
+            "the answer\n[tool result t1]: 42"
+        );
+    }
+
+    #[test]
+    fn edits_change_the_token_count_by_their_difference() {
+        let role_mapping = RoleMapping::new("", false).unwrap();
+        let original = message(json!({"role": "user", "content": "hi"}));
+        let updated = apply_patch(
+            original.clone(),
+            &patch(json!({"content": "hi, how is everyone doing today?"})),
+            &role_mapping,
+        );
+
+        let before = count_tokens(std::slice::from_ref(&original));
+        let after = count_tokens(std::slice::from_ref(&updated));
+
+        assert!(after > before);
+        assert_eq!(count_tokens(&[original, updated]), before + after);
+    }
+}
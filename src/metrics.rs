@@ -0,0 +1,13 @@
+use crate::models::{AppState, MetricsResponse};
+use actix_web::{get, web, Responder};
+use std::sync::Arc;
+
+#[get("/metrics")]
+pub async fn get_metrics(data: web::Data<Arc<AppState>>) -> actix_web::Result<impl Responder> {
+    let res = MetricsResponse {
+        indexing: data.indexer.metrics(),
+        embedding_cache: data.embeddings.metrics(),
+    };
+
+    Ok(web::Json(res))
+}
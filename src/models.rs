@@ -1,3 +1,6 @@
+use crate::chunker::Chunker;
+use crate::embedding_cache::EmbeddingCache;
+use crate::indexer::Indexer;
 use async_openai::{
     config::{AzureConfig, OpenAIConfig},
     error::OpenAIError,
@@ -17,6 +20,7 @@ use std::env;
 use std::error::Error;
 use std::sync::Arc;
 use tokio::sync::Mutex;
+use tokio::task::AbortHandle;
 
 pub struct OpenAIClientManager {}
 
@@ -26,28 +30,23 @@ impl Manager for OpenAIClientManager {
     type Error = MotorheadError;
 
     async fn create(&self) -> Result<AnyOpenAIClient, MotorheadError> {
-        let openai_client = match (
-            env::var("AZURE_API_KEY"),
-            env::var("AZURE_DEPLOYMENT_ID"),
-            env::var("AZURE_DEPLOYMENT_ID_ADA"),
-            env::var("AZURE_API_BASE"),
-        ) {
-            (
-                Ok(azure_api_key),
-                Ok(azure_deployment_id),
-                Ok(azure_deployment_id_ada),
-                Ok(azure_api_base),
-            ) => {
+        let openai_client = match OpenAIEndpoint::from_env() {
+            OpenAIEndpoint::Azure {
+                api_key,
+                api_base,
+                deployment_id,
+                deployment_id_ada,
+            } => {
                 let config = AzureConfig::new()
-                    .with_api_base(&azure_api_base)
-                    .with_api_key(&azure_api_key)
-                    .with_deployment_id(azure_deployment_id)
+                    .with_api_base(&api_base)
+                    .with_api_key(&api_key)
+                    .with_deployment_id(deployment_id)
                     .with_api_version("2023-05-15");
 
                 let config_ada = AzureConfig::new()
-                    .with_api_base(&azure_api_base)
-                    .with_api_key(&azure_api_key)
-                    .with_deployment_id(azure_deployment_id_ada)
+                    .with_api_base(&api_base)
+                    .with_api_key(&api_key)
+                    .with_deployment_id(deployment_id_ada)
                     .with_api_version("2023-05-15");
 
                 AnyOpenAIClient::Azure {
@@ -55,24 +54,21 @@ impl Manager for OpenAIClientManager {
                     completion_client: Client::with_config(config),
                 }
             }
-            _ => {
-                let openai_api_base = env::var("OPENAI_API_BASE");
-
-                if let Ok(openai_api_base) = openai_api_base {
-                    let embedding_config = OpenAIConfig::default().with_api_base(&openai_api_base);
-                    let completion_config = OpenAIConfig::default().with_api_base(&openai_api_base);
+            OpenAIEndpoint::OpenAI {
+                api_base: Some(api_base),
+            } => {
+                let embedding_config = OpenAIConfig::default().with_api_base(&api_base);
+                let completion_config = OpenAIConfig::default().with_api_base(&api_base);
 
-                    AnyOpenAIClient::OpenAI {
-                        embedding_client: Client::with_config(embedding_config),
-                        completion_client: Client::with_config(completion_config),
-                    }
-                } else {
-                    AnyOpenAIClient::OpenAI {
-                        embedding_client: Client::new(),
-                        completion_client: Client::new(),
-                    }
+                AnyOpenAIClient::OpenAI {
+                    embedding_client: Client::with_config(embedding_config),
+                    completion_client: Client::with_config(completion_config),
                 }
             }
+            OpenAIEndpoint::OpenAI { api_base: None } => AnyOpenAIClient::OpenAI {
+                embedding_client: Client::new(),
+                completion_client: Client::new(),
+            },
         };
         Ok(openai_client)
     }
@@ -82,6 +78,61 @@ impl Manager for OpenAIClientManager {
     }
 }
 
+pub const EMBEDDING_MODEL: &str = "text-embedding-ada-002";
+
+/// The API the OpenAI clients talk to, as configured in the environment: Azure
+/// when all of its settings are there, OpenAI otherwise.
+pub enum OpenAIEndpoint {
+    Azure {
+        api_key: String,
+        api_base: String,
+        deployment_id: String,
+        deployment_id_ada: String,
+    },
+    OpenAI {
+        api_base: Option<String>,
+    },
+}
+
+impl OpenAIEndpoint {
+    pub fn from_env() -> Self {
+        match (
+            env::var("AZURE_API_KEY"),
+            env::var("AZURE_DEPLOYMENT_ID"),
+            env::var("AZURE_DEPLOYMENT_ID_ADA"),
+            env::var("AZURE_API_BASE"),
+        ) {
+            (Ok(api_key), Ok(deployment_id), Ok(deployment_id_ada), Ok(api_base)) => {
+                OpenAIEndpoint::Azure {
+                    api_key,
+                    api_base,
+                    deployment_id,
+                    deployment_id_ada,
+                }
+            }
+            _ => OpenAIEndpoint::OpenAI {
+                api_base: env::var("OPENAI_API_BASE").ok(),
+            },
+        }
+    }
+
+    /// Names the model embeddings come from: the Azure deployment, or the
+    /// OpenAI model and the API serving it.
+    pub fn embedding_model(&self) -> String {
+        match self {
+            OpenAIEndpoint::Azure {
+                api_base,
+                deployment_id_ada,
+                ..
+            } => format!("azure:{}/{}", api_base, deployment_id_ada),
+            OpenAIEndpoint::OpenAI {
+                api_base: Some(api_base),
+            } => format!("{}/{}", api_base, EMBEDDING_MODEL),
+            OpenAIEndpoint::OpenAI { api_base: None } => String::from(EMBEDDING_MODEL),
+        }
+    }
+}
+
 pub enum AnyOpenAIClient {
     Azure {
         embedding_client: Client<AzureConfig>,
@@ -127,7 +178,7 @@ impl AnyOpenAIClient {
                 embedding_client, ..
             } => {
                 let request = CreateEmbeddingRequestArgs::default()
-                    .model("text-embedding-ada-002")
+                    .model(EMBEDDING_MODEL)
                     .input(query_vec)
                     .build()?;
 
@@ -147,7 +198,7 @@ impl AnyOpenAIClient {
                     .into_iter()
                     .map(|query| async {
                         let request = CreateEmbeddingRequestArgs::default()
-                            .model("text-embedding-ada-002")
+                            .model(EMBEDDING_MODEL)
                             .input(vec![query])
                             .build()?;
 
@@ -177,20 +228,662 @@ impl AnyOpenAIClient {
 pub struct AppState {
     pub window_size: i64,
     pub session_cleanup: Arc<Mutex<HashMap<String, bool>>>,
+    pub session_tasks: Arc<Mutex<HashMap<String, Vec<AbortHandle>>>>,
     pub openai_pool: deadpool::managed::Pool<OpenAIClientManager>,
     pub long_term_memory: bool,
+    pub fact_extraction: bool,
+    pub knowledge_graph: bool,
     pub model: String,
+    pub idempotency_ttl: usize,
+    pub indexed_parts: IndexedParts,
+    pub chunker: Chunker,
+    pub indexer: Indexer,
+    pub embeddings: EmbeddingCache,
+    pub indexing_rules: IndexingRules,
+    pub role_mapping: RoleMapping,
+    pub session_ttl: Option<i64>,
+}
+
+impl AppState {
+    /// Remembers a background compaction task so deleting the session can
+    /// cancel it.
+    pub async fn track_task(&self, session_id: &str, task: AbortHandle) {
+        let mut session_tasks = self.session_tasks.lock().await;
+        let tasks = session_tasks.entry(session_id.to_string()).or_default();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(task);
+    }
+
+    /// Aborts the session's unfinished background tasks, returning how many there
+    /// were.
+    pub async fn cancel_tasks(&self, session_id: &str) -> usize {
+        let tasks = self
+            .session_tasks
+            .lock()
+            .await
+            .remove(session_id)
+            .unwrap_or_default();
+        self.session_cleanup.lock().await.remove(session_id);
+
+        tasks
+            .into_iter()
+            .filter(|task| !task.is_finished())
+            .map(|task| task.abort())
+            .count()
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct SearchPayload {
     pub text: String,
+    #[serde(default = "default_top_k")]
+    pub top_k: usize,
+    /// Drops results further than this from the query.
+    pub max_distance: Option<f64>,
+    /// Drops results whose similarity, `1 - dist`, is below this.
+    pub min_score: Option<f64>,
+    /// Only searches messages with one of these roles.
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// Only searches messages created at or after this unix timestamp.
+    pub created_after: Option<i64>,
+    /// Only searches messages created at or before this unix timestamp.
+    pub created_before: Option<i64>,
+    #[serde(default)]
+    pub mode: SearchMode,
+    /// How hybrid searches combine keyword and vector results.
+    #[serde(default)]
+    pub fusion: Fusion,
+    /// Share of the hybrid score given to vector results, the rest going to
+    /// keyword results.
+    #[serde(default = "default_vector_weight")]
+    pub vector_weight: f64,
+    /// Re-ranks results with maximal marginal relevance, from 0 (relevance
+    /// only) to 1 (diversity only).
+    pub diversity: Option<f64>,
+    /// Drops results repeating a better ranked one, word for word or nearly.
+    #[serde(default)]
+    pub dedupe: bool,
+    /// Has the LLM score the top candidates against the query and returns the
+    /// best scored ones.
+    #[serde(default)]
+    pub rerank: bool,
+    /// How many candidates the LLM scores, at least `top_k`.
+    pub rerank_candidates: Option<usize>,
+    /// How many messages before and after each result are returned with it.
+    #[serde(default)]
+    pub context_window: usize,
+}
+
+/// A search across every session of a namespace, optionally narrowed down to
+/// some sessions or a user's.
+#[derive(Deserialize)]
+pub struct NamespaceSearchPayload {
+    #[serde(flatten)]
+    pub search: SearchPayload,
+    /// Only searches these sessions.
+    #[serde(default)]
+    pub sessions: Vec<String>,
+    /// Only searches the sessions linked to this user.
+    pub user_id: Option<String>,
+}
+
+impl NamespaceSearchPayload {
+    pub fn check(&self) -> Result<(), MotorheadError> {
+        self.search.check()?;
+
+        if self.sessions.iter().any(String::is_empty) {
+            return Err(MotorheadError::InvalidRequest(String::from(
+                "Invalid session filter",
+            )));
+        }
+
+        if self.user_id.as_deref() == Some("") {
+            return Err(MotorheadError::InvalidRequest(String::from(
+                "Invalid user filter",
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    #[default]
+    Vector,
+    Keyword,
+    Hybrid,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Fusion {
+    /// Reciprocal rank fusion, only looks at the rank in each result list.
+    #[default]
+    Rrf,
+    /// Adds up the vector similarity and the BM25 score normalized to the best
+    /// keyword hit.
+    Weighted,
+}
+
+impl SearchPayload {
+    pub fn check(&self) -> Result<(), MotorheadError> {
+        if !(1..=MAX_TOP_K).contains(&self.top_k) {
+            return Err(MotorheadError::InvalidRequest(format!(
+                "top_k must be between 1 and {}",
+                MAX_TOP_K
+            )));
+        }
+
+        if !(0.0..=1.0).contains(&self.vector_weight) {
+            return Err(MotorheadError::InvalidRequest(String::from(
+                "vector_weight must be between 0 and 1",
+            )));
+        }
+
+        if self
+            .diversity
+            .is_some_and(|diversity| !(0.0..=1.0).contains(&diversity))
+        {
+            return Err(MotorheadError::InvalidRequest(String::from(
+                "diversity must be between 0 and 1",
+            )));
+        }
+
+        if self.rerank && self.top_k > MAX_RERANK_CANDIDATES {
+            return Err(MotorheadError::InvalidRequest(format!(
+                "top_k can't be over {} when reranking",
+                MAX_RERANK_CANDIDATES
+            )));
+        }
+
+        if self.rerank && !(self.top_k..=MAX_RERANK_CANDIDATES).contains(&self.rerank_count()) {
+            return Err(MotorheadError::InvalidRequest(format!(
+                "rerank_candidates must be between top_k and {}",
+                MAX_RERANK_CANDIDATES
+            )));
+        }
+
+        if self.context_window > MAX_CONTEXT_WINDOW {
+            return Err(MotorheadError::InvalidRequest(format!(
+                "context_window can't be over {}",
+                MAX_CONTEXT_WINDOW
+            )));
+        }
+
+        if self
+            .roles
+            .iter()
+            .any(|role| role.is_empty() || !role.chars().all(|c| c.is_alphanumeric() || c == '_'))
+        {
+            return Err(MotorheadError::InvalidRequest(String::from(
+                "Invalid role filter",
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Whether results are diversified after the search, which then looks at
+    /// more candidates than it returns.
+    pub fn diversifies(&self) -> bool {
+        self.dedupe || self.diversity.is_some()
+    }
+
+    /// How many results the search returns before the final cut to `top_k`.
+    pub fn rerank_count(&self) -> usize {
+        if self.rerank {
+            self.rerank_candidates
+                .unwrap_or(DEFAULT_RERANK_CANDIDATES.max(self.top_k))
+        } else {
+            self.top_k
+        }
+    }
+
+    /// The tighter of `max_distance` and `min_score`, as a distance.
+    pub fn distance_threshold(&self) -> Option<f64> {
+        let from_score = self.min_score.map(|score| 1.0 - score);
+        match (self.max_distance, from_score) {
+            (Some(distance), Some(from_score)) => Some(distance.min(from_score)),
+            (distance, from_score) => distance.or(from_score),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FunctionCall {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type", default = "default_tool_call_type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+fn default_tool_call_type() -> String {
+    String::from("function")
+}
+
+// Either plain text, or a list of OpenAI/Anthropic style content parts (`text`,
+// `image_url`, `tool_use`, `tool_result`, ...). Parts are kept as raw JSON so
+// that unknown part types survive a round trip untouched.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<serde_json::Value>),
+    #[default]
+    Empty,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum PartKind {
+    Text,
+    ToolCall,
+    ToolResult,
+}
+
+fn render_part(part: &serde_json::Value) -> Option<(PartKind, String)> {
+    let kind = part
+        .get("type")
+        .and_then(|kind| kind.as_str())
+        .unwrap_or("");
+    let str_field = |field: &str| part.get(field).and_then(|value| value.as_str());
+
+    match kind {
+        "text" => str_field("text").map(|text| (PartKind::Text, text.to_string())),
+        "tool_use" => Some((
+            PartKind::ToolCall,
+            format!(
+                "[tool call {}: {}]",
+                str_field("name").unwrap_or_default(),
+                part.get("input").cloned().unwrap_or_default()
+            ),
+        )),
+        "tool_result" => {
+            let content = match part.get("content") {
+                Some(serde_json::Value::String(text)) => text.clone(),
+                Some(serde_json::Value::Array(parts)) => parts
+                    .iter()
+                    .filter_map(render_part)
+                    .map(|(_, text)| text)
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                _ => String::new(),
+            };
+            Some((
+                PartKind::ToolResult,
+                format!(
+                    "[tool result {}]: {}",
+                    str_field("tool_use_id").unwrap_or_default(),
+                    content
+                ),
+            ))
+        }
+        "" => None,
+        other => Some((PartKind::Text, format!("[{}]", other))),
+    }
+}
+
+impl MessageContent {
+    fn rendered_parts(&self) -> Vec<(PartKind, String)> {
+        match self {
+            MessageContent::Text(text) => vec![(PartKind::Text, text.clone())],
+            MessageContent::Parts(parts) => parts.iter().filter_map(render_part).collect(),
+            MessageContent::Empty => vec![],
+        }
+    }
+
+    pub fn render(&self) -> String {
+        self.rendered_parts()
+            .into_iter()
+            .map(|(_, text)| text)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Like `MemoryMessage::index_text`, for content whose message is gone.
+    pub fn index_text(&self, indexed_parts: &IndexedParts) -> String {
+        self.rendered_parts()
+            .into_iter()
+            .filter(|(kind, _)| indexed_parts.includes(*kind))
+            .map(|(_, text)| text)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Which parts of a message are embedded into long term memory.
+#[derive(Clone, Copy)]
+pub struct IndexedParts {
+    pub text: bool,
+    pub tool_calls: bool,
+    pub tool_results: bool,
+}
+
+impl Default for IndexedParts {
+    fn default() -> Self {
+        IndexedParts {
+            text: true,
+            tool_calls: false,
+            tool_results: false,
+        }
+    }
+}
+
+impl IndexedParts {
+    pub fn parse(value: &str) -> Self {
+        let parts: Vec<String> = value
+            .split(',')
+            .map(|part| part.trim().to_lowercase())
+            .collect();
+
+        IndexedParts {
+            text: parts.iter().any(|part| part == "text"),
+            tool_calls: parts.iter().any(|part| part == "tool_calls"),
+            tool_results: parts.iter().any(|part| part == "tool_results"),
+        }
+    }
+
+    fn includes(&self, kind: PartKind) -> bool {
+        match kind {
+            PartKind::Text => self.text,
+            PartKind::ToolCall => self.tool_calls,
+            PartKind::ToolResult => self.tool_results,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CanonicalRole {
+    System,
+    User,
+    Assistant,
+    Tool,
+}
+
+impl CanonicalRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CanonicalRole::System => "system",
+            CanonicalRole::User => "user",
+            CanonicalRole::Assistant => "assistant",
+            CanonicalRole::Tool => "tool",
+        }
+    }
+
+    // Matches the speaker labels used in the summarization prompt
+    fn prompt_label(&self) -> &'static str {
+        match self {
+            CanonicalRole::System => "System",
+            CanonicalRole::User => "Human",
+            CanonicalRole::Assistant => "AI",
+            CanonicalRole::Tool => "Tool",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "system" => Some(CanonicalRole::System),
+            "user" => Some(CanonicalRole::User),
+            "assistant" => Some(CanonicalRole::Assistant),
+            "tool" => Some(CanonicalRole::Tool),
+            _ => None,
+        }
+    }
+}
+
+/// Which messages are embedded into long term memory. The rules a namespace
+/// leaves out fall back to the global ones.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct IndexingRules {
+    /// Canonical roles of the messages indexed, all of them when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub roles: Option<Vec<CanonicalRole>>,
+    /// Messages with fewer tokens than this aren't indexed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_tokens: Option<usize>,
+    /// Regular expressions, messages matching any of them aren't indexed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exclude: Option<Vec<String>>,
+}
+
+impl IndexingRules {
+    /// `roles` is a comma separated list of canonical roles, empty for all.
+    pub fn parse(
+        roles: &str,
+        min_tokens: Option<usize>,
+        exclude: Option<String>,
+    ) -> Result<Self, String> {
+        let roles = roles
+            .split(',')
+            .map(str::trim)
+            .filter(|role| !role.is_empty())
+            .map(|role| {
+                CanonicalRole::parse(role)
+                    .ok_or_else(|| format!("Invalid canonical role: {}", role))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(IndexingRules {
+            roles: Some(roles).filter(|roles| !roles.is_empty()),
+            min_tokens,
+            exclude: exclude.map(|pattern| vec![pattern]),
+        })
+    }
+
+    pub fn or(&self, fallback: &IndexingRules) -> IndexingRules {
+        IndexingRules {
+            roles: self.roles.clone().or_else(|| fallback.roles.clone()),
+            min_tokens: self.min_tokens.or(fallback.min_tokens),
+            exclude: self.exclude.clone().or_else(|| fallback.exclude.clone()),
+        }
+    }
+}
+
+/// Maps the free-form roles clients send onto canonical roles. Matching is
+/// case-insensitive.
+pub struct RoleMapping {
+    aliases: HashMap<String, CanonicalRole>,
+    pub strict: bool,
+}
+
+impl RoleMapping {
+    /// `extra_aliases` is a comma separated list of `alias=role` pairs, added on
+    /// top of the built-in aliases.
+    pub fn new(extra_aliases: &str, strict: bool) -> Result<Self, String> {
+        let mut aliases: HashMap<String, CanonicalRole> = [
+            ("system", CanonicalRole::System),
+            ("developer", CanonicalRole::System),
+            ("user", CanonicalRole::User),
+            ("human", CanonicalRole::User),
+            ("assistant", CanonicalRole::Assistant),
+            ("ai", CanonicalRole::Assistant),
+            ("model", CanonicalRole::Assistant),
+            ("tool", CanonicalRole::Tool),
+            ("function", CanonicalRole::Tool),
+        ]
+        .into_iter()
+        .map(|(alias, role)| (alias.to_string(), role))
+        .collect();
+
+        for pair in extra_aliases
+            .split(',')
+            .filter(|pair| !pair.trim().is_empty())
+        {
+            let (alias, role) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid role alias: {}", pair))?;
+            let role = CanonicalRole::parse(role.trim())
+                .ok_or_else(|| format!("Invalid canonical role: {}", role))?;
+            aliases.insert(alias.trim().to_lowercase(), role);
+        }
+
+        Ok(RoleMapping { aliases, strict })
+    }
+
+    pub fn resolve(&self, role: &str) -> Option<CanonicalRole> {
+        self.aliases.get(&role.to_lowercase()).copied()
+    }
+
+    /// Sets the message's canonical role, failing in strict mode if its role is unknown.
+    pub fn apply(&self, message: &mut MemoryMessage) -> Result<(), String> {
+        message.canonical_role = self.resolve(&message.role);
+
+        if self.strict && message.canonical_role.is_none() {
+            return Err(format!("Unknown role: {}", message.role));
+        }
+
+        Ok(())
+    }
+
+    pub fn format(&self, mut message: MemoryMessage, role_format: RoleFormat) -> MemoryMessage {
+        if role_format == RoleFormat::Canonical {
+            if let Some(role) = message
+                .canonical_role
+                .or_else(|| self.resolve(&message.role))
+            {
+                message.role = role.as_str().to_string();
+                message.canonical_role = Some(role);
+            }
+        }
+
+        message
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RoleFormat {
+    #[default]
+    Native,
+    Canonical,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct MemoryMessage {
+    #[serde(default)]
+    pub id: String,
     pub role: String,
-    pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub canonical_role: Option<CanonicalRole>,
+    #[serde(default)]
+    pub content: MessageContent,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    #[serde(default)]
+    pub created_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+}
+
+impl MemoryMessage {
+    pub fn to_redis_entry(&self) -> String {
+        serde_json::to_string(self).expect("MemoryMessage is always serializable")
+    }
+
+    // Entries written before messages were stored as JSON records are plain
+    // `"{role}: {content}"` strings, so fall back to parsing those.
+    pub fn from_redis_entry(entry: &str) -> Option<Self> {
+        if let Ok(message) = serde_json::from_str::<MemoryMessage>(entry) {
+            return Some(message);
+        }
+
+        let mut parts = entry.splitn(2, ": ");
+        match (parts.next(), parts.next()) {
+            (Some(role), Some(content)) => Some(MemoryMessage {
+                id: String::new(),
+                role: role.to_string(),
+                canonical_role: None,
+                content: MessageContent::Text(content.to_string()),
+                name: None,
+                tool_calls: None,
+                tool_call_id: None,
+                created_at: 0,
+                client_id: None,
+                metadata: None,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.id.is_empty()
+    }
+
+    fn rendered_parts(&self) -> Vec<(PartKind, String)> {
+        let mut parts = self.content.rendered_parts();
+
+        // An OpenAI `tool` message carries the result as its plain content
+        if let Some(tool_call_id) = &self.tool_call_id {
+            parts = parts
+                .into_iter()
+                .map(|(kind, text)| match kind {
+                    PartKind::Text => (
+                        PartKind::ToolResult,
+                        format!("[tool result {}]: {}", tool_call_id, text),
+                    ),
+                    _ => (kind, text),
+                })
+                .collect();
+        }
+
+        for tool_call in self.tool_calls.iter().flatten() {
+            parts.push((
+                PartKind::ToolCall,
+                format!(
+                    "[tool call {}: {}]",
+                    tool_call.function.name, tool_call.function.arguments
+                ),
+            ));
+        }
+
+        parts
+    }
+
+    pub fn to_prompt_line(&self) -> String {
+        let body = self
+            .rendered_parts()
+            .into_iter()
+            .map(|(_, text)| text)
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let speaker = self
+            .canonical_role
+            .map(|role| role.prompt_label())
+            .unwrap_or(&self.role);
+
+        match &self.name {
+            Some(name) => format!("{} ({}): {}", speaker, name, body),
+            None => format!("{}: {}", speaker, body),
+        }
+    }
+
+    /// Text embedded into long term memory for this message, empty if none of
+    /// its parts are indexed.
+    pub fn index_text(&self, indexed_parts: &IndexedParts) -> String {
+        self.rendered_parts()
+            .into_iter()
+            .filter(|(kind, _)| indexed_parts.includes(*kind))
+            .map(|(_, text)| text)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
 }
 
 #[derive(Deserialize)]
@@ -204,6 +897,201 @@ pub struct MemoryResponse {
     pub messages: Vec<MemoryMessage>,
     pub context: Option<String>,
     pub tokens: Option<i64>,
+    pub context_stale: bool,
+    pub expires_at: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct MessagePatch {
+    pub role: Option<String>,
+    pub content: Option<MessageContent>,
+    pub metadata: Option<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+pub struct SessionMetadata {
+    pub id: String,
+    pub user_id: Option<String>,
+    pub title: Option<String>,
+    pub tags: Vec<String>,
+    pub metadata: Option<serde_json::Value>,
+    pub created_at: Option<i64>,
+    pub last_active: Option<i64>,
+    pub message_count: i64,
+    pub total_tokens: i64,
+    pub namespace: Option<String>,
+    pub ttl: Option<i64>,
+    pub expires_at: Option<i64>,
+    pub parent_id: Option<String>,
+    pub forked_from_message_id: Option<String>,
+}
+
+impl SessionMetadata {
+    pub fn from_hash(id: &str, hash: HashMap<String, String>) -> Self {
+        let parse_i64 = |field: &str| hash.get(field).and_then(|value| value.parse::<i64>().ok());
+
+        SessionMetadata {
+            id: id.to_string(),
+            user_id: hash.get("user_id").cloned(),
+            title: hash.get("title").cloned(),
+            tags: hash
+                .get("tags")
+                .and_then(|tags| serde_json::from_str(tags).ok())
+                .unwrap_or_default(),
+            metadata: hash
+                .get("metadata")
+                .and_then(|metadata| serde_json::from_str(metadata).ok()),
+            created_at: parse_i64("created_at"),
+            last_active: parse_i64("last_active"),
+            message_count: parse_i64("message_count").unwrap_or(0),
+            total_tokens: parse_i64("total_tokens").unwrap_or(0),
+            namespace: hash.get("namespace").cloned(),
+            ttl: parse_i64("ttl"),
+            expires_at: parse_i64("expires_at"),
+            parent_id: hash.get("parent_id").cloned(),
+            forked_from_message_id: hash.get("forked_from_message_id").cloned(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SessionUpdate {
+    pub user_id: Option<String>,
+    pub title: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub metadata: Option<serde_json::Value>,
+    /// Seconds of inactivity after which the session expires, 0 to never expire.
+    pub ttl: Option<i64>,
+}
+
+impl SessionUpdate {
+    pub fn check(&self) -> Result<(), MotorheadError> {
+        if self.ttl.is_some_and(|ttl| ttl < 0) {
+            return Err(MotorheadError::InvalidRequest(String::from(
+                "ttl can't be negative",
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ForkRequest {
+    /// ID of the new session, generated when missing.
+    pub session_id: Option<String>,
+    /// Last message copied into the fork, all messages when missing.
+    pub message_id: Option<String>,
+    #[serde(default)]
+    pub include_vectors: bool,
+}
+
+#[derive(Deserialize)]
+pub struct RenameRequest {
+    pub session_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct MergeRequest {
+    pub source_session_id: String,
+}
+
+/// Returned by fork, rename and merge, with the resulting session's ID and how
+/// many messages and vector docs it was given.
+#[derive(Serialize)]
+pub struct SessionTransferResponse {
+    pub session_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub messages: Option<usize>,
+    pub vectors: usize,
+}
+
+/// A durable fact extracted from conversations, with the sessions and messages
+/// it was extracted from.
+#[derive(Serialize, Clone)]
+pub struct Fact {
+    pub id: String,
+    pub fact: String,
+    pub session_ids: Vec<String>,
+    pub source_message_ids: Vec<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dist: Option<f64>,
+}
+
+/// An entity of the knowledge graph, keyed by its normalized name.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GraphEntity {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub description: Option<String>,
+    pub session_ids: Vec<String>,
+    pub updated_at: i64,
+}
+
+/// A directed relation between two entities, with the sessions and messages it
+/// was extracted from.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GraphRelation {
+    pub source: String,
+    pub relation: String,
+    pub target: String,
+    pub session_ids: Vec<String>,
+    pub source_message_ids: Vec<String>,
+    pub updated_at: i64,
+}
+
+#[derive(Serialize, Default)]
+pub struct KnowledgeGraph {
+    pub entities: Vec<GraphEntity>,
+    pub relations: Vec<GraphRelation>,
+}
+
+#[derive(Deserialize)]
+pub struct GraphQuery {
+    pub entity: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct UserProfile {
+    pub user_id: String,
+    pub profile: String,
+    pub tokens: i64,
+    pub updated_at: Option<i64>,
+}
+
+/// Counters of the indexing pipeline since startup. `blocked_enqueues` and
+/// `enqueue_wait_ms` tell how often and how long requests waited on a full queue.
+#[derive(Serialize)]
+pub struct IndexingMetrics {
+    pub queue_capacity: usize,
+    pub queue_depth: usize,
+    pub enqueued_jobs: u64,
+    pub blocked_enqueues: u64,
+    pub enqueue_wait_ms: u64,
+    pub dropped_jobs: u64,
+    pub batches: u64,
+    pub retries: u64,
+    pub split_batches: u64,
+    pub failed_jobs: u64,
+    pub indexed_messages: u64,
+    pub embedded_chunks: u64,
+}
+
+/// Lookups of the embedding cache since startup, one per text embedded.
+#[derive(Serialize)]
+pub struct EmbeddingCacheMetrics {
+    pub enabled: bool,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Serialize)]
+pub struct MetricsResponse {
+    pub indexing: IndexingMetrics,
+    pub embedding_cache: EmbeddingCacheMetrics,
 }
 
 #[derive(Serialize)]
@@ -216,10 +1104,22 @@ pub struct AckResponse {
     pub status: &'static str,
 }
 
+#[derive(Serialize)]
+pub struct DeleteResponse {
+    pub status: &'static str,
+    pub messages: i64,
+    pub keys: i64,
+    pub vectors: usize,
+    pub facts: usize,
+    pub entities: usize,
+    pub cancelled_tasks: usize,
+}
+
 #[derive(Debug)]
 pub enum MotorheadError {
     RedisError(RedisError),
     IncrementalSummarizationError(String),
+    InvalidRequest(String),
 }
 
 impl std::fmt::Display for MotorheadError {
@@ -229,6 +1129,7 @@ impl std::fmt::Display for MotorheadError {
             MotorheadError::IncrementalSummarizationError(e) => {
                 write!(f, "Incremental summarization error: {}", e)
             }
+            MotorheadError::InvalidRequest(e) => write!(f, "Invalid request: {}", e),
         }
     }
 }
@@ -247,11 +1148,34 @@ impl From<RedisError> for MotorheadError {
 
 impl std::error::Error for MotorheadError {}
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct RedisearchResult {
     pub role: String,
     pub content: String,
-    pub dist: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dist: Option<f64>,
+    /// Relevance of keyword and hybrid results, higher is better.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+    /// How relevant the LLM found the result, from 0 to 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rerank_score: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<i64>,
+    /// Which chunk of a long message matched.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunk: Option<usize>,
+    /// The messages right before and after the result, oldest first.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before: Option<Vec<RedisearchResult>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<Vec<RedisearchResult>>,
 }
 
 impl FromRedisValue for RedisearchResult {
@@ -259,13 +1183,23 @@ impl FromRedisValue for RedisearchResult {
         let values: Vec<String> = redis::from_redis_value(v)?;
         let mut content = String::new();
         let mut role = String::new();
-        let mut dist = 0.0;
+        let mut dist = None;
+        let mut session = None;
+        let mut message_id = None;
+        let mut created_at = None;
+        let mut position = None;
+        let mut chunk = None;
 
         for i in 0..values.len() {
             match values[i].as_str() {
                 "content" => content = values[i + 1].clone(),
                 "role" => role = values[i + 1].clone(),
-                "dist" => dist = values[i + 1].parse::<f64>().unwrap_or(0.0),
+                "dist" => dist = values[i + 1].parse::<f64>().ok(),
+                "session" => session = Some(values[i + 1].clone()),
+                "message_id" => message_id = Some(values[i + 1].clone()),
+                "created_at" => created_at = values[i + 1].parse::<i64>().ok(),
+                "position" => position = values[i + 1].parse::<i64>().ok(),
+                "chunk" => chunk = values[i + 1].parse::<usize>().ok(),
                 _ => continue,
             }
         }
@@ -274,30 +1208,55 @@ impl FromRedisValue for RedisearchResult {
             role,
             content,
             dist,
+            score: None,
+            rerank_score: None,
+            session,
+            message_id,
+            created_at,
+            position,
+            chunk,
+            before: None,
+            after: None,
         })
     }
 }
 
-pub fn parse_redisearch_response(response: &Value) -> Vec<RedisearchResult> {
-    match response {
-        Value::Bulk(array) => {
-            let mut results = Vec::new();
-            let n = array.len();
+/// Parses FT.SEARCH replies into the doc keys, their scores when the search
+/// was run `WITHSCORES`, and the returned fields.
+pub fn parse_redisearch_response(
+    values: Vec<Value>,
+    with_scores: bool,
+) -> Vec<(String, Option<f64>, RedisearchResult)> {
+    let mut results = Vec::new();
+    let mut values = values.into_iter().skip(1);
 
-            for item in array.iter().take(n).skip(1) {
-                if let Value::Bulk(ref bulk) = item {
-                    if let Ok(result) =
-                        RedisearchResult::from_redis_value(&Value::Bulk(bulk.clone()))
-                    {
-                        results.push(result);
-                    }
-                }
-            }
+    while let Some(key) = values.next() {
+        let score = if with_scores {
+            values
+                .next()
+                .and_then(|score| redis::from_redis_value::<f64>(&score).ok())
+        } else {
+            None
+        };
+        let Some(fields) = values.next() else {
+            break;
+        };
 
-            results
+        if let (Ok(key), Ok(result)) = (
+            redis::from_redis_value::<String>(&key),
+            RedisearchResult::from_redis_value(&fields),
+        ) {
+            results.push((key, score, result));
         }
-        _ => vec![],
     }
+
+    results
+}
+
+#[derive(serde::Deserialize)]
+pub struct GetMemoryQuery {
+    #[serde(default)]
+    pub role_format: RoleFormat,
 }
 
 #[derive(serde::Deserialize)]
@@ -305,6 +1264,14 @@ pub struct NamespaceQuery {
     pub namespace: Option<String>,
 }
 
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
 #[derive(serde::Deserialize)]
 pub struct GetSessionsQuery {
     #[serde(default = "default_page")]
@@ -312,6 +1279,31 @@ pub struct GetSessionsQuery {
     #[serde(default = "default_size")]
     pub size: usize,
     pub namespace: Option<String>,
+    #[serde(default)]
+    pub details: bool,
+    /// Opaque cursor from a previous response's `X-Next-Cursor` header, used instead of `page`.
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub order: SortOrder,
+    /// Unix timestamps bounding the session's last activity, inclusive.
+    pub active_after: Option<i64>,
+    pub active_before: Option<i64>,
+    pub user_id: Option<String>,
+    /// Comma separated tags the session must all have.
+    pub tags: Option<String>,
+}
+
+const MAX_TOP_K: usize = 100;
+const DEFAULT_RERANK_CANDIDATES: usize = 20;
+const MAX_RERANK_CANDIDATES: usize = 50;
+const MAX_CONTEXT_WINDOW: usize = 10;
+
+fn default_top_k() -> usize {
+    10
+}
+
+fn default_vector_weight() -> f64 {
+    0.5
 }
 
 fn default_page() -> usize {
@@ -321,3 +1313,291 @@ fn default_page() -> usize {
 fn default_size() -> usize {
     10
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_back_stored_messages() {
+        let entry =
+            r#"{"id":"abc","role":"user","content":"hello","created_at":42,"metadata":{"k":1}}"#;
+        let message = MemoryMessage::from_redis_entry(entry).unwrap();
+
+        assert_eq!(message.id, "abc");
+        assert_eq!(message.role, "user");
+        assert_eq!(message.created_at, 42);
+        assert_eq!(message.metadata, Some(serde_json::json!({"k": 1})));
+        assert!(!message.is_legacy());
+        assert_eq!(
+            MemoryMessage::from_redis_entry(&message.to_redis_entry())
+                .unwrap()
+                .id,
+            "abc"
+        );
+    }
+
+    #[test]
+    fn reads_legacy_entries() {
+        let message = MemoryMessage::from_redis_entry("assistant: hi: there").unwrap();
+
+        assert!(message.is_legacy());
+        assert_eq!(message.role, "assistant");
+        assert_eq!(message.created_at, 0);
+        assert_eq!(message.to_prompt_line(), "assistant: hi: there");
+    }
+
+    #[test]
+    fn skips_unreadable_entries() {
+        assert!(MemoryMessage::from_redis_entry("no separator").is_none());
+    }
+
+    fn content(value: serde_json::Value) -> MessageContent {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn renders_content_parts() {
+        let parts = content(serde_json::json!([
+            {"type": "text", "text": "Let me check"},
+            {"type": "tool_use", "id": "t1", "name": "weather", "input": {"city": "Paris"}},
+            {"type": "tool_result", "tool_use_id": "t1", "content": [{"type": "text", "text": "Sunny"}]},
+            {"type": "image_url", "image_url": {"url": "https://example.com/a.png"}},
+            {"no_type": true},
+        ]));
+
+        assert_eq!(
+            parts.render(),
+            "Let me check\n\
+             [tool call weather: {\"city\":\"Paris\"}]\n\
+             [tool result t1]: Sunny\n\
+             [image_url]"
+        );
+        assert_eq!(content(serde_json::json!("plain")).render(), "plain");
+        assert_eq!(MessageContent::Empty.render(), "");
+    }
+
+    #[test]
+    fn indexes_only_the_configured_parts() {
+        let message: MemoryMessage = serde_json::from_value(serde_json::json!({
+            "role": "assistant",
+            "content": "Checking",
+            "tool_calls": [{"id": "c1", "function": {"name": "lookup", "arguments": "{}"}}],
+        }))
+        .unwrap();
+
+        assert_eq!(message.index_text(&IndexedParts::default()), "Checking");
+        assert_eq!(
+            message.index_text(&IndexedParts::parse("tool_calls")),
+            "[tool call lookup: {}]"
+        );
+        assert_eq!(message.index_text(&IndexedParts::parse("")), "");
+    }
+
+    #[test]
+    fn tool_messages_carry_results() {
+        let message: MemoryMessage = serde_json::from_value(serde_json::json!({
+            "role": "tool",
+            "canonical_role": "tool",
+            "name": "lookup",
+            "tool_call_id": "c1",
+            "content": "42",
+        }))
+        .unwrap();
+
+        assert_eq!(message.index_text(&IndexedParts::default()), "");
+        assert_eq!(
+            message.index_text(&IndexedParts::parse("tool_results")),
+            "[tool result c1]: 42"
+        );
+        assert_eq!(
+            message.to_prompt_line(),
+            "Tool (lookup): [tool result c1]: 42"
+        );
+    }
+
+    #[test]
+    fn maps_builtin_and_configured_aliases() {
+        let role_mapping = RoleMapping::new(" Narrator = system ,bot=assistant", false).unwrap();
+
+        assert_eq!(role_mapping.resolve("Human"), Some(CanonicalRole::User));
+        assert_eq!(
+            role_mapping.resolve("developer"),
+            Some(CanonicalRole::System)
+        );
+        assert_eq!(
+            role_mapping.resolve("NARRATOR"),
+            Some(CanonicalRole::System)
+        );
+        assert_eq!(role_mapping.resolve("bot"), Some(CanonicalRole::Assistant));
+        assert_eq!(role_mapping.resolve("bard"), None);
+    }
+
+    #[test]
+    fn rejects_bad_aliases() {
+        assert!(RoleMapping::new("narrator", false).is_err());
+        assert!(RoleMapping::new("narrator=storyteller", false).is_err());
+        assert!(RoleMapping::new(" , ", false).is_ok());
+    }
+
+    #[test]
+    fn strict_mode_rejects_unknown_roles() {
+        let mut message = MemoryMessage::from_redis_entry("bard: a song").unwrap();
+
+        assert!(RoleMapping::new("", false)
+            .unwrap()
+            .apply(&mut message)
+            .is_ok());
+        assert_eq!(message.canonical_role, None);
+        assert!(RoleMapping::new("", true)
+            .unwrap()
+            .apply(&mut message)
+            .is_err());
+
+        let mut message = MemoryMessage::from_redis_entry("AI: hi").unwrap();
+        assert!(RoleMapping::new("", true)
+            .unwrap()
+            .apply(&mut message)
+            .is_ok());
+        assert_eq!(message.canonical_role, Some(CanonicalRole::Assistant));
+    }
+
+    #[test]
+    fn formats_roles_natively_or_canonically() {
+        let role_mapping = RoleMapping::new("", false).unwrap();
+        let message = || MemoryMessage::from_redis_entry("Human: hi").unwrap();
+
+        assert_eq!(
+            role_mapping.format(message(), RoleFormat::Native).role,
+            "Human"
+        );
+        assert_eq!(
+            role_mapping.format(message(), RoleFormat::Canonical).role,
+            "user"
+        );
+
+        let unknown = MemoryMessage::from_redis_entry("bard: hi").unwrap();
+        assert_eq!(
+            role_mapping.format(unknown, RoleFormat::Canonical).role,
+            "bard"
+        );
+    }
+
+    #[test]
+    fn session_ttls_are_never_negative() {
+        let update = |ttl: i64| -> SessionUpdate {
+            serde_json::from_value(serde_json::json!({ "ttl": ttl })).unwrap()
+        };
+
+        assert!(update(-1).check().is_err());
+        assert!(update(0).check().is_ok());
+        assert!(update(3600).check().is_ok());
+    }
+
+    #[test]
+    fn tool_calls_default_to_functions() {
+        let tool_call: ToolCall =
+            serde_json::from_value(serde_json::json!({"id": "c1", "function": {"name": "f"}}))
+                .unwrap();
+
+        assert_eq!(tool_call.kind, "function");
+        assert_eq!(tool_call.function.arguments, "");
+    }
+
+    fn search_payload(value: serde_json::Value) -> SearchPayload {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn rerank_candidates_default_to_at_least_top_k() {
+        let payload = search_payload(serde_json::json!({"text": "q", "rerank": true}));
+        assert_eq!(payload.rerank_count(), DEFAULT_RERANK_CANDIDATES);
+
+        let payload = search_payload(serde_json::json!({"text": "q", "rerank": true, "top_k": 30}));
+        assert_eq!(payload.rerank_count(), 30);
+
+        let payload = search_payload(serde_json::json!({"text": "q", "top_k": 30}));
+        assert_eq!(payload.rerank_count(), 30);
+    }
+
+    #[test]
+    fn rerank_candidates_stay_between_top_k_and_the_max() {
+        let check = |value| search_payload(value).check().is_ok();
+
+        assert!(check(serde_json::json!({
+            "text": "q", "rerank": true, "top_k": 5, "rerank_candidates": 5
+        })));
+        assert!(check(serde_json::json!({
+            "text": "q", "rerank": true, "rerank_candidates": MAX_RERANK_CANDIDATES
+        })));
+        assert!(!check(serde_json::json!({
+            "text": "q", "rerank": true, "top_k": 10, "rerank_candidates": 5
+        })));
+        assert!(!check(serde_json::json!({
+            "text": "q", "rerank": true, "rerank_candidates": MAX_RERANK_CANDIDATES + 1
+        })));
+        assert!(!check(serde_json::json!({
+            "text": "q", "rerank": true, "top_k": MAX_RERANK_CANDIDATES + 1
+        })));
+        assert!(check(serde_json::json!({
+            "text": "q", "top_k": MAX_RERANK_CANDIDATES + 1
+        })));
+    }
+
+    #[test]
+    fn context_window_is_bounded() {
+        let check = |window: usize| {
+            search_payload(serde_json::json!({"text": "q", "context_window": window}))
+                .check()
+                .is_ok()
+        };
+
+        assert!(check(0));
+        assert!(check(MAX_CONTEXT_WINDOW));
+        assert!(!check(MAX_CONTEXT_WINDOW + 1));
+    }
+
+    #[test]
+    fn embedding_models_name_where_they_are_served() {
+        let azure = OpenAIEndpoint::Azure {
+            api_key: String::from("key"),
+            api_base: String::from("https://example.openai.azure.com"),
+            deployment_id: String::from("gpt"),
+            deployment_id_ada: String::from("ada"),
+        };
+        assert_eq!(
+            azure.embedding_model(),
+            "azure:https://example.openai.azure.com/ada"
+        );
+
+        let proxied = OpenAIEndpoint::OpenAI {
+            api_base: Some(String::from("http://localhost:8080/v1")),
+        };
+        assert_eq!(
+            proxied.embedding_model(),
+            format!("http://localhost:8080/v1/{}", EMBEDDING_MODEL)
+        );
+
+        let openai = OpenAIEndpoint::OpenAI { api_base: None };
+        assert_eq!(openai.embedding_model(), EMBEDDING_MODEL);
+    }
+
+    #[test]
+    fn namespace_searches_reject_empty_filters() {
+        let check = |value| {
+            serde_json::from_value::<NamespaceSearchPayload>(value)
+                .unwrap()
+                .check()
+                .is_ok()
+        };
+
+        assert!(check(
+            serde_json::json!({"text": "q", "sessions": ["s1"], "user_id": "u1"})
+        ));
+        assert!(!check(
+            serde_json::json!({"text": "q", "sessions": ["s1", ""]})
+        ));
+        assert!(!check(serde_json::json!({"text": "q", "user_id": ""})));
+        assert!(!check(serde_json::json!({"text": "q", "top_k": 0})));
+    }
+}
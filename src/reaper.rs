@@ -0,0 +1,102 @@
+use crate::facts::delete_session_facts;
+use crate::graph::delete_session_graph;
+use crate::long_term_memory::delete_session_vectors;
+use crate::models::{AppState, MotorheadError};
+use crate::sessions::{delete_session, session_expiry_key, SessionKeys, NAMESPACES_KEY};
+use std::sync::Arc;
+use std::time::Duration;
+
+async fn reap_expired_sessions(
+    state: &AppState,
+    namespace: Option<&str>,
+    mut conn: redis::aio::ConnectionManager,
+) -> Result<usize, MotorheadError> {
+    let now = chrono::Utc::now().timestamp();
+    let expiry_key = session_expiry_key(namespace);
+    let session_ids: Vec<String> = redis::cmd("ZRANGE")
+        .arg(&expiry_key)
+        .arg("-inf")
+        .arg(now)
+        .arg("BYSCORE")
+        .arg("LIMIT")
+        .arg(0)
+        .arg(100)
+        .query_async(&mut conn)
+        .await?;
+
+    for session_id in &session_ids {
+        // The session may have seen activity since the range was read
+        let expires_at: Option<i64> = redis::cmd("ZSCORE")
+            .arg(&expiry_key)
+            .arg(session_id)
+            .query_async(&mut conn)
+            .await?;
+        if expires_at.is_none_or(|expires_at| expires_at > now) {
+            continue;
+        }
+
+        let session = SessionKeys::new(namespace, session_id);
+        state.cancel_tasks(&session.scoped_id()).await;
+        state.indexer.cancel(&session).await;
+
+        if state.long_term_memory {
+            delete_session_vectors(&session, conn.clone()).await?;
+        }
+
+        if state.fact_extraction {
+            delete_session_facts(&session, conn.clone()).await?;
+        }
+
+        if state.knowledge_graph {
+            delete_session_graph(&session, conn.clone()).await?;
+        }
+
+        delete_session(&session, &mut conn).await?;
+        log::info!("Expired session {}", session.scoped_id());
+    }
+
+    Ok(session_ids.len())
+}
+
+/// Periodically deletes sessions whose TTL has run out, along with their long
+/// term memory vectors.
+pub async fn run_session_reaper(redis: redis::Client, state: Arc<AppState>, interval: Duration) {
+    let conn = match redis.get_tokio_connection_manager().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            log::error!("Session reaper could not connect to redis: {:?}", e);
+            return;
+        }
+    };
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        let namespaces: Vec<String> = match redis::Cmd::smembers(NAMESPACES_KEY)
+            .query_async(&mut conn.clone())
+            .await
+        {
+            Ok(namespaces) => namespaces,
+            Err(e) => {
+                log::error!("Error reading namespaces: {:?}", e);
+                continue;
+            }
+        };
+
+        for namespace in std::iter::once(None).chain(namespaces.iter().map(|ns| Some(ns.as_str())))
+        {
+            // Keep going while full batches of expired sessions come back
+            loop {
+                match reap_expired_sessions(&state, namespace, conn.clone()).await {
+                    Ok(100) => continue,
+                    Ok(_) => break,
+                    Err(e) => {
+                        log::error!("Error reaping expired sessions: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
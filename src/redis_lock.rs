@@ -0,0 +1,134 @@
+use nanoid::nanoid;
+use redis::aio::ConnectionManager;
+use redis::{ErrorKind, RedisResult};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+// Expires a lock whose holder died before releasing it. Held locks are renewed
+// well before that.
+const LOCK_TTL: Duration = Duration::from_secs(10);
+const LOCK_RENEWAL: Duration = Duration::from_secs(3);
+const LOCK_WAIT: Duration = Duration::from_secs(30);
+const LOCK_RETRY: Duration = Duration::from_millis(20);
+
+// Only touch the lock if it is still the one taken with the token
+const RENEW_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+end
+return 0
+"#;
+
+const RELEASE_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+/// A lock held in Redis, so it is shared by every instance using the same
+/// server. It is kept alive while held and released when dropped.
+pub struct RedisLock {
+    key: String,
+    token: String,
+    redis_conn: ConnectionManager,
+    renewal: JoinHandle<()>,
+}
+
+impl RedisLock {
+    /// Waits for the lock, giving up after `LOCK_WAIT`.
+    pub async fn acquire(key: String, redis_conn: &ConnectionManager) -> RedisResult<Self> {
+        let mut redis_conn = redis_conn.clone();
+        let token = nanoid!();
+        let deadline = Instant::now() + LOCK_WAIT;
+
+        loop {
+            let acquired: Option<String> = redis::cmd("SET")
+                .arg(&key)
+                .arg(&token)
+                .arg("NX")
+                .arg("PX")
+                .arg(LOCK_TTL.as_millis() as u64)
+                .query_async(&mut redis_conn)
+                .await?;
+            if acquired.is_some() {
+                break;
+            }
+
+            if Instant::now() >= deadline {
+                return Err((ErrorKind::TryAgain, "Timed out waiting for lock").into());
+            }
+            tokio::time::sleep(LOCK_RETRY).await;
+        }
+
+        let renewal = tokio::spawn(renew(key.clone(), token.clone(), redis_conn.clone()));
+
+        Ok(RedisLock {
+            key,
+            token,
+            redis_conn,
+            renewal,
+        })
+    }
+
+    /// Takes two locks in a consistent order, so concurrent calls can't deadlock.
+    pub async fn acquire_both(
+        first: String,
+        second: String,
+        redis_conn: &ConnectionManager,
+    ) -> RedisResult<(Self, Self)> {
+        if first <= second {
+            let first = Self::acquire(first, redis_conn).await?;
+            Ok((first, Self::acquire(second, redis_conn).await?))
+        } else {
+            let second = Self::acquire(second, redis_conn).await?;
+            Ok((Self::acquire(first, redis_conn).await?, second))
+        }
+    }
+}
+
+async fn renew(key: String, token: String, mut redis_conn: ConnectionManager) {
+    loop {
+        tokio::time::sleep(LOCK_RENEWAL).await;
+
+        let renewed: RedisResult<i64> = redis::cmd("EVAL")
+            .arg(RENEW_SCRIPT)
+            .arg(1)
+            .arg(&key)
+            .arg(&token)
+            .arg(LOCK_TTL.as_millis() as u64)
+            .query_async(&mut redis_conn)
+            .await;
+
+        match renewed {
+            Ok(1) => {}
+            Ok(_) => {
+                log::error!("Lock {} expired while held", key);
+                return;
+            }
+            Err(e) => log::error!("Error renewing lock {}: {:?}", key, e),
+        }
+    }
+}
+
+impl Drop for RedisLock {
+    fn drop(&mut self) {
+        self.renewal.abort();
+
+        let key = std::mem::take(&mut self.key);
+        let token = std::mem::take(&mut self.token);
+        let mut redis_conn = self.redis_conn.clone();
+        tokio::spawn(async move {
+            let released: RedisResult<i64> = redis::cmd("EVAL")
+                .arg(RELEASE_SCRIPT)
+                .arg(1)
+                .arg(&key)
+                .arg(&token)
+                .query_async(&mut redis_conn)
+                .await;
+            if let Err(e) = released {
+                log::error!("Error releasing lock {}: {:?}", key, e);
+            }
+        });
+    }
+}
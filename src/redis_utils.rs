@@ -1,9 +1,19 @@
-use redis::{self, RedisResult};
+use crate::facts::{FACTS_INDEX, FACT_KEY_PREFIX};
+use crate::long_term_memory::{session_filter, GLOBAL_NAMESPACE_TAG};
+use crate::models::{MemoryMessage, RoleMapping};
+use crate::sessions::{
+    check_namespace, session_expiry_key, tag_sessions_key, user_sessions_key, SessionKeys,
+    NAMESPACES_KEY,
+};
+use nanoid::nanoid;
+use redis::{self, RedisResult, Value};
+use std::collections::HashMap;
 
 pub fn ensure_redisearch_index(
     redis: &redis::Client,
     vector_dimensions: usize,
     distance_metric: &str,
+    role_mapping: &RoleMapping,
 ) -> RedisResult<()> {
     let mut con = redis.get_connection()?;
     let index_name = "motorhead";
@@ -26,10 +36,22 @@ pub fn ensure_redisearch_index(
                 .arg("SCHEMA")
                 .arg("session")
                 .arg("TAG")
+                .arg("namespace")
+                .arg("TAG")
+                .arg("user")
+                .arg("TAG")
                 .arg("content")
                 .arg("TEXT")
                 .arg("role")
                 .arg("TEXT")
+                .arg("canonical_role")
+                .arg("TAG")
+                .arg("created_at")
+                .arg("NUMERIC")
+                .arg("position")
+                .arg("NUMERIC")
+                .arg("chunk")
+                .arg("NUMERIC")
                 .arg("vector")
                 .arg("VECTOR")
                 .arg("HNSW")
@@ -40,7 +62,96 @@ pub fn ensure_redisearch_index(
                 .arg(vector_dimensions.to_string())
                 .arg("DISTANCE_METRIC")
                 .arg(distance_metric)
-                .query(&mut con)?;
+                .query::<()>(&mut con)?;
+        } else {
+            return Err(err);
+        }
+    } else {
+        // Indexes created by older versions lack the namespace and user tags. Their
+        // docs all belong to sessions outside any namespace until migrated.
+        if add_index_field(&mut con, index_name, "namespace", "TAG")? {
+            let vector_keys = vector_keys(&mut con)?;
+            let mut pipe = redis::pipe();
+            for key in &vector_keys {
+                pipe.cmd("HSETNX")
+                    .arg(key)
+                    .arg("namespace")
+                    .arg(GLOBAL_NAMESPACE_TAG)
+                    .ignore();
+            }
+            pipe.query::<()>(&mut con)?;
+
+            log::info!(
+                "Tagged {} vector docs with the global namespace",
+                vector_keys.len()
+            );
+        }
+
+        if add_index_field(&mut con, index_name, "user", "TAG")? {
+            let tagged = tag_session_users(&mut con)?;
+            log::info!("Tagged {} vector docs with their session's user", tagged);
+        }
+
+        if add_index_field(&mut con, index_name, "canonical_role", "TAG")? {
+            let tagged = tag_canonical_roles(&mut con, role_mapping)?;
+            log::info!("Tagged {} vector docs with their canonical role", tagged);
+        }
+
+        // Adding a field reindexes the docs that already carry it
+        add_index_field(&mut con, index_name, "created_at", "NUMERIC")?;
+        add_index_field(&mut con, index_name, "position", "NUMERIC")?;
+        add_index_field(&mut con, index_name, "chunk", "NUMERIC")?;
+    }
+
+    Ok(())
+}
+
+pub fn ensure_facts_index(
+    redis: &redis::Client,
+    vector_dimensions: usize,
+    distance_metric: &str,
+) -> RedisResult<()> {
+    let mut con = redis.get_connection()?;
+
+    let index_info: Result<redis::Value, _> =
+        redis::cmd("FT.INFO").arg(FACTS_INDEX).query(&mut con);
+
+    if let Err(err) = index_info {
+        if err
+            .to_string()
+            .to_lowercase()
+            .contains("unknown: index name")
+        {
+            redis::cmd("FT.CREATE")
+                .arg(FACTS_INDEX)
+                .arg("ON")
+                .arg("HASH")
+                .arg("PREFIX")
+                .arg("1")
+                .arg(FACT_KEY_PREFIX)
+                .arg("SCHEMA")
+                .arg("session")
+                .arg("TAG")
+                .arg("namespace")
+                .arg("TAG")
+                .arg("user")
+                .arg("TAG")
+                .arg("fact")
+                .arg("TEXT")
+                .arg("updated_at")
+                .arg("NUMERIC")
+                .arg("SORTABLE")
+                .arg("vector")
+                .arg("VECTOR")
+                .arg("HNSW")
+                .arg("6")
+                .arg("TYPE")
+                .arg("FLOAT32")
+                .arg("DIM")
+                .arg(vector_dimensions.to_string())
+                .arg("DISTANCE_METRIC")
+                .arg(distance_metric)
+                .query::<()>(&mut con)?;
         } else {
             return Err(err);
         }
@@ -48,3 +159,372 @@ pub fn ensure_redisearch_index(
 
     Ok(())
 }
+
+// Returns whether the field was missing from the index
+fn add_index_field(
+    con: &mut redis::Connection,
+    index_name: &str,
+    field: &str,
+    field_type: &str,
+) -> RedisResult<bool> {
+    let altered: RedisResult<()> = redis::cmd("FT.ALTER")
+        .arg(index_name)
+        .arg("SCHEMA")
+        .arg("ADD")
+        .arg(field)
+        .arg(field_type)
+        .query(con);
+
+    match altered {
+        Ok(()) => Ok(true),
+        Err(err) if err.to_string().to_lowercase().contains("duplicate") => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn vector_keys(con: &mut redis::Connection) -> RedisResult<Vec<String>> {
+    let keys = redis::cmd("SCAN")
+        .cursor_arg(0)
+        .arg("MATCH")
+        .arg("motorhead:*")
+        .arg("TYPE")
+        .arg("hash")
+        .clone()
+        .iter(con)?
+        .collect();
+
+    Ok(keys)
+}
+
+fn tag_canonical_roles(
+    con: &mut redis::Connection,
+    role_mapping: &RoleMapping,
+) -> RedisResult<usize> {
+    let mut pipe = redis::pipe();
+    let mut tagged = 0;
+
+    for key in vector_keys(con)? {
+        let role: Option<String> = redis::Cmd::hget(&key, "role").query(con)?;
+        if let Some(role) = role.and_then(|role| role_mapping.resolve(&role)) {
+            pipe.cmd("HSET")
+                .arg(&key)
+                .arg("canonical_role")
+                .arg(role.as_str())
+                .ignore();
+            tagged += 1;
+        }
+    }
+    pipe.query::<()>(con)?;
+
+    Ok(tagged)
+}
+
+fn tag_session_users(con: &mut redis::Connection) -> RedisResult<usize> {
+    let mut users: HashMap<String, Option<String>> = HashMap::new();
+    let mut tagged = 0;
+
+    for key in vector_keys(con)? {
+        let (session_id, namespace): (Option<String>, Option<String>) = redis::cmd("HMGET")
+            .arg(&key)
+            .arg("session")
+            .arg("namespace")
+            .query(con)?;
+        let Some(session_id) = session_id else {
+            continue;
+        };
+
+        let namespace = namespace.filter(|namespace| namespace != GLOBAL_NAMESPACE_TAG);
+        let session = SessionKeys::new(namespace.as_deref(), &session_id);
+        let meta_key = session.meta();
+
+        if !users.contains_key(&meta_key) {
+            let user_id: Option<String> = redis::Cmd::hget(&meta_key, "user_id").query(con)?;
+            users.insert(meta_key.clone(), user_id);
+        }
+
+        if let Some(user_id) = &users[&meta_key] {
+            redis::Cmd::hset(&key, "user", user_id).query::<()>(con)?;
+            tagged += 1;
+        }
+    }
+
+    Ok(tagged)
+}
+
+pub fn migrate_legacy_messages(redis: &redis::Client) -> RedisResult<usize> {
+    let mut con = redis.get_connection()?;
+
+    let session_keys: Vec<String> = redis::cmd("SCAN")
+        .cursor_arg(0)
+        .arg("MATCH")
+        .arg("*session:*")
+        .arg("TYPE")
+        .arg("list")
+        .clone()
+        .iter(&mut con)?
+        .collect();
+
+    let mut migrated = 0;
+
+    for session_key in session_keys {
+        // Rewritten under WATCH, so messages pushed meanwhile by another
+        // instance aren't lost, the rewrite is retried instead
+        let changed: bool = redis::transaction(&mut con, &[&session_key], |con, pipe| {
+            let entries: Vec<String> = redis::cmd("LRANGE")
+                .arg(&session_key)
+                .arg(0)
+                .arg(-1)
+                .query(con)?;
+
+            let now = chrono::Utc::now().timestamp();
+            let mut changed = false;
+            let messages: Vec<String> = entries
+                .iter()
+                .map(|entry| match MemoryMessage::from_redis_entry(entry) {
+                    Some(message) if message.is_legacy() => {
+                        changed = true;
+                        MemoryMessage {
+                            id: nanoid!(),
+                            created_at: now,
+                            ..message
+                        }
+                        .to_redis_entry()
+                    }
+                    _ => entry.clone(),
+                })
+                .collect();
+
+            if !changed {
+                return Ok(Some(false));
+            }
+
+            pipe.cmd("DEL")
+                .arg(&session_key)
+                .ignore()
+                .cmd("RPUSH")
+                .arg(&session_key)
+                .arg(messages)
+                .ignore()
+                .query::<Option<()>>(con)
+                .map(|exec| exec.map(|_| true))
+        })?;
+
+        if changed {
+            migrated += 1;
+        }
+    }
+
+    Ok(migrated)
+}
+
+// Namespace session lists written by older versions, which kept the sessions'
+// data under the keys of sessions outside any namespace
+fn legacy_sessions_keys(con: &mut redis::Connection) -> RedisResult<Vec<String>> {
+    let keys = redis::cmd("SCAN")
+        .cursor_arg(0)
+        .arg("MATCH")
+        .arg("sessions:*")
+        .arg("TYPE")
+        .arg("zset")
+        .clone()
+        .iter(con)?
+        .collect();
+
+    Ok(keys)
+}
+
+// Set once the namespaced sessions were migrated. The namespace session lists
+// are still in use afterwards, migrating them again would pull sessions outside
+// any namespace into the namespaces listing the same IDs.
+const NAMESPACES_MIGRATED_KEY: &str = "migrations:namespaced_sessions";
+
+/// Counts the sessions listed in a namespace whose data may still be stored
+/// outside of it, waiting for `migrate_namespaced_sessions`. None once that ran.
+pub fn count_unmigrated_sessions(redis: &redis::Client) -> RedisResult<usize> {
+    let mut con = redis.get_connection()?;
+    let migrated: bool = redis::Cmd::exists(NAMESPACES_MIGRATED_KEY).query(&mut con)?;
+    if migrated {
+        return Ok(0);
+    }
+
+    let mut unmigrated = 0;
+
+    for sessions_key in legacy_sessions_keys(&mut con)? {
+        let session_ids: Vec<String> = redis::Cmd::zrange(&sessions_key, 0, -1).query(&mut con)?;
+        if session_ids.is_empty() {
+            continue;
+        }
+
+        let mut pipe = redis::pipe();
+        for session_id in &session_ids {
+            pipe.cmd("EXISTS")
+                .arg(SessionKeys::new(None, session_id).all());
+        }
+        let exists: Vec<usize> = pipe.query(&mut con)?;
+        unmigrated += exists.iter().filter(|exists| **exists > 0).count();
+    }
+
+    Ok(unmigrated)
+}
+
+/// Moves the data of sessions listed in a namespace, which used to share keys
+/// with sessions outside any namespace, under the namespace's own keys. When an
+/// ID was listed in several namespaces, the first one migrated gets its data.
+/// Only runs once, returning `None` when it already did.
+pub fn migrate_namespaced_sessions(
+    redis: &redis::Client,
+    long_term_memory: bool,
+) -> RedisResult<Option<usize>> {
+    let mut con = redis.get_connection()?;
+
+    let migrated: bool = redis::Cmd::exists(NAMESPACES_MIGRATED_KEY).query(&mut con)?;
+    if migrated {
+        return Ok(None);
+    }
+
+    let sessions_keys = legacy_sessions_keys(&mut con)?;
+
+    let mut migrated = 0;
+
+    for sessions_key in sessions_keys {
+        let namespace = sessions_key.trim_start_matches("sessions:");
+        if let Err(err) = check_namespace(Some(namespace)) {
+            log::warn!("Skipping sessions of namespace {:?}: {}", namespace, err);
+            continue;
+        }
+
+        redis::Cmd::sadd(NAMESPACES_KEY, namespace).query::<()>(&mut con)?;
+
+        let session_ids: Vec<String> = redis::Cmd::zrange(&sessions_key, 0, -1).query(&mut con)?;
+
+        for session_id in session_ids {
+            let global = SessionKeys::new(None, &session_id);
+            let session = SessionKeys::new(Some(namespace), &session_id);
+
+            let (user_id, tags): (Option<String>, Option<String>) = redis::cmd("HMGET")
+                .arg(global.meta())
+                .arg("user_id")
+                .arg("tags")
+                .query(&mut con)?;
+            let tags: Vec<String> = tags
+                .and_then(|tags| serde_json::from_str(&tags).ok())
+                .unwrap_or_default();
+
+            let mut sorted_set_keys: Vec<(String, String)> = user_id
+                .iter()
+                .map(|user_id| {
+                    (
+                        user_sessions_key(None, user_id),
+                        user_sessions_key(Some(namespace), user_id),
+                    )
+                })
+                .chain(tags.iter().map(|tag| {
+                    (
+                        tag_sessions_key(None, tag),
+                        tag_sessions_key(Some(namespace), tag),
+                    )
+                }))
+                .collect();
+            sorted_set_keys.push((
+                session_expiry_key(None),
+                session_expiry_key(Some(namespace)),
+            ));
+
+            let mut pipe = redis::pipe();
+            for key in global.all() {
+                pipe.cmd("EXISTS").arg(key);
+            }
+            let exists: Vec<bool> = pipe.query(&mut con)?;
+
+            let mut pipe = redis::pipe();
+            for (global_key, _) in &sorted_set_keys {
+                pipe.cmd("ZSCORE").arg(global_key).arg(&session_id);
+            }
+            let scores: Vec<Option<f64>> = pipe.query(&mut con)?;
+
+            if !exists.iter().any(|exists| *exists) {
+                continue;
+            }
+
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for ((global_key, key), _) in global
+                .all()
+                .into_iter()
+                .zip(session.all())
+                .zip(&exists)
+                .filter(|(_, exists)| **exists)
+            {
+                pipe.cmd("RENAMENX").arg(global_key).arg(key).ignore();
+            }
+            for ((global_key, key), score) in sorted_set_keys.iter().zip(scores) {
+                if let Some(score) = score {
+                    pipe.cmd("ZREM").arg(global_key).arg(&session_id).ignore();
+                    pipe.cmd("ZADD")
+                        .arg(key)
+                        .arg(score)
+                        .arg(&session_id)
+                        .ignore();
+                }
+            }
+            pipe.cmd("HSET")
+                .arg(session.meta())
+                .arg("namespace")
+                .arg(namespace)
+                .ignore();
+            pipe.query::<()>(&mut con)?;
+
+            if long_term_memory {
+                tag_vectors_namespace(&mut con, &global, namespace)?;
+            }
+
+            migrated += 1;
+        }
+    }
+
+    redis::Cmd::set(NAMESPACES_MIGRATED_KEY, chrono::Utc::now().timestamp())
+        .query::<()>(&mut con)?;
+
+    Ok(Some(migrated))
+}
+
+// Docs leave the search once tagged, so the first page is searched until empty
+fn tag_vectors_namespace(
+    con: &mut redis::Connection,
+    session: &SessionKeys,
+    namespace: &str,
+) -> RedisResult<()> {
+    let page_size = 1000;
+
+    loop {
+        let values: Vec<Value> = redis::cmd("FT.SEARCH")
+            .arg("motorhead")
+            .arg(session_filter(session))
+            .arg("NOCONTENT")
+            .arg("LIMIT")
+            .arg(0)
+            .arg(page_size)
+            .arg("DIALECT")
+            .arg("2")
+            .query(con)?;
+
+        let keys: Vec<String> = values
+            .iter()
+            .skip(1)
+            .filter_map(|value| redis::from_redis_value(value).ok())
+            .collect();
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        for key in &keys {
+            pipe.cmd("HSET")
+                .arg(key)
+                .arg("namespace")
+                .arg(namespace)
+                .ignore();
+        }
+        pipe.query::<()>(con)?;
+    }
+}
@@ -1,4 +1,5 @@
-use crate::models::{AnyOpenAIClient, MotorheadError};
+use crate::models::{AnyOpenAIClient, MemoryMessage, MotorheadError};
+use crate::sessions::SessionKeys;
 use std::error::Error;
 use tiktoken_rs::p50k_base;
 
@@ -52,16 +53,63 @@ New summary:
     Ok((completion, tokens_used))
 }
 
+pub async fn incremental_profile(
+    model: String,
+    openai_client: &AnyOpenAIClient,
+    profile: Option<String>,
+    new_lines: String,
+) -> Result<(String, u32), Box<dyn Error + Send + Sync>> {
+    let prev_profile = profile.as_deref().unwrap_or_default();
+    let profile_prompt = format!(
+        r#"
+Maintain a profile of the human across all of their conversations with the AI. Update the current profile with any lasting facts, preferences and goals of the human found in new lines of one of their conversations, returning the new profile. Keep everything in the current profile that the new lines don't contradict. If the new lines have nothing worth remembering just return the current profile
+
+Current profile:
+{prev_profile}
+New lines of conversation:
+{new_lines}
+New profile:
+"#
+    );
+
+    let response = openai_client
+        .create_chat_completion(&model, &profile_prompt)
+        .await?;
+
+    let completion = response
+        .choices
+        .first()
+        .ok_or("No completion found")?
+        .message
+        .content
+        .clone();
+
+    let usage = response.usage.ok_or("No Usage found")?;
+    let tokens_used = usage.total_tokens;
+
+    Ok((completion, tokens_used))
+}
+
+/// Cuts the JSON object out of a completion, models like to wrap it in a code block.
+pub fn extract_json(completion: &str) -> &str {
+    let start = completion.find('{').unwrap_or(0);
+    let end = completion
+        .rfind('}')
+        .map(|end| end + 1)
+        .unwrap_or(completion.len());
+    &completion[start..end.max(start)]
+}
+
 pub async fn handle_compaction(
-    session_id: String,
+    session: SessionKeys,
     model: String,
     window_size: i64,
     openai_client: &AnyOpenAIClient,
     mut redis_conn: redis::aio::ConnectionManager,
-) -> Result<(), MotorheadError> {
+) -> Result<Vec<MemoryMessage>, MotorheadError> {
     let half = window_size / 2;
-    let session_key = format!("session:{}", &*session_id);
-    let context_key = format!("context:{}", &*session_id);
+    let session_key = session.messages();
+    let context_key = session.context();
     let (messages, mut context): (Vec<String>, Option<String>) = redis::pipe()
         .cmd("LRANGE")
         .arg(session_key.clone())
@@ -81,7 +129,17 @@ pub async fn handle_compaction(
     let mut temp_messages = Vec::new();
     let mut total_tokens_temp = 0;
 
-    for message in messages {
+    let messages: Vec<MemoryMessage> = messages
+        .iter()
+        .filter_map(|entry| MemoryMessage::from_redis_entry(entry))
+        .collect();
+    let summarized_ids: Vec<String> = messages
+        .iter()
+        .filter(|message| !message.is_legacy())
+        .map(|message| message.id.clone())
+        .collect();
+
+    for message in messages.iter().map(MemoryMessage::to_prompt_line) {
         let bpe = p50k_base().unwrap();
         let message_tokens = bpe.encode_with_special_tokens(&message);
         let message_tokens_used = message_tokens.len();
@@ -114,23 +172,37 @@ pub async fn handle_compaction(
     }
 
     if let Some(new_context) = context {
-        let token_count_key = format!("tokens:{}", &*session_id);
-        let redis_pipe_response_result: Result<((), (), i64), redis::RedisError> = redis::pipe()
-            .cmd("LTRIM")
+        let token_count_key = session.tokens();
+        let summarized_key = session.summarized();
+        let mut pipe = redis::pipe();
+        pipe.cmd("LTRIM")
             .arg(session_key)
             .arg(0)
             .arg(half)
+            .ignore()
             .cmd("SET")
             .arg(context_key)
             .arg(new_context)
+            .ignore()
             .cmd("INCRBY")
             .arg(token_count_key)
             .arg(total_tokens)
-            .query_async(&mut redis_conn)
-            .await;
+            .ignore();
+
+        // Remember which messages were folded into the summary, so edits to them
+        // can flag the summary as stale.
+        if !summarized_ids.is_empty() {
+            pipe.cmd("SADD")
+                .arg(summarized_key)
+                .arg(summarized_ids)
+                .ignore();
+        }
+
+        let redis_pipe_response_result: Result<(), redis::RedisError> =
+            pipe.query_async(&mut redis_conn).await;
 
         match redis_pipe_response_result {
-            Ok(_) => Ok(()),
+            Ok(_) => Ok(messages),
             Err(e) => {
                 log::error!("Error executing the redis pipeline: {:?}", e);
                 Err(MotorheadError::RedisError(e))
@@ -1,5 +1,7 @@
-use crate::long_term_memory::search_messages;
-use crate::models::{AppState, SearchPayload};
+use crate::facts::search_facts;
+use crate::long_term_memory::{search_messages, search_namespace_messages};
+use crate::models::{AppState, NamespaceQuery, NamespaceSearchPayload, SearchPayload};
+use crate::sessions::{check_namespace, SessionKeys};
 use actix_web::{error, post, web, HttpResponse, Responder};
 use std::ops::Deref;
 use std::sync::Arc;
@@ -10,7 +12,12 @@ pub async fn run_retrieval(
     web::Json(payload): web::Json<SearchPayload>,
     data: web::Data<Arc<AppState>>,
     redis: web::Data<redis::Client>,
+    web::Query(namespace_query): web::Query<NamespaceQuery>,
 ) -> actix_web::Result<impl Responder> {
+    let session = SessionKeys::parse(namespace_query.namespace.as_deref(), &session_id)
+        .map_err(error::ErrorBadRequest)?;
+    payload.check().map_err(error::ErrorBadRequest)?;
+
     if !data.long_term_memory {
         return Ok(HttpResponse::BadRequest().body("Long term memory is disabled"));
     }
@@ -23,7 +30,7 @@ pub async fn run_retrieval(
     let client_wrapper = data.openai_pool.get().await.unwrap();
     let openai_client = client_wrapper.deref();
 
-    match search_messages(payload.text, session_id.clone(), openai_client, conn).await {
+    match search_messages(&payload, &session, &data, openai_client, conn).await {
         Ok(results) => Ok(HttpResponse::Ok().json(results)),
         Err(e) => {
             log::error!("Error Retrieval API: {:?}", e);
@@ -31,3 +38,76 @@ pub async fn run_retrieval(
         }
     }
 }
+
+#[post("/retrieval")]
+pub async fn run_namespace_retrieval(
+    web::Json(payload): web::Json<NamespaceSearchPayload>,
+    data: web::Data<Arc<AppState>>,
+    redis: web::Data<redis::Client>,
+    web::Query(namespace_query): web::Query<NamespaceQuery>,
+) -> actix_web::Result<impl Responder> {
+    let namespace = namespace_query.namespace.as_deref();
+    check_namespace(namespace).map_err(error::ErrorBadRequest)?;
+    payload.check().map_err(error::ErrorBadRequest)?;
+
+    if !data.long_term_memory {
+        return Ok(HttpResponse::BadRequest().body("Long term memory is disabled"));
+    }
+
+    let conn = redis
+        .get_tokio_connection_manager()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let client_wrapper = data
+        .openai_pool
+        .get()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    let openai_client = client_wrapper.deref();
+
+    match search_namespace_messages(&payload, namespace, &data, openai_client, conn).await {
+        Ok(results) => Ok(HttpResponse::Ok().json(results)),
+        Err(e) => {
+            log::error!("Error Namespace Retrieval API: {:?}", e);
+            Ok(HttpResponse::InternalServerError().body("Internal server error"))
+        }
+    }
+}
+
+#[post("/sessions/{session_id}/facts/retrieval")]
+pub async fn run_fact_retrieval(
+    session_id: web::Path<String>,
+    web::Json(payload): web::Json<SearchPayload>,
+    data: web::Data<Arc<AppState>>,
+    redis: web::Data<redis::Client>,
+    web::Query(namespace_query): web::Query<NamespaceQuery>,
+) -> actix_web::Result<impl Responder> {
+    let session = SessionKeys::parse(namespace_query.namespace.as_deref(), &session_id)
+        .map_err(error::ErrorBadRequest)?;
+    payload.check().map_err(error::ErrorBadRequest)?;
+
+    if !data.fact_extraction {
+        return Ok(HttpResponse::BadRequest().body("Fact extraction is disabled"));
+    }
+
+    let conn = redis
+        .get_tokio_connection_manager()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let client_wrapper = data
+        .openai_pool
+        .get()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    let openai_client = client_wrapper.deref();
+
+    match search_facts(&payload, &session, &data.embeddings, openai_client, conn).await {
+        Ok(results) => Ok(HttpResponse::Ok().json(results)),
+        Err(e) => {
+            log::error!("Error Fact Retrieval API: {:?}", e);
+            Ok(HttpResponse::InternalServerError().body("Internal server error"))
+        }
+    }
+}
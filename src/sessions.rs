@@ -0,0 +1,1340 @@
+use crate::facts::{move_session_facts, rehome_session_facts};
+use crate::graph::{move_session_graph, rehome_session_graph};
+use crate::history::{copy_history, read_history};
+use crate::idempotency::move_idempotency_keys;
+use crate::long_term_memory::{copy_session_vectors, move_session_vectors, retag_session_vectors};
+use crate::memory::spawn_compaction;
+use crate::models::{
+    AckResponse, AppState, ForkRequest, GetSessionsQuery, MemoryMessage, MergeRequest,
+    MotorheadError, NamespaceQuery, RenameRequest, SessionMetadata, SessionTransferResponse,
+    SessionUpdate, SortOrder,
+};
+use crate::redis_lock::RedisLock;
+use crate::reducer::incremental_summarization;
+use actix_web::{error, get, post, put, web, HttpResponse, Responder};
+use nanoid::nanoid;
+use redis::aio::ConnectionManager;
+use redis::RedisResult;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+use std::sync::Arc;
+use tiktoken_rs::p50k_base_singleton;
+
+/// Qualifies a key with a namespace. Keys of namespaced sessions look like
+/// `{namespace}/session:{id}`, and since namespaces can't contain `:` they never
+/// clash with the keys of sessions outside any namespace.
+pub fn namespaced_key(namespace: Option<&str>, key: &str) -> String {
+    match namespace {
+        Some(namespace) => format!("{}/{}", namespace, key),
+        None => key.to_string(),
+    }
+}
+
+pub fn check_session_id(session_id: &str) -> Result<(), MotorheadError> {
+    if session_id.is_empty() {
+        return Err(MotorheadError::InvalidRequest(String::from(
+            "Invalid session id",
+        )));
+    }
+
+    Ok(())
+}
+
+pub fn check_namespace(namespace: Option<&str>) -> Result<(), MotorheadError> {
+    match namespace {
+        Some(namespace) if namespace.is_empty() || namespace.contains(':') => Err(
+            MotorheadError::InvalidRequest(format!("Invalid namespace: {:?}", namespace)),
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// A session ID together with the namespace it lives in, every key the session
+/// owns is derived from it.
+#[derive(Clone, Debug)]
+pub struct SessionKeys {
+    pub namespace: Option<String>,
+    pub id: String,
+}
+
+impl SessionKeys {
+    pub fn new(namespace: Option<&str>, id: &str) -> Self {
+        SessionKeys {
+            namespace: namespace.map(String::from),
+            id: id.to_string(),
+        }
+    }
+
+    /// Like `new`, for namespaces that come from a request.
+    pub fn parse(namespace: Option<&str>, id: &str) -> Result<Self, MotorheadError> {
+        check_namespace(namespace)?;
+        Ok(Self::new(namespace, id))
+    }
+
+    fn key(&self, prefix: &str) -> String {
+        namespaced_key(
+            self.namespace.as_deref(),
+            &format!("{}:{}", prefix, self.id),
+        )
+    }
+
+    /// Identifies the session across namespaces, for in-process tasks.
+    pub fn scoped_id(&self) -> String {
+        self.key("session")
+    }
+
+    pub fn messages(&self) -> String {
+        self.key("session")
+    }
+
+    pub fn context(&self) -> String {
+        self.key("context")
+    }
+
+    pub fn tokens(&self) -> String {
+        self.key("tokens")
+    }
+
+    pub fn summarized(&self) -> String {
+        self.key("summarized")
+    }
+
+    pub fn context_stale(&self) -> String {
+        self.key("context_stale")
+    }
+
+    pub fn client_ids(&self) -> String {
+        self.key("client_ids")
+    }
+
+    pub fn meta(&self) -> String {
+        self.key("session_meta")
+    }
+
+    pub fn history(&self) -> String {
+        self.key("history")
+    }
+
+    pub fn message_positions(&self) -> String {
+        self.key("message_positions")
+    }
+
+    /// Serializes writes to the session's keys across instances, held by
+    /// `post_memory` and by operations that move a session's data around.
+    pub fn lock(&self) -> String {
+        self.key("session_lock")
+    }
+
+    pub fn idempotency(&self, key: &str) -> String {
+        format!("{}:{}", self.key("idempotency"), key)
+    }
+
+    pub fn idempotency_keys(&self) -> String {
+        self.key("idempotency_keys")
+    }
+
+    pub fn sessions(&self) -> String {
+        sessions_key(self.namespace.as_deref())
+    }
+
+    pub fn expiry(&self) -> String {
+        session_expiry_key(self.namespace.as_deref())
+    }
+
+    // Every per-session key, besides the sorted sets the session is a member of
+    pub fn all(&self) -> Vec<String> {
+        vec![
+            self.meta(),
+            self.context(),
+            self.messages(),
+            self.tokens(),
+            self.summarized(),
+            self.context_stale(),
+            self.client_ids(),
+            self.history(),
+            self.message_positions(),
+            self.idempotency_keys(),
+        ]
+    }
+}
+
+pub fn user_sessions_key(namespace: Option<&str>, user_id: &str) -> String {
+    namespaced_key(namespace, &format!("user_sessions:{}", user_id))
+}
+
+pub fn tag_sessions_key(namespace: Option<&str>, tag: &str) -> String {
+    namespaced_key(namespace, &format!("tag_sessions:{}", tag))
+}
+
+// Sessions are also kept in per user and per tag sorted sets, scored by last
+// activity like the `sessions` set, so listings can be filtered on them.
+fn filter_keys(namespace: Option<&str>, user_id: Option<&str>, tags: &[String]) -> Vec<String> {
+    user_id
+        .map(|user_id| user_sessions_key(namespace, user_id))
+        .into_iter()
+        .chain(tags.iter().map(|tag| tag_sessions_key(namespace, tag)))
+        .collect()
+}
+
+async fn get_filter_keys(
+    session: &SessionKeys,
+    conn: &mut ConnectionManager,
+) -> RedisResult<Vec<String>> {
+    let (user_id, tags): (Option<String>, Option<String>) = redis::cmd("HMGET")
+        .arg(session.meta())
+        .arg("user_id")
+        .arg("tags")
+        .query_async(conn)
+        .await?;
+    let tags: Vec<String> = tags
+        .and_then(|tags| serde_json::from_str(&tags).ok())
+        .unwrap_or_default();
+
+    Ok(filter_keys(
+        session.namespace.as_deref(),
+        user_id.as_deref(),
+        &tags,
+    ))
+}
+
+pub async fn unindex_session(
+    session: &SessionKeys,
+    conn: &mut ConnectionManager,
+) -> RedisResult<()> {
+    let keys = get_filter_keys(session, conn).await?;
+
+    let mut pipe = redis::pipe();
+    for key in keys {
+        pipe.cmd("ZREM").arg(key).arg(&session.id).ignore();
+    }
+
+    pipe.query_async(conn).await
+}
+
+pub fn sessions_key(namespace: Option<&str>) -> String {
+    match namespace {
+        Some(namespace) => format!("sessions:{}", namespace),
+        None => String::from("sessions"),
+    }
+}
+
+// Expiring sessions are tracked in a sorted set per namespace, scored by their
+// expiry time
+pub fn session_expiry_key(namespace: Option<&str>) -> String {
+    namespaced_key(namespace, "session_expiry")
+}
+
+// Every namespace that was ever written to, so the reaper can find their expiry sets
+pub const NAMESPACES_KEY: &str = "namespaces";
+
+/// Recomputes when a session expires from its last activity and its own `ttl`,
+/// falling back to `default_ttl`. A `ttl` of 0 means the session never expires.
+pub async fn refresh_expiry(
+    session: &SessionKeys,
+    last_active: i64,
+    default_ttl: Option<i64>,
+    conn: &mut ConnectionManager,
+) -> RedisResult<()> {
+    let key = session.meta();
+    let ttl: Option<i64> = redis::Cmd::hget(&key, "ttl").query_async(conn).await?;
+
+    let mut pipe = redis::pipe();
+    match ttl.or(default_ttl).filter(|ttl| *ttl > 0) {
+        Some(ttl) => {
+            let expires_at = last_active + ttl;
+            pipe.cmd("HSET")
+                .arg(&key)
+                .arg("expires_at")
+                .arg(expires_at)
+                .ignore()
+                .cmd("ZADD")
+                .arg(session.expiry())
+                .arg(expires_at)
+                .arg(&session.id)
+                .ignore();
+        }
+        None => {
+            pipe.cmd("HDEL")
+                .arg(&key)
+                .arg("expires_at")
+                .ignore()
+                .cmd("ZREM")
+                .arg(session.expiry())
+                .arg(&session.id)
+                .ignore();
+        }
+    }
+
+    pipe.query_async(conn).await
+}
+
+// Sorted sets the session is a member of, other than the namespace's `sessions` set
+async fn membership_keys(
+    session: &SessionKeys,
+    conn: &mut ConnectionManager,
+) -> RedisResult<Vec<String>> {
+    let mut keys = get_filter_keys(session, conn).await?;
+    keys.push(session.expiry());
+    Ok(keys)
+}
+
+/// Removes everything stored for a session except its long term memory vectors,
+/// returning how many short term messages and keys were removed.
+pub async fn delete_session(
+    session: &SessionKeys,
+    conn: &mut ConnectionManager,
+) -> RedisResult<(i64, i64)> {
+    unindex_session(session, conn).await?;
+
+    redis::pipe()
+        .atomic()
+        .cmd("ZREM")
+        .arg(session.sessions())
+        .arg(&session.id)
+        .ignore()
+        .cmd("ZREM")
+        .arg(session.expiry())
+        .arg(&session.id)
+        .ignore()
+        .cmd("LLEN")
+        .arg(session.messages())
+        .cmd("DEL")
+        .arg(session.all())
+        .query_async(conn)
+        .await
+}
+
+/// Tokens the messages add to a session's `total_tokens`.
+pub fn count_tokens(messages: &[MemoryMessage]) -> i64 {
+    let bpe = p50k_base_singleton();
+    let bpe = bpe.lock();
+    messages
+        .iter()
+        .map(|message| {
+            bpe.encode_with_special_tokens(&message.to_prompt_line())
+                .len() as i64
+        })
+        .sum()
+}
+
+pub async fn record_activity(
+    session: &SessionKeys,
+    messages: &[MemoryMessage],
+    now: i64,
+    default_ttl: Option<i64>,
+    conn: &mut ConnectionManager,
+) -> RedisResult<()> {
+    let total_tokens = count_tokens(messages);
+
+    let key = session.meta();
+    let mut pipe = redis::pipe();
+    pipe.cmd("HSETNX")
+        .arg(&key)
+        .arg("created_at")
+        .arg(now)
+        .ignore()
+        .cmd("HSET")
+        .arg(&key)
+        .arg("last_active")
+        .arg(now)
+        .ignore()
+        .cmd("HINCRBY")
+        .arg(&key)
+        .arg("message_count")
+        .arg(messages.len())
+        .ignore()
+        .cmd("HINCRBY")
+        .arg(&key)
+        .arg("total_tokens")
+        .arg(total_tokens)
+        .ignore();
+
+    if let Some(namespace) = &session.namespace {
+        pipe.cmd("HSET")
+            .arg(&key)
+            .arg("namespace")
+            .arg(namespace)
+            .ignore()
+            .cmd("SADD")
+            .arg(NAMESPACES_KEY)
+            .arg(namespace)
+            .ignore();
+    }
+
+    pipe.query_async::<_, ()>(conn).await?;
+
+    refresh_expiry(session, now, default_ttl, conn).await?;
+
+    let keys = get_filter_keys(session, conn).await?;
+    if keys.is_empty() {
+        return Ok(());
+    }
+
+    let mut pipe = redis::pipe();
+    for key in keys {
+        pipe.cmd("ZADD").arg(key).arg(now).arg(&session.id).ignore();
+    }
+
+    pipe.query_async(conn).await
+}
+
+pub struct SessionPage {
+    pub session_ids: Vec<String>,
+    pub next_cursor: Option<String>,
+    pub total: i64,
+}
+
+// A cursor is the score the next page starts at, plus how many sessions with
+// exactly that score were already returned.
+fn parse_cursor(cursor: &str) -> Option<(i64, usize)> {
+    let (score, skip) = cursor.split_once(':')?;
+    Some((score.parse().ok()?, skip.parse().ok()?))
+}
+
+pub async fn list_sessions(
+    query: &GetSessionsQuery,
+    conn: &mut ConnectionManager,
+) -> Result<SessionPage, MotorheadError> {
+    let tags: Vec<String> = query
+        .tags
+        .iter()
+        .flat_map(|tags| tags.split(','))
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    let namespace = query.namespace.as_deref();
+    let filter_keys = filter_keys(namespace, query.user_id.as_deref(), &tags);
+    let sessions_key = sessions_key(namespace);
+
+    let (source_key, temp_key) = if filter_keys.is_empty() {
+        (sessions_key, None)
+    } else {
+        let temp_key = format!("sessions_query:{}", nanoid!());
+        // Only keep the namespace set's scores
+        let weights: Vec<u8> = std::iter::once(1)
+            .chain(filter_keys.iter().map(|_| 0))
+            .collect();
+
+        redis::pipe()
+            .cmd("ZINTERSTORE")
+            .arg(&temp_key)
+            .arg(filter_keys.len() + 1)
+            .arg(&sessions_key)
+            .arg(&filter_keys)
+            .arg("WEIGHTS")
+            .arg(weights)
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(&temp_key)
+            .arg(60)
+            .ignore()
+            .query_async::<_, ()>(conn)
+            .await?;
+
+        (temp_key.clone(), Some(temp_key))
+    };
+
+    let (bound, skip) = match &query.cursor {
+        Some(cursor) => {
+            let (score, skip) = parse_cursor(cursor)
+                .ok_or_else(|| MotorheadError::InvalidRequest(String::from("Invalid cursor")))?;
+            (Some(score), skip)
+        }
+        None => {
+            let bound = match query.order {
+                SortOrder::Asc => query.active_after,
+                SortOrder::Desc => query.active_before,
+            };
+            (bound, (query.page - 1) * query.size)
+        }
+    };
+
+    let min = query
+        .active_after
+        .map(|score| score.to_string())
+        .unwrap_or_else(|| String::from("-inf"));
+    let max = query
+        .active_before
+        .map(|score| score.to_string())
+        .unwrap_or_else(|| String::from("+inf"));
+    let bound_str = bound.map(|score| score.to_string());
+
+    let mut range = redis::cmd("ZRANGE");
+    range.arg(&source_key);
+    match query.order {
+        SortOrder::Asc => range.arg(bound_str.as_ref().unwrap_or(&min)).arg(&max),
+        SortOrder::Desc => range
+            .arg(bound_str.as_ref().unwrap_or(&max))
+            .arg(&min)
+            .arg("REV"),
+    };
+    range
+        .arg("BYSCORE")
+        .arg("LIMIT")
+        .arg(skip)
+        .arg(query.size + 1)
+        .arg("WITHSCORES");
+
+    let (mut sessions, total): (Vec<(String, i64)>, i64) = redis::pipe()
+        .add_command(range)
+        .cmd("ZCOUNT")
+        .arg(&source_key)
+        .arg(&min)
+        .arg(&max)
+        .query_async(conn)
+        .await?;
+
+    if let Some(temp_key) = temp_key {
+        redis::Cmd::del(temp_key).query_async::<_, ()>(conn).await?;
+    }
+
+    let mut next_cursor = None;
+    if sessions.len() > query.size {
+        sessions.truncate(query.size);
+        let last_score = sessions[sessions.len() - 1].1;
+        let same_score = sessions
+            .iter()
+            .filter(|(_, score)| *score == last_score)
+            .count();
+        let next_skip = if bound == Some(last_score) {
+            skip + same_score
+        } else {
+            same_score
+        };
+        next_cursor = Some(format!("{}:{}", last_score, next_skip));
+    }
+
+    Ok(SessionPage {
+        session_ids: sessions.into_iter().map(|(id, _)| id).collect(),
+        next_cursor,
+        total,
+    })
+}
+
+pub async fn get_sessions_metadata(
+    namespace: Option<&str>,
+    session_ids: &[String],
+    conn: &mut ConnectionManager,
+) -> RedisResult<Vec<SessionMetadata>> {
+    if session_ids.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut pipe = redis::pipe();
+    for session_id in session_ids {
+        pipe.cmd("HGETALL")
+            .arg(SessionKeys::new(namespace, session_id).meta());
+    }
+
+    let hashes: Vec<HashMap<String, String>> = pipe.query_async(conn).await?;
+
+    Ok(session_ids
+        .iter()
+        .zip(hashes)
+        .map(|(session_id, hash)| SessionMetadata::from_hash(session_id, hash))
+        .collect())
+}
+
+// Sessions created before metadata was tracked only have a message list, and
+// posting only a context doesn't track any
+async fn session_exists(session: &SessionKeys, conn: &mut ConnectionManager) -> RedisResult<bool> {
+    let existing: usize = redis::cmd("EXISTS")
+        .arg(session.meta())
+        .arg(session.messages())
+        .arg(session.context())
+        .query_async(conn)
+        .await?;
+
+    Ok(existing > 0)
+}
+
+#[get("/sessions/{session_id}")]
+pub async fn get_session(
+    session_id: web::Path<String>,
+    redis: web::Data<redis::Client>,
+    web::Query(namespace_query): web::Query<NamespaceQuery>,
+) -> actix_web::Result<impl Responder> {
+    let session = SessionKeys::parse(namespace_query.namespace.as_deref(), &session_id)
+        .map_err(error::ErrorBadRequest)?;
+
+    let mut conn = redis
+        .get_tokio_connection_manager()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let hash: HashMap<String, String> = redis::Cmd::hgetall(session.meta())
+        .query_async(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    if hash.is_empty()
+        && !session_exists(&session, &mut conn)
+            .await
+            .map_err(error::ErrorInternalServerError)?
+    {
+        return Err(error::ErrorNotFound("Session not found"));
+    }
+
+    let response = SessionMetadata::from_hash(&session_id, hash);
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(response))
+}
+
+#[put("/sessions/{session_id}")]
+pub async fn put_session(
+    session_id: web::Path<String>,
+    web::Json(update): web::Json<SessionUpdate>,
+    data: web::Data<Arc<AppState>>,
+    redis: web::Data<redis::Client>,
+    web::Query(namespace_query): web::Query<NamespaceQuery>,
+) -> actix_web::Result<impl Responder> {
+    let session = SessionKeys::parse(namespace_query.namespace.as_deref(), &session_id)
+        .map_err(error::ErrorBadRequest)?;
+    update.check().map_err(error::ErrorBadRequest)?;
+
+    let mut conn = redis
+        .get_tokio_connection_manager()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    // Sessions are created by posting messages, not by setting their metadata
+    if !session_exists(&session, &mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+    {
+        return Err(error::ErrorNotFound("Session not found"));
+    }
+
+    let old_filter_keys = get_filter_keys(&session, &mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let key = session.meta();
+    let old_user_id: Option<String> = redis::Cmd::hget(&key, "user_id")
+        .query_async(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    let user_changed = update.user_id.is_some() && update.user_id != old_user_id;
+
+    let mut pipe = redis::pipe();
+    pipe.cmd("HSETNX")
+        .arg(&key)
+        .arg("created_at")
+        .arg(chrono::Utc::now().timestamp())
+        .ignore();
+
+    if let Some(user_id) = &update.user_id {
+        pipe.cmd("HSET")
+            .arg(&key)
+            .arg("user_id")
+            .arg(user_id)
+            .ignore();
+    }
+
+    if let Some(title) = update.title {
+        pipe.cmd("HSET").arg(&key).arg("title").arg(title).ignore();
+    }
+
+    if let Some(tags) = update.tags {
+        let tags = serde_json::to_string(&tags).map_err(error::ErrorInternalServerError)?;
+        pipe.cmd("HSET").arg(&key).arg("tags").arg(tags).ignore();
+    }
+
+    if let Some(ttl) = update.ttl {
+        pipe.cmd("HSET").arg(&key).arg("ttl").arg(ttl).ignore();
+    }
+
+    if let Some(metadata) = update.metadata {
+        pipe.cmd("HSET")
+            .arg(&key)
+            .arg("metadata")
+            .arg(metadata.to_string())
+            .ignore();
+    }
+
+    pipe.query_async::<_, ()>(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let new_filter_keys = get_filter_keys(&session, &mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    let (last_active, created_at): (Option<i64>, i64) = redis::cmd("HMGET")
+        .arg(&key)
+        .arg("last_active")
+        .arg("created_at")
+        .query_async(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let mut pipe = redis::pipe();
+    for filter_key in old_filter_keys
+        .iter()
+        .filter(|filter_key| !new_filter_keys.contains(filter_key))
+    {
+        pipe.cmd("ZREM").arg(filter_key).arg(&*session_id).ignore();
+    }
+    for filter_key in &new_filter_keys {
+        pipe.cmd("ZADD")
+            .arg(filter_key)
+            .arg(last_active.unwrap_or(created_at))
+            .arg(&*session_id)
+            .ignore();
+    }
+    pipe.query_async::<_, ()>(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    refresh_expiry(
+        &session,
+        last_active.unwrap_or(created_at),
+        data.session_ttl,
+        &mut conn,
+    )
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+
+    if let (true, Some(user_id)) = (user_changed, &update.user_id) {
+        // What was learned from the session belongs to its new user
+        if data.fact_extraction {
+            rehome_session_facts(&session, user_id, conn.clone())
+                .await
+                .map_err(error::ErrorInternalServerError)?;
+        }
+
+        if data.knowledge_graph {
+            rehome_session_graph(&session, old_user_id.as_deref(), user_id, conn.clone())
+                .await
+                .map_err(error::ErrorInternalServerError)?;
+        }
+
+        if data.long_term_memory {
+            retag_session_vectors(&session, conn)
+                .await
+                .map_err(error::ErrorInternalServerError)?;
+        }
+    }
+
+    let response = AckResponse { status: "Ok" };
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(response))
+}
+
+// Gives the messages new IDs, the same one for each old ID across calls. Legacy
+// messages have no ID to go by and stay as they are.
+fn remap_message_ids<'a>(
+    messages: impl IntoIterator<Item = &'a mut MemoryMessage>,
+    message_ids: &mut HashMap<String, String>,
+) {
+    for message in messages {
+        if message.is_legacy() {
+            continue;
+        }
+
+        let new_id = message_ids
+            .entry(message.id.clone())
+            .or_insert_with(|| nanoid!());
+        message.id = new_id.clone();
+    }
+}
+
+#[post("/sessions/{session_id}/fork")]
+pub async fn fork_session(
+    session_id: web::Path<String>,
+    web::Json(fork): web::Json<ForkRequest>,
+    data: web::Data<Arc<AppState>>,
+    redis: web::Data<redis::Client>,
+    web::Query(namespace_query): web::Query<NamespaceQuery>,
+) -> actix_web::Result<impl Responder> {
+    let namespace = namespace_query.namespace.as_deref();
+    let session = SessionKeys::parse(namespace, &session_id).map_err(error::ErrorBadRequest)?;
+    let fork_id = fork.session_id.clone().unwrap_or_else(|| nanoid!());
+    let fork_session = SessionKeys::new(namespace, &fork_id);
+
+    let mut conn = redis
+        .get_tokio_connection_manager()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let (exists, meta_exists): (bool, bool) = redis::pipe()
+        .cmd("EXISTS")
+        .arg(fork_session.messages())
+        .cmd("EXISTS")
+        .arg(fork_session.meta())
+        .query_async(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    if exists || meta_exists {
+        return Err(error::ErrorConflict("Session already exists"));
+    }
+
+    let (entries, context, tokens, parent): (
+        Vec<String>,
+        Option<String>,
+        Option<i64>,
+        HashMap<String, String>,
+    ) = redis::pipe()
+        .cmd("LRANGE")
+        .arg(session.messages())
+        .arg(0)
+        .arg(-1)
+        .cmd("GET")
+        .arg(session.context())
+        .cmd("GET")
+        .arg(session.tokens())
+        .cmd("HGETALL")
+        .arg(session.meta())
+        .query_async(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    if entries.is_empty() && context.is_none() && parent.is_empty() {
+        return Err(error::ErrorNotFound("Session not found"));
+    }
+
+    let mut messages: Vec<MemoryMessage> = entries
+        .iter()
+        .filter_map(|entry| MemoryMessage::from_redis_entry(entry))
+        .collect();
+
+    // The list is newest first, so everything from the fork point on is older
+    let mut excluded_ids = HashSet::new();
+    if let Some(message_id) = &fork.message_id {
+        let position = messages
+            .iter()
+            .position(|message| !message.is_legacy() && &message.id == message_id)
+            .ok_or_else(|| {
+                error::ErrorBadRequest("Message is not in the session's short term memory")
+            })?;
+        excluded_ids = messages
+            .drain(..position)
+            .filter(|message| !message.is_legacy())
+            .map(|message| message.id)
+            .collect();
+    }
+
+    // Messages get new IDs, vector docs are keyed by them
+    let mut message_ids = HashMap::new();
+    remap_message_ids(&mut messages, &mut message_ids);
+
+    // Compacted messages are only left in the history, and get new IDs as well
+    let mut history = read_history(&session, &mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    history.retain(|(_, message)| message.is_legacy() || !excluded_ids.contains(&message.id));
+    remap_message_ids(
+        history.iter_mut().map(|(_, message)| message),
+        &mut message_ids,
+    );
+
+    let now = chrono::Utc::now().timestamp();
+
+    let mut pipe = redis::pipe();
+    if !messages.is_empty() {
+        let fork_entries: Vec<String> =
+            messages.iter().map(MemoryMessage::to_redis_entry).collect();
+        pipe.cmd("RPUSH")
+            .arg(fork_session.messages())
+            .arg(fork_entries)
+            .ignore();
+    }
+    if let Some(context) = context {
+        pipe.cmd("SET")
+            .arg(fork_session.context())
+            .arg(context)
+            .ignore();
+    }
+    if let Some(tokens) = tokens {
+        pipe.cmd("SET")
+            .arg(fork_session.tokens())
+            .arg(tokens)
+            .ignore();
+    }
+    copy_history(&mut pipe, &fork_session, &history, 0);
+
+    let fork_meta_key = fork_session.meta();
+    for field in ["user_id", "title", "tags", "metadata", "ttl", "sequence"] {
+        if let Some(value) = parent.get(field) {
+            pipe.cmd("HSET")
+                .arg(&fork_meta_key)
+                .arg(field)
+                .arg(value)
+                .ignore();
+        }
+    }
+    pipe.cmd("HSET")
+        .arg(&fork_meta_key)
+        .arg("parent_id")
+        .arg(&*session_id)
+        .ignore();
+    if let Some(message_id) = &fork.message_id {
+        pipe.cmd("HSET")
+            .arg(&fork_meta_key)
+            .arg("forked_from_message_id")
+            .arg(message_id)
+            .ignore();
+    }
+    pipe.cmd("ZADD")
+        .arg(fork_session.sessions())
+        .arg(now)
+        .arg(&fork_session.id)
+        .ignore();
+
+    pipe.query_async::<_, ()>(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    record_activity(&fork_session, &messages, now, data.session_ttl, &mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let vectors = if fork.include_vectors && data.long_term_memory {
+        copy_session_vectors(&session, &fork_session, &message_ids, &excluded_ids, conn)
+            .await
+            .map_err(error::ErrorInternalServerError)?
+    } else {
+        0
+    };
+
+    let response = SessionTransferResponse {
+        session_id: fork_session.id,
+        messages: Some(messages.len()),
+        vectors,
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(response))
+}
+
+#[post("/sessions/{session_id}/rename")]
+pub async fn rename_session(
+    session_id: web::Path<String>,
+    web::Json(rename): web::Json<RenameRequest>,
+    data: web::Data<Arc<AppState>>,
+    redis: web::Data<redis::Client>,
+    web::Query(namespace_query): web::Query<NamespaceQuery>,
+) -> actix_web::Result<impl Responder> {
+    let namespace = namespace_query.namespace.as_deref();
+    let session = SessionKeys::parse(namespace, &session_id).map_err(error::ErrorBadRequest)?;
+    check_session_id(&rename.session_id).map_err(error::ErrorBadRequest)?;
+    let renamed = SessionKeys::new(namespace, &rename.session_id);
+    if renamed.id == session.id {
+        return Err(error::ErrorBadRequest("Session already has that id"));
+    }
+
+    let mut conn = redis
+        .get_tokio_connection_manager()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let _locks = RedisLock::acquire_both(session.lock(), renamed.lock(), &conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    // A running compaction would write to the old keys
+    data.cancel_tasks(&session.scoped_id()).await;
+
+    let mut pipe = redis::pipe();
+    for key in session.all().into_iter().chain(renamed.all()) {
+        pipe.cmd("EXISTS").arg(key);
+    }
+    let exists: Vec<bool> = pipe
+        .query_async(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    let (source_exists, target_exists) = exists.split_at(exists.len() / 2);
+
+    if !source_exists.iter().any(|exists| *exists) {
+        return Err(error::ErrorNotFound("Session not found"));
+    }
+    if target_exists.iter().any(|exists| *exists) {
+        return Err(error::ErrorConflict("Session already exists"));
+    }
+
+    let mut sorted_set_keys = membership_keys(&session, &mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    sorted_set_keys.push(session.sessions());
+
+    let mut pipe = redis::pipe();
+    for key in &sorted_set_keys {
+        pipe.cmd("ZSCORE").arg(key).arg(&session.id);
+    }
+    let scores: Vec<Option<f64>> = pipe
+        .query_async(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    // Retries of requests sent before the rename are still recognized
+    move_idempotency_keys(&mut pipe, &session, &renamed, &mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    for ((old_key, new_key), _) in session
+        .all()
+        .into_iter()
+        .zip(renamed.all())
+        .zip(source_exists)
+        .filter(|(_, exists)| **exists)
+    {
+        pipe.cmd("RENAME").arg(old_key).arg(new_key).ignore();
+    }
+    for (key, score) in sorted_set_keys.iter().zip(scores) {
+        if let Some(score) = score {
+            pipe.cmd("ZREM").arg(key).arg(&session.id).ignore();
+            pipe.cmd("ZADD")
+                .arg(key)
+                .arg(score)
+                .arg(&renamed.id)
+                .ignore();
+        }
+    }
+    pipe.query_async::<_, ()>(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    // Messages still waiting to be indexed go to the new keys
+    data.indexer.redirect(&session, &renamed, 0).await;
+
+    let vectors = if data.long_term_memory {
+        move_session_vectors(&session, &renamed, 0, conn.clone())
+            .await
+            .map_err(error::ErrorInternalServerError)?
+    } else {
+        0
+    };
+
+    if data.fact_extraction {
+        move_session_facts(&session, &renamed, conn.clone())
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+    }
+
+    if data.knowledge_graph {
+        let user_id: Option<String> = redis::Cmd::hget(renamed.meta(), "user_id")
+            .query_async(&mut conn)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        move_session_graph(&session, &renamed, user_id.as_deref(), conn)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+    }
+
+    let response = SessionTransferResponse {
+        session_id: renamed.id,
+        messages: None,
+        vectors,
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(response))
+}
+
+#[post("/sessions/{session_id}/merge")]
+pub async fn merge_session(
+    session_id: web::Path<String>,
+    web::Json(merge): web::Json<MergeRequest>,
+    data: web::Data<Arc<AppState>>,
+    redis: web::Data<redis::Client>,
+    web::Query(namespace_query): web::Query<NamespaceQuery>,
+) -> actix_web::Result<impl Responder> {
+    let namespace = namespace_query.namespace.as_deref();
+    let session = SessionKeys::parse(namespace, &session_id).map_err(error::ErrorBadRequest)?;
+    check_session_id(&merge.source_session_id).map_err(error::ErrorBadRequest)?;
+    let source = SessionKeys::new(namespace, &merge.source_session_id);
+    if source.id == session.id {
+        return Err(error::ErrorBadRequest("Can't merge a session into itself"));
+    }
+
+    let mut conn = redis
+        .get_tokio_connection_manager()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let _locks = RedisLock::acquire_both(session.lock(), source.lock(), &conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    data.cancel_tasks(&session.scoped_id()).await;
+    data.cancel_tasks(&source.scoped_id()).await;
+
+    let (source_entries, source_context, source_tokens): (
+        Vec<String>,
+        Option<String>,
+        Option<i64>,
+    ) = redis::pipe()
+        .cmd("LRANGE")
+        .arg(source.messages())
+        .arg(0)
+        .arg(-1)
+        .cmd("GET")
+        .arg(source.context())
+        .cmd("GET")
+        .arg(source.tokens())
+        .query_async(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    let source_meta: HashMap<String, String> = redis::Cmd::hgetall(source.meta())
+        .query_async(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    let (target_entries, target_context): (Vec<String>, Option<String>) = redis::pipe()
+        .cmd("LRANGE")
+        .arg(session.messages())
+        .arg(0)
+        .arg(-1)
+        .cmd("GET")
+        .arg(session.context())
+        .query_async(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    if source_entries.is_empty() && source_context.is_none() && source_meta.is_empty() {
+        return Err(error::ErrorNotFound("Session not found"));
+    }
+
+    // Both lists are newest first, interleave them by creation time
+    let mut entries: Vec<(i64, String)> = target_entries
+        .into_iter()
+        .chain(source_entries)
+        .map(|entry| {
+            let created_at = MemoryMessage::from_redis_entry(&entry)
+                .map(|message| message.created_at)
+                .unwrap_or(0);
+            (created_at, entry)
+        })
+        .collect();
+    entries.sort_by_key(|(created_at, _)| Reverse(*created_at));
+    let entries: Vec<String> = entries.into_iter().map(|(_, entry)| entry).collect();
+
+    let mut summary_tokens = source_tokens.unwrap_or(0);
+    let context = match (target_context, source_context) {
+        (Some(target_context), Some(source_context)) => {
+            let client_wrapper = data
+                .openai_pool
+                .get()
+                .await
+                .map_err(error::ErrorInternalServerError)?;
+            let (summary, tokens_used) = incremental_summarization(
+                data.model.to_string(),
+                client_wrapper.deref(),
+                Some(target_context),
+                vec![format!(
+                    "Summary of an earlier part of the conversation: {}",
+                    source_context
+                )],
+            )
+            .await
+            .map_err(|e| {
+                log::error!("Error merging summaries: {:?}", e);
+                error::ErrorInternalServerError("Internal server error")
+            })?;
+            summary_tokens += tokens_used as i64;
+            Some(summary)
+        }
+        (target_context, source_context) => target_context.or(source_context),
+    };
+
+    let target_meta_key = session.meta();
+    // The source's messages are numbered after the target's
+    let target_sequence: Option<i64> = redis::Cmd::hget(&target_meta_key, "sequence")
+        .query_async(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    let parse_i64 = |field: &str| {
+        source_meta
+            .get(field)
+            .and_then(|value| value.parse::<i64>().ok())
+            .unwrap_or(0)
+    };
+    let now = chrono::Utc::now().timestamp();
+    let source_history = read_history(&source, &mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let mut pipe = redis::pipe();
+    pipe.atomic().cmd("DEL").arg(session.messages()).ignore();
+    copy_history(
+        &mut pipe,
+        &session,
+        &source_history,
+        target_sequence.unwrap_or(0),
+    );
+    if !entries.is_empty() {
+        pipe.cmd("RPUSH")
+            .arg(session.messages())
+            .arg(&entries)
+            .ignore();
+    }
+    if let Some(context) = context {
+        pipe.cmd("SET").arg(session.context()).arg(context).ignore();
+    }
+    pipe.cmd("INCRBY")
+        .arg(session.tokens())
+        .arg(summary_tokens)
+        .ignore()
+        .cmd("SUNIONSTORE")
+        .arg(session.summarized())
+        .arg(session.summarized())
+        .arg(source.summarized())
+        .ignore()
+        .cmd("HSETNX")
+        .arg(&target_meta_key)
+        .arg("created_at")
+        .arg(now)
+        .ignore()
+        .cmd("HINCRBY")
+        .arg(&target_meta_key)
+        .arg("message_count")
+        .arg(parse_i64("message_count"))
+        .ignore()
+        .cmd("HINCRBY")
+        .arg(&target_meta_key)
+        .arg("total_tokens")
+        .arg(parse_i64("total_tokens"))
+        .ignore()
+        .cmd("HINCRBY")
+        .arg(&target_meta_key)
+        .arg("sequence")
+        .arg(parse_i64("sequence"))
+        .ignore()
+        .cmd("HSET")
+        .arg(&target_meta_key)
+        .arg("last_active")
+        .arg(now)
+        .ignore();
+    pipe.query_async::<_, ()>(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    // The source's messages still waiting to be indexed go to the target
+    data.indexer
+        .redirect(&source, &session, target_sequence.unwrap_or(0))
+        .await;
+
+    delete_session(&source, &mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    redis::cmd("ZADD")
+        .arg(session.sessions())
+        .arg(now)
+        .arg(&session.id)
+        .query_async::<_, ()>(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    record_activity(&session, &[], now, data.session_ttl, &mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let vectors = if data.long_term_memory {
+        move_session_vectors(
+            &source,
+            &session,
+            target_sequence.unwrap_or(0),
+            conn.clone(),
+        )
+        .await
+        .map_err(error::ErrorInternalServerError)?
+    } else {
+        0
+    };
+
+    if data.fact_extraction {
+        move_session_facts(&source, &session, conn.clone())
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+    }
+
+    if data.knowledge_graph {
+        let user_id = source_meta.get("user_id").map(String::as_str);
+        move_session_graph(&source, &session, user_id, conn.clone())
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+    }
+
+    if entries.len() as i64 > data.window_size {
+        spawn_compaction(&data, &session, conn).await;
+    }
+
+    let response = SessionTransferResponse {
+        session_id: session.id,
+        messages: Some(entries.len()),
+        vectors,
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forks_remap_ids_but_leave_legacy_messages() {
+        let message = |id: &str, content: &str| {
+            let mut message =
+                MemoryMessage::from_redis_entry(&format!("user: {}", content)).unwrap();
+            message.id = id.to_string();
+            message
+        };
+        let mut messages = vec![
+            message("", "one"),
+            message("", "two"),
+            message("m3", "three"),
+        ];
+        let mut history = vec![message("m3", "three"), message("m0", "zero")];
+        let mut message_ids = HashMap::new();
+
+        remap_message_ids(&mut messages, &mut message_ids);
+        remap_message_ids(&mut history, &mut message_ids);
+
+        assert!(messages[0].is_legacy() && messages[1].is_legacy());
+        assert!(!message_ids.contains_key(""));
+        assert_eq!(messages[2].id, message_ids["m3"]);
+        assert_eq!(history[0].id, messages[2].id);
+        assert_eq!(history[1].id, message_ids["m0"]);
+        assert_ne!(history[1].id, "m0");
+    }
+
+    #[test]
+    fn session_ids_are_never_empty() {
+        assert!(check_session_id("").is_err());
+        assert!(check_session_id("a:b").is_ok());
+    }
+
+    #[test]
+    fn idempotency_keys_stay_apart_from_similar_session_ids() {
+        let session = SessionKeys::new(None, "a");
+        let similar = SessionKeys::new(None, "a:b");
+
+        assert_ne!(session.idempotency_keys(), similar.idempotency_keys());
+        assert!(session.all().contains(&session.idempotency_keys()));
+    }
+
+    #[test]
+    fn parses_cursors() {
+        assert_eq!(parse_cursor("1700000000:3"), Some((1700000000, 3)));
+        assert_eq!(parse_cursor("-5:0"), Some((-5, 0)));
+        assert_eq!(parse_cursor("1700000000"), None);
+        assert_eq!(parse_cursor("abc:1"), None);
+        assert_eq!(parse_cursor("1:-1"), None);
+    }
+
+    #[test]
+    fn filters_on_user_and_every_tag() {
+        let tags = vec![String::from("a"), String::from("b")];
+
+        assert_eq!(
+            filter_keys(None, Some("u1"), &tags),
+            ["user_sessions:u1", "tag_sessions:a", "tag_sessions:b"]
+        );
+        assert_eq!(
+            filter_keys(Some("acme"), None, &tags[..1]),
+            ["acme/tag_sessions:a"]
+        );
+        assert!(filter_keys(None, None, &[]).is_empty());
+    }
+}
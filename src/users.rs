@@ -0,0 +1,171 @@
+use crate::long_term_memory::search_user_messages;
+use crate::models::{
+    AckResponse, AnyOpenAIClient, AppState, MemoryMessage, MotorheadError, NamespaceQuery,
+    SearchPayload, UserProfile,
+};
+use crate::reducer::incremental_profile;
+use crate::sessions::{check_namespace, namespaced_key, SessionKeys};
+use actix_web::{delete, error, get, post, web, HttpResponse, Responder};
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+
+pub fn user_profile_key(namespace: Option<&str>, user_id: &str) -> String {
+    namespaced_key(namespace, &format!("user_profile:{}", user_id))
+}
+
+/// Folds the messages that were just summarized into the rolling profile of the
+/// user the session is linked to, if any. The rest of the session's summary was
+/// already folded in by earlier compactions.
+pub async fn update_user_profile(
+    session: &SessionKeys,
+    messages: &[MemoryMessage],
+    model: String,
+    openai_client: &AnyOpenAIClient,
+    mut redis_conn: redis::aio::ConnectionManager,
+) -> Result<(), MotorheadError> {
+    if messages.is_empty() {
+        return Ok(());
+    }
+
+    let user_id: Option<String> = redis::Cmd::hget(session.meta(), "user_id")
+        .query_async(&mut redis_conn)
+        .await?;
+    let Some(user_id) = user_id else {
+        return Ok(());
+    };
+
+    let key = user_profile_key(session.namespace.as_deref(), &user_id);
+    let profile: Option<String> = redis::Cmd::hget(&key, "profile")
+        .query_async(&mut redis_conn)
+        .await?;
+
+    let new_lines = messages
+        .iter()
+        .map(MemoryMessage::to_prompt_line)
+        .collect::<Vec<_>>()
+        .join("\n");
+    let (profile, tokens_used) =
+        incremental_profile(model, openai_client, profile, new_lines).await?;
+
+    redis::pipe()
+        .atomic()
+        .cmd("HSET")
+        .arg(&key)
+        .arg("profile")
+        .arg(profile)
+        .arg("updated_at")
+        .arg(chrono::Utc::now().timestamp())
+        .ignore()
+        .cmd("HINCRBY")
+        .arg(&key)
+        .arg("tokens")
+        .arg(tokens_used)
+        .ignore()
+        .query_async::<_, ()>(&mut redis_conn)
+        .await?;
+
+    Ok(())
+}
+
+#[get("/users/{user_id}/profile")]
+pub async fn get_user_profile(
+    user_id: web::Path<String>,
+    redis: web::Data<redis::Client>,
+    web::Query(namespace_query): web::Query<NamespaceQuery>,
+) -> actix_web::Result<impl Responder> {
+    let namespace = namespace_query.namespace.as_deref();
+    check_namespace(namespace).map_err(error::ErrorBadRequest)?;
+
+    let mut conn = redis
+        .get_tokio_connection_manager()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let mut hash: HashMap<String, String> =
+        redis::Cmd::hgetall(user_profile_key(namespace, &user_id))
+            .query_async(&mut conn)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+
+    let Some(profile) = hash.remove("profile") else {
+        return Err(error::ErrorNotFound("User profile not found"));
+    };
+
+    let response = UserProfile {
+        user_id: user_id.to_string(),
+        profile,
+        tokens: hash
+            .get("tokens")
+            .and_then(|tokens| tokens.parse().ok())
+            .unwrap_or(0),
+        updated_at: hash
+            .get("updated_at")
+            .and_then(|updated_at| updated_at.parse().ok()),
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(response))
+}
+
+#[delete("/users/{user_id}/profile")]
+pub async fn delete_user_profile(
+    user_id: web::Path<String>,
+    redis: web::Data<redis::Client>,
+    web::Query(namespace_query): web::Query<NamespaceQuery>,
+) -> actix_web::Result<impl Responder> {
+    let namespace = namespace_query.namespace.as_deref();
+    check_namespace(namespace).map_err(error::ErrorBadRequest)?;
+
+    let mut conn = redis
+        .get_tokio_connection_manager()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    redis::Cmd::del(user_profile_key(namespace, &user_id))
+        .query_async::<_, ()>(&mut conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let response = AckResponse { status: "Ok" };
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(response))
+}
+
+#[post("/users/{user_id}/retrieval")]
+pub async fn run_user_retrieval(
+    user_id: web::Path<String>,
+    web::Json(payload): web::Json<SearchPayload>,
+    data: web::Data<Arc<AppState>>,
+    redis: web::Data<redis::Client>,
+    web::Query(namespace_query): web::Query<NamespaceQuery>,
+) -> actix_web::Result<impl Responder> {
+    let namespace = namespace_query.namespace.as_deref();
+    check_namespace(namespace).map_err(error::ErrorBadRequest)?;
+    payload.check().map_err(error::ErrorBadRequest)?;
+
+    if !data.long_term_memory {
+        return Ok(HttpResponse::BadRequest().body("Long term memory is disabled"));
+    }
+
+    let conn = redis
+        .get_tokio_connection_manager()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let client_wrapper = data
+        .openai_pool
+        .get()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    let openai_client = client_wrapper.deref();
+
+    match search_user_messages(&payload, namespace, &user_id, &data, openai_client, conn).await {
+        Ok(results) => Ok(HttpResponse::Ok().json(results)),
+        Err(e) => {
+            log::error!("Error User Retrieval API: {:?}", e);
+            Ok(HttpResponse::InternalServerError().body("Internal server error"))
+        }
+    }
+}